@@ -7,7 +7,10 @@ use std::{
 	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use wasm_instrument::{
-	gas_metering::{self, host_function, mutable_global, ConstantCostRules},
+	gas_metering::{
+		self, host_function, inline_global, mutable_global, signed_global, ConstantCostRules,
+		GasMeteringConfig,
+	},
 	parity_wasm::{deserialize_buffer, elements::Module, serialize},
 };
 use wasmi::{
@@ -49,6 +52,15 @@ struct HostFunctionMetering;
 /// Instrument the module using [`mutable_global::Injector`].
 struct MutableGlobalMetering;
 
+/// Instrument the module using [`signed_global::Injector`].
+struct SignedGlobalMetering;
+
+/// Instrument the module using [`inline_global::Injector`].
+struct InlineGlobalMetering;
+
+/// Instrument the module using [`host_function::Injector`] with loop hoisting enabled.
+struct LoopHoistingMetering;
+
 impl MeteringStrategy for NoMetering {}
 
 impl MeteringStrategy for WasmiMetering {
@@ -100,6 +112,47 @@ impl MeteringStrategy for MutableGlobalMetering {
 	}
 }
 
+impl MeteringStrategy for SignedGlobalMetering {
+	fn instrument_module(module: Module) -> Module {
+		let backend = signed_global::Injector::new("gas_left");
+		gas_metering::inject(module, backend, &ConstantCostRules::default()).unwrap()
+	}
+
+	fn init_instance(module: &mut BenchInstance) {
+		// the signed counter must not exceed `i64::MAX`
+		module
+			.instance
+			.get_global(&mut module.store, "gas_left")
+			.unwrap()
+			.set(&mut module.store, Value::I64(i64::MAX))
+			.unwrap();
+	}
+}
+
+impl MeteringStrategy for InlineGlobalMetering {
+	fn instrument_module(module: Module) -> Module {
+		let backend = inline_global::Injector::new("gas_left");
+		gas_metering::inject(module, backend, &ConstantCostRules::default()).unwrap()
+	}
+
+	fn init_instance(module: &mut BenchInstance) {
+		MutableGlobalMetering::init_instance(module)
+	}
+}
+
+impl MeteringStrategy for LoopHoistingMetering {
+	fn instrument_module(module: Module) -> Module {
+		let backend = host_function::Injector::new("env", "gas");
+		let config = GasMeteringConfig::new().with_loop_hoisting(true);
+		gas_metering::inject_with_config(module, backend, &ConstantCostRules::default(), &config)
+			.unwrap()
+	}
+
+	fn define_host_funcs(linker: &mut Linker<u64>) {
+		HostFunctionMetering::define_host_funcs(linker)
+	}
+}
+
 /// A wasm instance ready to be benchmarked.
 struct BenchInstance {
 	store: Store<u64>,
@@ -161,6 +214,15 @@ where
 
 	let mut module = BenchInstance::new::<MutableGlobalMetering, _>(wasm, &define_host_funcs);
 	group.bench_function("mutable_global", |bench| f(bench, &mut module));
+
+	let mut module = BenchInstance::new::<SignedGlobalMetering, _>(wasm, &define_host_funcs);
+	group.bench_function("signed_global", |bench| f(bench, &mut module));
+
+	let mut module = BenchInstance::new::<InlineGlobalMetering, _>(wasm, &define_host_funcs);
+	group.bench_function("inline_global", |bench| f(bench, &mut module));
+
+	let mut module = BenchInstance::new::<LoopHoistingMetering, _>(wasm, &define_host_funcs);
+	group.bench_function("loop_hoisting", |bench| f(bench, &mut module));
 }
 
 /// Converts the `.wat` encoded `bytes` into `.wasm` encoded bytes.
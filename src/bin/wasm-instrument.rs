@@ -0,0 +1,535 @@
+//! Command-line interface to instrument wasm files.
+//!
+//! Reads `.wasm` or `.wat` files and writes the instrumented module in the format given by the
+//! extension of the output file.
+
+// The command line interface depends on `clap`, which requires a newer toolchain than the
+// library itself.
+#![allow(clippy::incompatible_msrv)]
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs,
+	num::NonZeroU32,
+	path::PathBuf,
+	process,
+};
+use wasm_instrument::{
+	gas_metering::{
+		self, host_function, imported_global, inline_global, lazy, mutable_global, signed_global,
+		ConstantCostRules, GasMeteringConfig, InjectError, MemoryGrowCost, Rules,
+	},
+	inject_stack_limiter,
+	parity_wasm::elements::{self, Instruction},
+	ConstantStackCostRules, Report, ReportFormat, StackLimiterConfig,
+};
+
+#[derive(Parser)]
+#[command(name = "wasm-instrument", version, about = "Instrument wasm modules")]
+struct Cli {
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Inject gas metering.
+	Gas(GasArgs),
+	/// Inject a stack height limiter.
+	StackLimit(StackLimitArgs),
+	/// Print the stack costs and metered blocks of every function.
+	Analyze(AnalyzeArgs),
+}
+
+#[derive(Args)]
+struct Files {
+	/// The `.wasm` or `.wat` file to instrument.
+	input: PathBuf,
+	/// Where to write the instrumented module. Written as text if the extension is `.wat`.
+	#[arg(short, long)]
+	output: PathBuf,
+}
+
+#[derive(Args)]
+struct GasArgs {
+	#[command(flatten)]
+	files: Files,
+	/// How the gas is tracked.
+	#[arg(long, value_enum, default_value_t = Backend::HostFunction)]
+	backend: Backend,
+	/// The module the gas function, the gas global or the out-of-gas function is imported from.
+	#[arg(long, default_value = "env")]
+	module: String,
+	/// The name of the gas function imported by the `host-function` backend.
+	#[arg(long, default_value = "gas")]
+	function: String,
+	/// The name of the gas global, which is imported by the `imported-global` backend and
+	/// exported by the other global backends.
+	#[arg(long, default_value = "gas_left")]
+	global: String,
+	/// Call the function of this name imported from `--module` when running out of gas instead
+	/// of trapping. Only supported by the `mutable-global` and `inline-global` backends.
+	#[arg(long)]
+	out_of_gas_function: Option<String>,
+	/// Accumulate the costs of metered blocks in a local and charge them less often.
+	#[arg(long)]
+	lazy: bool,
+	#[command(flatten)]
+	costs: Costs,
+}
+
+#[derive(Args)]
+struct Costs {
+	/// The cost of every instruction.
+	#[arg(long, default_value_t = 1, conflicts_with = "schedule")]
+	instruction_cost: u32,
+	/// The cost per page of `memory.grow`. Zero disables charging it.
+	#[arg(long, default_value_t = 0, conflicts_with = "schedule")]
+	memory_grow_cost: u32,
+	/// The cost per local of a function call.
+	#[arg(long, default_value_t = 1, conflicts_with = "schedule")]
+	call_per_local_cost: u32,
+	/// A file with the costs of the instructions.
+	///
+	/// Every line holds a `name = cost` pair, where the name is an instruction like `i64.div_s`
+	/// or one of `default`, `memory_grow` and `call_per_local`. Instructions are named like
+	/// `parity-wasm` displays them, e.g. `get_local`. A cost of `forbidden` rejects modules using
+	/// the instruction. Lines starting with `#` are ignored.
+	#[arg(long)]
+	schedule: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+	/// Charge gas by calling an imported host function.
+	HostFunction,
+	/// Charge gas by a local function decreasing an exported mutable global.
+	MutableGlobal,
+	/// Like `mutable-global`, but inline the gas function into metered blocks within loops.
+	InlineGlobal,
+	/// Like `mutable-global`, but with a signed counter that traps once it becomes negative.
+	SignedGlobal,
+	/// Like `mutable-global`, but the global is imported from the host.
+	ImportedGlobal,
+}
+
+#[derive(Args)]
+struct StackLimitArgs {
+	#[command(flatten)]
+	files: Files,
+	/// The maximum stack height.
+	#[arg(long)]
+	limit: u32,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+	/// The `.wasm` or `.wat` file to analyze.
+	input: PathBuf,
+	/// Where to write the report. Printed to the standard output if omitted.
+	#[arg(short, long)]
+	output: Option<PathBuf>,
+	/// How the report is rendered.
+	#[arg(long, value_enum, default_value_t = Format::Human)]
+	format: Format,
+	/// The maximum stack height, which decides about the generation of thunks.
+	#[arg(long, default_value_t = 1024)]
+	limit: u32,
+	#[command(flatten)]
+	costs: Costs,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+	/// A plain text listing.
+	Human,
+	/// A JSON object with a `functions` array.
+	Json,
+	/// A CSV table with a row per metered block, or per function without any.
+	Csv,
+}
+
+impl From<Format> for ReportFormat {
+	fn from(format: Format) -> Self {
+		match format {
+			Format::Human => ReportFormat::Human,
+			Format::Json => ReportFormat::Json,
+			Format::Csv => ReportFormat::Csv,
+		}
+	}
+}
+
+/// The cost of an instruction in a [`Schedule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cost {
+	Forbidden,
+	Amount(u32),
+}
+
+/// [`Rules`] read from a schedule file.
+#[derive(Debug, PartialEq, Eq)]
+struct Schedule {
+	default: Cost,
+	memory_grow: u32,
+	call_per_local: u32,
+	/// The costs of instructions by their name.
+	instructions: BTreeMap<String, Cost>,
+}
+
+impl Schedule {
+	fn parse(source: &str) -> Result<Self, String> {
+		let mut schedule = Schedule {
+			default: Cost::Amount(1),
+			memory_grow: 0,
+			call_per_local: 1,
+			instructions: BTreeMap::new(),
+		};
+		let known: BTreeSet<String> =
+			gas_metering::supported_instructions().iter().map(instruction_name).collect();
+		for (number, line) in (1..).zip(source.lines()) {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue
+			}
+			let error = |message: &str| format!("line {}: {}", number, message);
+			let (name, cost) =
+				line.split_once('=').ok_or_else(|| error("expected `name = cost`"))?;
+			let cost = match cost.trim() {
+				"forbidden" => Cost::Forbidden,
+				cost => Cost::Amount(cost.parse().map_err(|_| error("invalid cost"))?),
+			};
+			match (name.trim(), cost) {
+				("default", cost) => schedule.default = cost,
+				("memory_grow", Cost::Amount(cost)) => schedule.memory_grow = cost,
+				("call_per_local", Cost::Amount(cost)) => schedule.call_per_local = cost,
+				("memory_grow" | "call_per_local", Cost::Forbidden) =>
+					return Err(error("only instructions can be forbidden")),
+				(name, _) if !known.contains(name) =>
+					return Err(error(&format!("unknown instruction `{}`", name))),
+				(name, cost) => {
+					schedule.instructions.insert(name.into(), cost);
+				},
+			}
+		}
+		Ok(schedule)
+	}
+}
+
+/// Returns the name of `instruction` as used in a [`Schedule`].
+fn instruction_name(instruction: &Instruction) -> String {
+	// Instructions are displayed by their name followed by their immediates.
+	let mut name = instruction.to_string();
+	name.truncate(name.find(' ').unwrap_or(name.len()));
+	name
+}
+
+impl Rules for Schedule {
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+		match self
+			.instructions
+			.get(&instruction_name(instruction))
+			.copied()
+			.unwrap_or(self.default)
+		{
+			Cost::Forbidden => None,
+			Cost::Amount(cost) => Some(cost),
+		}
+	}
+
+	fn memory_grow_cost(&self) -> MemoryGrowCost {
+		NonZeroU32::new(self.memory_grow).map_or(MemoryGrowCost::Free, MemoryGrowCost::Linear)
+	}
+
+	fn call_per_local_cost(&self) -> u32 {
+		self.call_per_local
+	}
+}
+
+/// The gas [`Rules`] selected by [`Costs`].
+enum CostRules {
+	Constant(ConstantCostRules),
+	Schedule(Schedule),
+}
+
+impl Costs {
+	fn rules(&self) -> Result<CostRules, String> {
+		match &self.schedule {
+			Some(path) => {
+				let source = fs::read_to_string(path)
+					.map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+				let schedule =
+					Schedule::parse(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+				Ok(CostRules::Schedule(schedule))
+			},
+			None => Ok(CostRules::Constant(ConstantCostRules::new(
+				self.instruction_cost,
+				self.memory_grow_cost,
+				self.call_per_local_cost,
+			))),
+		}
+	}
+}
+
+fn read_module(path: &PathBuf) -> Result<elements::Module, String> {
+	let source = fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+	// Binary modules are passed through as they are.
+	let binary = wat::parse_bytes(&source).map_err(|e| format!("{}: {}", path.display(), e))?;
+	let module: elements::Module = elements::deserialize_buffer(&binary)
+		.map_err(|e| format!("failed to decode {}: {}", path.display(), e))?;
+	// Keep the name section up to date with the instrumentation.
+	Ok(module.parse_names().unwrap_or_else(|(_, module)| module))
+}
+
+fn write_module(path: &PathBuf, module: elements::Module) -> Result<(), String> {
+	let binary = elements::serialize(module).map_err(|e| format!("failed to encode: {}", e))?;
+	let output = if path.extension().map_or(false, |extension| extension == "wat") {
+		wasmprinter::print_bytes(&binary)
+			.map_err(|e| format!("failed to print: {}", e))?
+			.into()
+	} else {
+		binary
+	};
+	fs::write(path, output).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+fn gas(args: &GasArgs, module: elements::Module) -> Result<elements::Module, String> {
+	fn inject<B: gas_metering::Backend, R: Rules>(
+		args: &GasArgs,
+		module: elements::Module,
+		backend: B,
+		rules: &R,
+	) -> Result<elements::Module, InjectError> {
+		let config = GasMeteringConfig::new();
+		if args.lazy {
+			gas_metering::inject_with_config(module, lazy::Injector::new(backend), rules, &config)
+		} else {
+			gas_metering::inject_with_config(module, backend, rules, &config)
+		}
+	}
+
+	fn inject_backend<R: Rules>(
+		args: &GasArgs,
+		module: elements::Module,
+		rules: &R,
+	) -> Result<elements::Module, String> {
+		// The backends take `&'static str` names. Leaking them is fine, since the process exits
+		// after instrumenting a single module.
+		let leak = |name: &String| -> &'static str { Box::leak(name.clone().into_boxed_str()) };
+		let out_of_gas = match &args.out_of_gas_function {
+			Some(function) => match args.backend {
+				Backend::MutableGlobal | Backend::InlineGlobal =>
+					mutable_global::OutOfGas::HostFunction {
+						module: leak(&args.module),
+						function: leak(function),
+					},
+				_ => return Err("the backend doesn't support an out-of-gas function".into()),
+			},
+			None => mutable_global::OutOfGas::Sentinel,
+		};
+		let result = match args.backend {
+			Backend::HostFunction => inject(
+				args,
+				module,
+				host_function::Injector::new(leak(&args.module), leak(&args.function)),
+				rules,
+			),
+			Backend::MutableGlobal => inject(
+				args,
+				module,
+				mutable_global::Injector::new(leak(&args.global)).with_out_of_gas(out_of_gas),
+				rules,
+			),
+			Backend::InlineGlobal => inject(
+				args,
+				module,
+				inline_global::Injector::new(leak(&args.global)).with_out_of_gas(out_of_gas),
+				rules,
+			),
+			Backend::SignedGlobal =>
+				inject(args, module, signed_global::Injector::new(leak(&args.global)), rules),
+			Backend::ImportedGlobal => inject(
+				args,
+				module,
+				imported_global::Injector::new(leak(&args.module), leak(&args.global)),
+				rules,
+			),
+		};
+		result.map_err(|error| match error {
+			InjectError::ForbiddenInstruction(_) =>
+				"the module uses an instruction forbidden by the cost schedule".into(),
+			InjectError::DuplicateExport(_) =>
+				format!("the module already exports `{}`", args.global),
+			_ => "the module can't be metered".into(),
+		})
+	}
+
+	match args.costs.rules()? {
+		CostRules::Constant(rules) => inject_backend(args, module, &rules),
+		CostRules::Schedule(schedule) => inject_backend(args, module, &schedule),
+	}
+}
+
+fn analyze(args: &AnalyzeArgs, module: &elements::Module) -> Result<String, String> {
+	let stack_rules = ConstantStackCostRules::default();
+	let config = StackLimiterConfig::new(args.limit);
+	let report = match args.costs.rules()? {
+		CostRules::Constant(rules) => Report::new(module, &rules, &stack_rules, &config),
+		CostRules::Schedule(schedule) => Report::new(module, &schedule, &stack_rules, &config),
+	};
+	Ok(report?.render(args.format.into()))
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+	match cli.command {
+		Command::Gas(args) => {
+			let module = read_module(&args.files.input)?;
+			let module = gas(&args, module)?;
+			write_module(&args.files.output, module)
+		},
+		Command::StackLimit(args) => {
+			let module = read_module(&args.files.input)?;
+			let module = inject_stack_limiter(module, args.limit).map_err(String::from)?;
+			write_module(&args.files.output, module)
+		},
+		Command::Analyze(args) => {
+			let module = read_module(&args.input)?;
+			let report = analyze(&args, &module)?;
+			match &args.output {
+				Some(path) => fs::write(path, report)
+					.map_err(|e| format!("failed to write {}: {}", path.display(), e)),
+				None => {
+					print!("{}", report);
+					Ok(())
+				},
+			}
+		},
+	}
+}
+
+fn main() {
+	if let Err(error) = run(Cli::parse()) {
+		eprintln!("error: {}", error);
+		process::exit(1);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::Path;
+
+	fn fixture(dir: &str, name: &str) -> PathBuf {
+		Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join(dir).join(name)
+	}
+
+	/// Run the command line and return the written `.wat` file.
+	fn run_cli(args: &[&str], name: &str) -> String {
+		let output = std::env::temp_dir().join(format!("wasm-instrument-cli-{}.wat", name));
+		let output_arg = output.to_str().unwrap();
+		let output_args = ["-o", output_arg];
+		let args = ["wasm-instrument"].iter().chain(args).chain(&output_args);
+		run(Cli::parse_from(args)).unwrap();
+		let written = fs::read_to_string(&output).unwrap();
+		fs::remove_file(&output).unwrap();
+		written
+	}
+
+	#[test]
+	fn reproduces_diff_expectations() {
+		let input = fixture("fixtures/gas", "call.wat");
+		let written = run_cli(&["gas", input.to_str().unwrap()], "host_fn");
+		let expected = fs::read_to_string(fixture("expectations/gas", "call_host_fn.wat")).unwrap();
+		assert_eq!(written, expected);
+
+		let args = ["gas", "--backend", "mutable-global", input.to_str().unwrap()];
+		let written = run_cli(&args, "mut_global");
+		let expected =
+			fs::read_to_string(fixture("expectations/gas", "call_mut_global.wat")).unwrap();
+		assert_eq!(written, expected);
+
+		let input = fixture("fixtures/stack-height", "table.wat");
+		let written =
+			run_cli(&["stack-limit", "--limit", "1024", input.to_str().unwrap()], "table");
+		let expected =
+			fs::read_to_string(fixture("expectations/stack-height", "table.wat")).unwrap();
+		assert_eq!(written, expected);
+	}
+
+	#[test]
+	fn other_backends() {
+		let input = fixture("fixtures/gas", "call.wat");
+		let input = input.to_str().unwrap();
+
+		let args = ["gas", "--backend", "imported-global", "--lazy", input];
+		let written = run_cli(&args, "imported_global");
+		assert!(written.contains(r#"(import "env" "gas_left" (global"#));
+
+		let args = ["gas", "--backend", "inline-global", "--out-of-gas-function", "oog", input];
+		let written = run_cli(&args, "inline_global");
+		assert!(written.contains(r#"(import "env" "oog" (func"#));
+		assert!(written.contains(r#"(export "gas_left" (global"#));
+
+		let args =
+			["wasm-instrument", "gas", "--out-of-gas-function", "oog", input, "-o", "x.wasm"];
+		assert_eq!(
+			run(Cli::parse_from(args)).unwrap_err(),
+			"the backend doesn't support an out-of-gas function"
+		);
+	}
+
+	#[test]
+	fn analyze_reports_functions() {
+		let input = fixture("fixtures/gas", "call.wat");
+		let output = std::env::temp_dir().join("wasm-instrument-cli-report.csv");
+		let args = [
+			"wasm-instrument",
+			"analyze",
+			"--format",
+			"csv",
+			input.to_str().unwrap(),
+			"-o",
+			output.to_str().unwrap(),
+		];
+		run(Cli::parse_from(args)).unwrap();
+		let written = fs::read_to_string(&output).unwrap();
+		fs::remove_file(&output).unwrap();
+
+		let module = read_module(&input).unwrap();
+		let expected = Report::new(
+			&module,
+			&ConstantCostRules::default(),
+			&ConstantStackCostRules::default(),
+			&StackLimiterConfig::new(1024),
+		)
+		.unwrap()
+		.render(ReportFormat::Csv);
+		assert_eq!(written, expected);
+		assert!(written.lines().count() > 1);
+	}
+
+	#[test]
+	fn parse_schedule() {
+		let schedule = Schedule::parse(
+			"# Costs\n\ndefault = 2\nmemory_grow = 100\ni64.div_s = 10\ni32.const=forbidden\n",
+		)
+		.unwrap();
+		assert_eq!(schedule.instruction_cost(&Instruction::I64DivS), Some(10));
+		assert_eq!(schedule.instruction_cost(&Instruction::I32Const(1)), None);
+		assert_eq!(schedule.instruction_cost(&Instruction::I32Add), Some(2));
+		assert_eq!(
+			schedule.memory_grow_cost(),
+			MemoryGrowCost::Linear(NonZeroU32::new(100).unwrap())
+		);
+		assert_eq!(schedule.call_per_local_cost(), 1);
+
+		assert_eq!(Schedule::parse("default 2").unwrap_err(), "line 1: expected `name = cost`");
+		assert_eq!(Schedule::parse("\ndefault = -1").unwrap_err(), "line 2: invalid cost");
+		assert!(Schedule::parse("memory_grow = forbidden").is_err());
+		assert_eq!(
+			Schedule::parse("# Costs\ni32.ad = 5").unwrap_err(),
+			"line 2: unknown instruction `i32.ad`"
+		);
+		assert!(Schedule::parse("get_local = 1\nbr_table = 3\ncall_indirect = 4").is_ok());
+	}
+}
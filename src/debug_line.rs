@@ -0,0 +1,648 @@
+//! Rewriting of the DWARF line tables of instrumented modules.
+//!
+//! The addresses of the line tables in the `.debug_line` custom section are byte offsets within
+//! the code section. Instrumentation inserts instructions into the function bodies, which makes
+//! these addresses stale. They are mapped to the instrumented code section with the help of the
+//! [`OffsetMap`] returned by the passes.
+
+use crate::offset_map::{AddressMap, OffsetMap};
+use alloc::vec::Vec;
+use parity_wasm::elements;
+
+/// The name of the custom section holding the DWARF line tables.
+const SECTION_NAME: &str = ".debug_line";
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// Rewrite the addresses of the `.debug_line` custom section of the instrumented `module`.
+///
+/// `original` is the binary of the module before instrumentation and `offsets` the [`OffsetMap`]
+/// returned by the pass, see e.g. [`gas_metering::inject_with_offset_map`]. Maps of several
+/// passes can be combined with [`OffsetMap::chain`]. An address of an original instruction is
+/// mapped to the first instruction inserted in front of it, so that e.g. a breakpoint also covers
+/// the metering code of the instruction. Addresses outside of the function bodies are left as
+/// they are.
+///
+/// The original offsets are read from `original`, which may encode integers with more bytes than
+/// necessary as e.g. linkers do. The rewritten addresses are those of `module` as serialized by
+/// `parity-wasm`. Fails if `original` isn't the binary the instrumented module was deserialized
+/// from.
+///
+/// The line programs are re-encoded and may change their size. Hence references into the section
+/// from other DWARF sections, like `DW_AT_stmt_list` in `.debug_info`, need to be updated using
+/// the returned list of the original and rewritten offsets of every line program. Does nothing
+/// if the module has no `.debug_line` section.
+///
+/// With the `dwarf` feature enabled `rewrite_debug_info` rewrites all DWARF sections, including
+/// `.debug_line`, and this function must not be applied on top of it.
+///
+/// [`gas_metering::inject_with_offset_map`]: crate::gas_metering::inject_with_offset_map
+pub fn rewrite_debug_line(
+	original: &[u8],
+	module: &mut elements::Module,
+	offsets: &OffsetMap,
+) -> Result<Vec<(u64, u64)>, &'static str> {
+	let section = match module.custom_sections().find(|section| section.name() == SECTION_NAME) {
+		Some(section) => section,
+		None => return Ok(Vec::new()),
+	};
+	let addresses = AddressMap::new(original, module, offsets)?;
+	let (payload, unit_offsets) =
+		rewrite_line_programs(section.payload(), |address| addresses.map(address))?;
+	module.set_custom_section(SECTION_NAME, payload);
+	Ok(unit_offsets)
+}
+
+/// Pairs of the original and rewritten offset of every line program in the section.
+pub(crate) type UnitOffsets = Vec<(u64, u64)>;
+
+/// Rewrite the addresses of all line programs in `section` using `map`.
+///
+/// Returns the rewritten section together with the offsets of its line programs.
+pub(crate) fn rewrite_line_programs(
+	section: &[u8],
+	map: impl Fn(u64) -> Option<u64>,
+) -> Result<(Vec<u8>, UnitOffsets), &'static str> {
+	let mut output = Vec::with_capacity(section.len());
+	let mut unit_offsets = Vec::new();
+	let mut reader = Reader { data: section, pos: 0 };
+	while reader.pos < section.len() {
+		unit_offsets.push((reader.pos as u64, output.len() as u64));
+		rewrite_unit(&mut reader, &mut output, &map)?;
+	}
+	Ok((output, unit_offsets))
+}
+
+/// The parameters of a line program required to interpret its opcodes.
+struct LineProgram {
+	minimum_instruction_length: u8,
+	line_base: i8,
+	line_range: u8,
+	opcode_base: u8,
+	standard_opcode_lengths: Vec<u8>,
+	address_size: usize,
+}
+
+fn rewrite_unit(
+	reader: &mut Reader,
+	output: &mut Vec<u8>,
+	map: &impl Fn(u64) -> Option<u64>,
+) -> Result<(), &'static str> {
+	let unit_start = reader.pos;
+	let output_start = output.len();
+	let (unit_length, offset_size) = match reader.u32()? {
+		0xffff_ffff => (reader.u64()?, 8),
+		length if length >= 0xffff_fff0 => return Err("Invalid unit length in .debug_line"),
+		length => (u64::from(length), 4),
+	};
+	let length_size = reader.pos - unit_start;
+	let unit_end = usize::try_from(unit_length)
+		.ok()
+		.and_then(|length| reader.pos.checked_add(length))
+		.filter(|unit_end| *unit_end <= reader.data.len())
+		.ok_or("Truncated unit in .debug_line")?;
+
+	let version = reader.u16()?;
+	if !(2..=5).contains(&version) {
+		return Err("Unsupported .debug_line version")
+	}
+	// Wasm32 uses 4 byte addresses. Only version 5 headers state the size.
+	let mut address_size = 4;
+	if version >= 5 {
+		address_size = reader.u8()? as usize;
+		reader.u8()?;
+	}
+	let header_length = if offset_size == 8 { reader.u64()? } else { u64::from(reader.u32()?) };
+	let program_start = usize::try_from(header_length)
+		.ok()
+		.and_then(|length| reader.pos.checked_add(length))
+		.filter(|program_start| *program_start <= unit_end)
+		.ok_or("Invalid header length in .debug_line")?;
+	let minimum_instruction_length = reader.u8()?;
+	if version >= 4 && reader.u8()? != 1 {
+		return Err("Line programs for VLIW architectures aren't supported")
+	}
+	// default_is_stmt
+	reader.u8()?;
+	let line_base = reader.u8()? as i8;
+	let line_range = reader.u8()?;
+	let opcode_base = reader.u8()?;
+	if minimum_instruction_length == 0 || line_range == 0 || opcode_base == 0 {
+		return Err("Invalid .debug_line header")
+	}
+	let standard_opcode_lengths = reader.bytes(opcode_base as usize - 1)?.to_vec();
+	let program = LineProgram {
+		minimum_instruction_length,
+		line_base,
+		line_range,
+		opcode_base,
+		standard_opcode_lengths,
+		address_size,
+	};
+
+	// The header is kept as it is, only the unit length is updated after the program.
+	output.extend_from_slice(&reader.data[unit_start..program_start]);
+	let mut program_reader = Reader { data: &reader.data[..unit_end], pos: program_start };
+	rewrite_program(&program, &mut program_reader, output, map)?;
+	reader.pos = unit_end;
+
+	let new_length = (output.len() - output_start - length_size) as u64;
+	match offset_size {
+		8 => output[output_start + 4..output_start + 12].copy_from_slice(&new_length.to_le_bytes()),
+		_ => {
+			let new_length = u32::try_from(new_length)
+				.ok()
+				.filter(|length| *length < 0xffff_fff0)
+				.ok_or("The rewritten line program is too large")?;
+			output[output_start..output_start + 4].copy_from_slice(&new_length.to_le_bytes());
+		},
+	}
+	Ok(())
+}
+
+/// The state of the rewritten line program.
+struct Rewriter<'a, F> {
+	program: &'a LineProgram,
+	output: &'a mut Vec<u8>,
+	map: &'a F,
+	/// The address of the original state machine.
+	address: u64,
+	/// The address of the rewritten state machine.
+	new_address: u64,
+	/// The size of the operand of the last `DW_LNE_set_address`.
+	address_size: usize,
+}
+
+impl<F: Fn(u64) -> Option<u64>> Rewriter<'_, F> {
+	/// The rewritten address of the current original address.
+	///
+	/// Addresses that can't be mapped are kept.
+	fn target(&self) -> u64 {
+		(self.map)(self.address).unwrap_or(self.address)
+	}
+
+	/// The operation advance to reach `target` from the current rewritten address, if possible.
+	fn operation_advance(&self, target: u64) -> Option<u64> {
+		let min_length = u64::from(self.program.minimum_instruction_length);
+		let delta = target.checked_sub(self.new_address)?;
+		(delta % min_length == 0).then(|| delta / min_length)
+	}
+
+	/// Move the rewritten state machine to the rewritten current address.
+	fn sync_address(&mut self) {
+		let target = self.target();
+		match self.operation_advance(target) {
+			Some(0) => {},
+			Some(advance) => {
+				self.output.push(DW_LNS_ADVANCE_PC);
+				write_uleb(self.output, advance);
+			},
+			None => {
+				self.output.push(0);
+				write_uleb(self.output, 1 + self.address_size as u64);
+				self.output.push(DW_LNE_SET_ADDRESS);
+				self.output.extend_from_slice(&target.to_le_bytes()[..self.address_size]);
+			},
+		}
+		self.new_address = target;
+	}
+
+	/// Append a row after advancing the line by `line_advance`.
+	fn append_row(&mut self, line_advance: i64) {
+		let program = self.program;
+		let target = self.target();
+		let special = self.operation_advance(target).and_then(|advance| {
+			let line = line_advance.checked_sub(i64::from(program.line_base))?;
+			if !(0..i64::from(program.line_range)).contains(&line) {
+				return None
+			}
+			let opcode = advance
+				.checked_mul(u64::from(program.line_range))?
+				.checked_add(line as u64)?
+				.checked_add(u64::from(program.opcode_base))?;
+			u8::try_from(opcode).ok()
+		});
+		match special {
+			Some(opcode) => {
+				self.output.push(opcode);
+				self.new_address = target;
+			},
+			None => {
+				self.sync_address();
+				if line_advance != 0 {
+					self.output.push(DW_LNS_ADVANCE_LINE);
+					write_sleb(self.output, line_advance);
+				}
+				self.output.push(DW_LNS_COPY);
+			},
+		}
+	}
+}
+
+fn rewrite_program<F: Fn(u64) -> Option<u64>>(
+	program: &LineProgram,
+	reader: &mut Reader,
+	output: &mut Vec<u8>,
+	map: &F,
+) -> Result<(), &'static str> {
+	let min_length = u64::from(program.minimum_instruction_length);
+	let mut rewriter = Rewriter {
+		program,
+		output,
+		map,
+		address: 0,
+		new_address: 0,
+		address_size: program.address_size,
+	};
+
+	while reader.pos < reader.data.len() {
+		let opcode_start = reader.pos;
+		let opcode = reader.u8()?;
+		if opcode >= program.opcode_base {
+			let adjusted = opcode - program.opcode_base;
+			let advance = u64::from(adjusted / program.line_range) * min_length;
+			let line_advance =
+				i64::from(program.line_base) + i64::from(adjusted % program.line_range);
+			rewriter.address = rewriter.address.wrapping_add(advance);
+			rewriter.append_row(line_advance);
+			continue
+		}
+
+		match opcode {
+			0 => {
+				let length = reader.uleb()? as usize;
+				let instruction_start = reader.pos;
+				let instruction = reader.bytes(length)?;
+				match instruction.split_first() {
+					Some((&DW_LNE_END_SEQUENCE, _)) => {
+						rewriter.sync_address();
+						rewriter.output.extend_from_slice(&reader.data[opcode_start..reader.pos]);
+						rewriter.address = 0;
+						rewriter.new_address = 0;
+					},
+					Some((&DW_LNE_SET_ADDRESS, operand)) => {
+						let mut address = [0u8; 8];
+						address
+							.get_mut(..operand.len())
+							.ok_or("Invalid address size in .debug_line")?
+							.copy_from_slice(operand);
+						rewriter.address_size = operand.len();
+						rewriter.address = u64::from_le_bytes(address);
+					},
+					_ => rewriter
+						.output
+						.extend_from_slice(&reader.data[opcode_start..instruction_start + length]),
+				}
+			},
+			DW_LNS_COPY => rewriter.append_row(0),
+			DW_LNS_ADVANCE_PC => {
+				let advance = reader.uleb()?;
+				rewriter.address = rewriter.address.wrapping_add(advance.wrapping_mul(min_length));
+			},
+			DW_LNS_ADVANCE_LINE => {
+				let line_advance = reader.sleb()?;
+				// Line advances are applied to the next row, which may get a special opcode.
+				rewriter.output.push(DW_LNS_ADVANCE_LINE);
+				write_sleb(rewriter.output, line_advance);
+			},
+			DW_LNS_CONST_ADD_PC => {
+				let adjusted = 255 - program.opcode_base;
+				let advance = u64::from(adjusted / program.line_range) * min_length;
+				rewriter.address = rewriter.address.wrapping_add(advance);
+			},
+			DW_LNS_FIXED_ADVANCE_PC => {
+				let advance = reader.u16()?;
+				rewriter.address = rewriter.address.wrapping_add(u64::from(advance));
+			},
+			_ => {
+				// All other standard opcodes don't change the address and are copied.
+				for _ in 0..program.standard_opcode_lengths[opcode as usize - 1] {
+					reader.uleb()?;
+				}
+				rewriter.output.extend_from_slice(&reader.data[opcode_start..reader.pos]);
+			},
+		}
+	}
+
+	Ok(())
+}
+
+/// Reads the values of a DWARF section.
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+		let bytes = self
+			.pos
+			.checked_add(len)
+			.and_then(|end| self.data.get(self.pos..end))
+			.ok_or("Unexpected end of .debug_line")?;
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8, &'static str> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, &'static str> {
+		let mut bytes = [0; 2];
+		bytes.copy_from_slice(self.bytes(2)?);
+		Ok(u16::from_le_bytes(bytes))
+	}
+
+	fn u32(&mut self) -> Result<u32, &'static str> {
+		let mut bytes = [0; 4];
+		bytes.copy_from_slice(self.bytes(4)?);
+		Ok(u32::from_le_bytes(bytes))
+	}
+
+	fn u64(&mut self) -> Result<u64, &'static str> {
+		let mut bytes = [0; 8];
+		bytes.copy_from_slice(self.bytes(8)?);
+		Ok(u64::from_le_bytes(bytes))
+	}
+
+	fn uleb(&mut self) -> Result<u64, &'static str> {
+		let mut value = 0;
+		for shift in (0..64).step_by(7) {
+			let byte = self.u8()?;
+			value |= u64::from(byte & 0x7f) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(value)
+			}
+		}
+		Err("Invalid LEB128 in .debug_line")
+	}
+
+	fn sleb(&mut self) -> Result<i64, &'static str> {
+		let mut value = 0;
+		for shift in (0..64).step_by(7) {
+			let byte = self.u8()?;
+			value |= i64::from(byte & 0x7f) << shift;
+			if byte & 0x80 == 0 {
+				if shift + 7 < 64 && byte & 0x40 != 0 {
+					value |= -1 << (shift + 7);
+				}
+				return Ok(value)
+			}
+		}
+		Err("Invalid LEB128 in .debug_line")
+	}
+}
+
+fn write_uleb(output: &mut Vec<u8>, mut value: u64) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			output.push(byte);
+			return
+		}
+		output.push(byte | 0x80);
+	}
+}
+
+fn write_sleb(output: &mut Vec<u8>, mut value: i64) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+			output.push(byte);
+			return
+		}
+		output.push(byte | 0x80);
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::gas_metering;
+
+	/// The offsets of the instructions of every function body within the code section.
+	pub(crate) fn instruction_addresses(module: &elements::Module) -> Vec<Vec<u64>> {
+		let len = |bytes: Vec<u8>| bytes.len() as u64;
+		let bodies = module.code_section().unwrap().bodies();
+		let mut offset = len(elements::serialize(elements::VarUint32::from(bodies.len())).unwrap());
+		bodies
+			.iter()
+			.map(|body| {
+				let end = offset + len(elements::serialize(body.clone()).unwrap());
+				let code = len(elements::serialize(body.code().clone()).unwrap());
+				let mut address = end - code;
+				offset = end;
+				body.code()
+					.elements()
+					.iter()
+					.map(|instruction| {
+						let start = address;
+						address += len(elements::serialize(instruction.clone()).unwrap());
+						start
+					})
+					.collect()
+			})
+			.collect()
+	}
+
+	/// A version 4 line program with a row of line `pos + 1` for each instruction.
+	pub(crate) fn line_program(addresses: &[Vec<u64>]) -> Vec<u8> {
+		let mut program = Vec::new();
+		for body in addresses {
+			// DW_LNE_set_address
+			program.extend_from_slice(&[0, 5, DW_LNE_SET_ADDRESS]);
+			program.extend_from_slice(&(body[0] as u32).to_le_bytes());
+			program.push(DW_LNS_COPY);
+			for window in body.windows(2) {
+				// A special opcode advancing the line by one.
+				program.push(13 + (1 + 5) + 14 * (window[1] - window[0]) as u8);
+			}
+			program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+		}
+
+		let mut header = vec![1, 1, 1, -5i8 as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+		// No include directories and a single file.
+		header.extend_from_slice(b"\0a.wat\0\0\0\0\0");
+		let mut unit = 4u16.to_le_bytes().to_vec();
+		unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+		unit.extend(header);
+		unit.extend(program);
+		let mut section = (unit.len() as u32).to_le_bytes().to_vec();
+		section.extend(unit);
+		section
+	}
+
+	/// Run the line program of the unit at the start of `section` and return its rows as pairs
+	/// of address and line.
+	pub(crate) fn rows(section: &[u8]) -> Vec<(u64, u64)> {
+		let mut reader = Reader { data: section, pos: 0 };
+		let unit_end = reader.u32().unwrap() as usize + 4;
+		assert_eq!(unit_end, section.len());
+		reader.u16().unwrap();
+		let header_length = reader.u32().unwrap() as usize;
+		reader.pos += header_length;
+
+		let mut rows = Vec::new();
+		let (mut address, mut line) = (0u64, 1i64);
+		while reader.pos < unit_end {
+			match reader.u8().unwrap() {
+				0 => {
+					let length = reader.uleb().unwrap() as usize;
+					match reader.bytes(length).unwrap() {
+						[DW_LNE_END_SEQUENCE] => {
+							address = 0;
+							line = 1;
+						},
+						[DW_LNE_SET_ADDRESS, operand @ ..] => {
+							let mut bytes = [0; 4];
+							bytes.copy_from_slice(operand);
+							address = u64::from(u32::from_le_bytes(bytes));
+						},
+						_ => panic!("Unexpected extended opcode"),
+					}
+				},
+				DW_LNS_COPY => rows.push((address, line as u64)),
+				DW_LNS_ADVANCE_PC => address += reader.uleb().unwrap(),
+				DW_LNS_ADVANCE_LINE => line += reader.sleb().unwrap(),
+				opcode if opcode >= 13 => {
+					address += u64::from((opcode - 13) / 14);
+					line += i64::from((opcode - 13) % 14) - 5;
+					rows.push((address, line as u64));
+				},
+				_ => panic!("Unexpected standard opcode"),
+			}
+		}
+		rows
+	}
+
+	/// The rows expected after rewriting the line program of `line_program` for the original
+	/// `addresses`: every row is moved to the first instruction belonging to its original
+	/// instruction.
+	fn expected_rows(
+		addresses: &[Vec<u64>],
+		metered: &elements::Module,
+		offsets: &OffsetMap,
+	) -> Vec<(u64, u64)> {
+		let metered_addresses = instruction_addresses(metered);
+		(1u32..)
+			.zip(addresses)
+			.flat_map(|(func_idx, body)| {
+				let metered_addresses = &metered_addresses;
+				(0..body.len() as u32).map(move |pos| {
+					let positions = offsets.instrumented_positions(func_idx, pos).unwrap();
+					(
+						metered_addresses[func_idx as usize - 1][positions.start as usize],
+						u64::from(pos) + 1,
+					)
+				})
+			})
+			.collect()
+	}
+
+	fn rewritten_rows(module: &elements::Module) -> Vec<(u64, u64)> {
+		let section =
+			module.custom_sections().find(|section| section.name() == SECTION_NAME).unwrap();
+		rows(section.payload())
+	}
+
+	fn inject_gas(module: &elements::Module) -> (elements::Module, OffsetMap) {
+		gas_metering::inject_with_offset_map(
+			module.clone(),
+			gas_metering::host_function::Injector::new("env", "gas"),
+			&gas_metering::ConstantCostRules::default(),
+			&gas_metering::GasMeteringConfig::new(),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn rewrite_line_table() {
+		let binary = wat::parse_str(
+			r#"
+(module
+	(func $f (export "f") (param i32) (result i32)
+		(if (result i32) (local.get 0)
+			(then (call $g (i32.const 1)))
+			(else (i32.const 2))
+		)
+	)
+	(func $g (param i32) (result i32)
+		(loop
+			(br_if 0 (i32.eqz (local.get 0)))
+		)
+		(i32.const 2)
+	)
+)
+"#,
+		)
+		.unwrap();
+		let module: elements::Module = elements::deserialize_buffer(&binary).unwrap();
+		let original_addresses = instruction_addresses(&module);
+		let original_rows = rows(&line_program(&original_addresses));
+
+		let (mut metered, offsets) = inject_gas(&module);
+		metered.set_custom_section(SECTION_NAME, line_program(&original_addresses));
+		assert_eq!(rewrite_debug_line(&binary, &mut metered, &offsets), Ok(vec![(0, 0)]));
+
+		let expected = expected_rows(&original_addresses, &metered, &offsets);
+		assert_eq!(rewritten_rows(&metered), expected);
+		assert_eq!(original_rows.len(), expected.len());
+		assert_ne!(original_rows, expected);
+
+		// The binary must be the one the module was deserialized from.
+		let other = wat::parse_str("(module (func (export \"f\")))").unwrap();
+		assert_eq!(
+			rewrite_debug_line(&other, &mut metered, &offsets),
+			Err("The offset map doesn't match the module")
+		);
+	}
+
+	#[test]
+	fn rewrite_padded_line_table() {
+		#[rustfmt::skip]
+		let binary = [
+			0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+			// A single function type without params and results.
+			0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+			0x03, 0x02, 0x01, 0x00,
+			// The body size and the `i32.const` are encoded with padding.
+			0x0a, 0x0c, 0x01, 0x89, 0x00,
+			0x00,
+			0x41, 0x81, 0x80, 0x80, 0x80, 0x00,
+			0x1a,
+			0x0b,
+		];
+		let module: elements::Module = elements::deserialize_buffer(&binary).unwrap();
+		let original_addresses = vec![vec![4, 10, 11]];
+		assert_ne!(instruction_addresses(&module), original_addresses);
+
+		let (mut metered, offsets) = inject_gas(&module);
+		metered.set_custom_section(SECTION_NAME, line_program(&original_addresses));
+		rewrite_debug_line(&binary, &mut metered, &offsets).unwrap();
+		assert_eq!(
+			rewritten_rows(&metered),
+			expected_rows(&original_addresses, &metered, &offsets)
+		);
+	}
+
+	#[test]
+	fn leb128() {
+		for value in [0, 1, 63, 64, 127, 128, 300, i64::MAX, -1, -64, -65, -300, i64::MIN] {
+			let mut output = Vec::new();
+			write_sleb(&mut output, value);
+			assert_eq!(Reader { data: &output, pos: 0 }.sleb(), Ok(value));
+			output.clear();
+			write_uleb(&mut output, value as u64);
+			assert_eq!(Reader { data: &output, pos: 0 }.uleb(), Ok(value as u64));
+		}
+	}
+}
@@ -0,0 +1,587 @@
+//! Preservation of the DWARF debug info of instrumented modules.
+//!
+//! The addresses of DWARF debug info in wasm are byte offsets within the code section, which
+//! become stale once instrumentation inserts instructions. With the `dwarf` feature enabled
+//! [`rewrite_debug_info`] maps the addresses of the `.debug_*` custom sections to the
+//! instrumented code section using the [`OffsetMap`] computed by the passes.
+//!
+//! The line programs of `.debug_line` are re-encoded by [`debug_line`](crate::debug_line). All
+//! other address references are patched in place, so that offsets between the sections stay
+//! valid:
+//!
+//! - address attributes of `.debug_info`, including the ones stored in `.debug_addr`,
+//! - `DW_AT_high_pc` stored as length relative to `DW_AT_low_pc`,
+//! - `DW_AT_stmt_list`, which moves with the re-encoded line programs,
+//! - the range and location lists of `.debug_ranges` and `.debug_loc`,
+//! - the address ranges of `.debug_aranges`.
+//!
+//! The range and location lists introduced by DWARF 5 aren't supported.
+//!
+//! The entry points of the passes that don't return an [`OffsetMap`] remove the DWARF sections
+//! instead of leaving them stale.
+
+use crate::{
+	debug_line,
+	offset_map::{AddressMap, OffsetMap},
+};
+use alloc::{collections::BTreeSet, vec::Vec};
+use gimli::{constants, AttributeValue, EndianSlice, LittleEndian};
+use parity_wasm::elements;
+
+/// The prefix of the names of the custom sections holding DWARF debug info.
+const SECTION_PREFIX: &str = ".debug_";
+
+const DEBUG_INFO: &str = ".debug_info";
+const DEBUG_LINE: &str = ".debug_line";
+const DEBUG_ADDR: &str = ".debug_addr";
+const DEBUG_RANGES: &str = ".debug_ranges";
+const DEBUG_LOC: &str = ".debug_loc";
+const DEBUG_ARANGES: &str = ".debug_aranges";
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Returns whether `module` has any DWARF custom sections.
+fn has_debug_sections(module: &elements::Module) -> bool {
+	module
+		.custom_sections()
+		.any(|section| section.name().starts_with(SECTION_PREFIX))
+}
+
+/// Map the addresses of the DWARF custom sections of the instrumented `module` from the code
+/// section of `original` to its own.
+///
+/// `original` is the binary of the module before instrumentation and `offsets` the
+/// [`OffsetMap`] returned by the pass, see e.g.
+/// [`gas_metering::inject_with_offset_map`](crate::gas_metering::inject_with_offset_map). Maps of
+/// several passes can be combined with [`OffsetMap::chain`]. The original offsets are read from
+/// `original`, while the rewritten addresses are those of `module` as serialized by
+/// `parity-wasm`. Nothing is changed if `offsets` is empty, as then the pass didn't instrument
+/// the module, or `module` has no DWARF sections.
+///
+/// This includes the line programs of `.debug_line`, hence
+/// [`rewrite_debug_line`](crate::rewrite_debug_line) must not be applied on top. Fails if the
+/// debug info can't be rewritten, in which case `module` is left unchanged. Stale addresses being
+/// worse than none, the debug info may then be removed with [`strip_debug_info`].
+pub fn rewrite_debug_info(
+	original: &[u8],
+	module: &mut elements::Module,
+	offsets: &OffsetMap,
+) -> Result<(), &'static str> {
+	if offsets.is_empty() || !has_debug_sections(module) {
+		return Ok(())
+	}
+
+	for (name, payload) in rewrite_sections(original, module, offsets)? {
+		module.set_custom_section(name, payload);
+	}
+	Ok(())
+}
+
+/// Remove all DWARF custom sections from `module`.
+pub fn strip_debug_info(module: &mut elements::Module) {
+	module.sections_mut().retain(|section| match section {
+		elements::Section::Custom(section) => !section.name().starts_with(SECTION_PREFIX),
+		_ => true,
+	});
+}
+
+/// The payloads of the sections patched in place.
+struct Sections {
+	debug_info: Vec<u8>,
+	debug_addr: Vec<u8>,
+	debug_ranges: Vec<u8>,
+	debug_loc: Vec<u8>,
+	/// The offsets of `.debug_addr` entries, range and location lists already patched.
+	patched: BTreeSet<(&'static str, usize)>,
+}
+
+/// Returns the rewritten payloads of all DWARF sections of `module` that need to change.
+fn rewrite_sections(
+	original: &[u8],
+	module: &elements::Module,
+	offsets: &OffsetMap,
+) -> Result<Vec<(&'static str, Vec<u8>)>, &'static str> {
+	let addresses = AddressMap::new(original, module, offsets)?;
+	let map = |address: u64| addresses.map(address).unwrap_or(address);
+	let section = |name: &str| {
+		module
+			.custom_sections()
+			.find(|section| section.name() == name)
+			.map(|section| section.payload())
+	};
+	let payload = |name: &str| section(name).unwrap_or(&[]);
+
+	let (debug_line, unit_offsets) =
+		debug_line::rewrite_line_programs(payload(DEBUG_LINE), |address| addresses.map(address))?;
+
+	let dwarf = gimli::Dwarf::load(|id| {
+		Ok::<_, gimli::Error>(EndianSlice::new(payload(id.name()), LittleEndian))
+	})
+	.map_err(|_| "Failed to load the DWARF sections")?;
+	let mut sections = Sections {
+		debug_info: payload(DEBUG_INFO).to_vec(),
+		debug_addr: payload(DEBUG_ADDR).to_vec(),
+		debug_ranges: payload(DEBUG_RANGES).to_vec(),
+		debug_loc: payload(DEBUG_LOC).to_vec(),
+		patched: BTreeSet::new(),
+	};
+	let mut units = dwarf.units();
+	while let Some(header) = units.next().map_err(|_| "Invalid .debug_info")? {
+		let unit = dwarf.unit(header).map_err(|_| "Invalid .debug_info")?;
+		rewrite_unit(&dwarf, &unit, &map, &unit_offsets, &mut sections)?;
+	}
+	let mut debug_aranges = payload(DEBUG_ARANGES).to_vec();
+	rewrite_aranges(&mut debug_aranges, &map)?;
+
+	let rewritten = [
+		(DEBUG_INFO, sections.debug_info),
+		(DEBUG_LINE, debug_line),
+		(DEBUG_ADDR, sections.debug_addr),
+		(DEBUG_RANGES, sections.debug_ranges),
+		(DEBUG_LOC, sections.debug_loc),
+		(DEBUG_ARANGES, debug_aranges),
+	];
+	Ok(rewritten.into_iter().filter(|(name, _)| section(name).is_some()).collect())
+}
+
+/// Patch the address references of the entries of `unit`.
+fn rewrite_unit(
+	dwarf: &gimli::Dwarf<Reader>,
+	unit: &gimli::Unit<Reader>,
+	map: &impl Fn(u64) -> u64,
+	unit_offsets: &debug_line::UnitOffsets,
+	sections: &mut Sections,
+) -> Result<(), &'static str> {
+	const INVALID: &str = "Invalid .debug_info";
+	const UNSUPPORTED_LISTS: &str = "DWARF 5 range and location lists aren't supported";
+	let unit_start = unit.header.offset().as_debug_info_offset().ok_or(INVALID)?.0;
+	let address_size = usize::from(unit.header.address_size());
+	if !matches!(address_size, 4 | 8) {
+		return Err("Unsupported address size in .debug_info")
+	}
+	let version = unit.header.version();
+
+	let mut entries = unit.entries_raw(None).map_err(|_| INVALID)?;
+	while !entries.is_empty() {
+		let abbreviation = match entries.read_abbreviation().map_err(|_| INVALID)? {
+			Some(abbreviation) => abbreviation,
+			None => continue,
+		};
+		let mut low_pc = None;
+		// The position, size, form and value of `DW_AT_high_pc` given as length.
+		let mut high_pc = None;
+		for spec in abbreviation.attributes() {
+			let start = entries.next_offset().0;
+			let attribute = entries.read_attribute(*spec).map_err(|_| INVALID)?;
+			let pos = unit_start + start;
+			let size = entries.next_offset().0 - start;
+
+			match attribute.value() {
+				AttributeValue::Addr(address) => {
+					if attribute.name() == constants::DW_AT_low_pc {
+						low_pc = Some(address);
+					}
+					write_value(&mut sections.debug_info, pos, size, spec.form(), map(address))?;
+				},
+				AttributeValue::DebugAddrIndex(index) => {
+					let address = dwarf.address(unit, index).map_err(|_| INVALID)?;
+					if attribute.name() == constants::DW_AT_low_pc {
+						low_pc = Some(address);
+					}
+					let pos = unit.addr_base.0 + index.0 * address_size;
+					if sections.patched.insert((DEBUG_ADDR, pos)) {
+						let form = constants::DW_FORM_addr;
+						write_value(
+							&mut sections.debug_addr,
+							pos,
+							address_size,
+							form,
+							map(address),
+						)?;
+					}
+				},
+				AttributeValue::Udata(length) if attribute.name() == constants::DW_AT_high_pc =>
+					high_pc = Some((pos, size, spec.form(), length)),
+				AttributeValue::DebugLineRef(offset) => {
+					let new_offset = unit_offsets
+						.iter()
+						.find(|(old, _)| *old == offset.0 as u64)
+						.map(|(_, new)| *new)
+						.ok_or("Invalid DW_AT_stmt_list")?;
+					write_value(&mut sections.debug_info, pos, size, spec.form(), new_offset)?;
+				},
+				AttributeValue::RangeListsRef(offset) => {
+					if version >= 5 {
+						return Err(UNSUPPORTED_LISTS)
+					}
+					if sections.patched.insert((DEBUG_RANGES, offset.0)) {
+						let list = &mut sections.debug_ranges;
+						rewrite_list(list, offset.0, address_size, unit.low_pc, map, false)?;
+					}
+				},
+				AttributeValue::LocationListsRef(offset) => {
+					if version >= 5 {
+						return Err(UNSUPPORTED_LISTS)
+					}
+					if sections.patched.insert((DEBUG_LOC, offset.0)) {
+						let list = &mut sections.debug_loc;
+						rewrite_list(list, offset.0, address_size, unit.low_pc, map, true)?;
+					}
+				},
+				AttributeValue::DebugRngListsIndex(_) | AttributeValue::DebugLocListsIndex(_) =>
+					return Err(UNSUPPORTED_LISTS),
+				_ => {},
+			}
+		}
+
+		if let Some((pos, size, form, length)) = high_pc {
+			let low_pc = low_pc.ok_or("DW_AT_high_pc is relative to a missing DW_AT_low_pc")?;
+			let end = map(low_pc.wrapping_add(length));
+			let new_length = end.saturating_sub(map(low_pc));
+			write_value(&mut sections.debug_info, pos, size, form, new_length)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Patch the entries of the range list, or location list if `locations` is set, at `offset` of
+/// the DWARF 4 `section`.
+///
+/// The entries are relative to the base address `base` of their unit unless the list selects
+/// another one.
+fn rewrite_list(
+	section: &mut [u8],
+	offset: usize,
+	address_size: usize,
+	base: u64,
+	map: &impl Fn(u64) -> u64,
+	locations: bool,
+) -> Result<(), &'static str> {
+	let max_address = u64::MAX >> (64 - 8 * address_size as u32);
+	let form = constants::DW_FORM_addr;
+	let (mut base, mut new_base) = (base, map(base));
+	let mut pos = offset;
+	loop {
+		let begin = read_value(section, pos, address_size)?;
+		let end = read_value(section, pos + address_size, address_size)?;
+		if begin == 0 && end == 0 {
+			return Ok(())
+		}
+
+		if begin == max_address {
+			base = end;
+			new_base = map(end);
+			write_value(section, pos + address_size, address_size, form, new_base)?;
+			pos += 2 * address_size;
+			continue
+		}
+		let new_begin = map(base.wrapping_add(begin)).wrapping_sub(new_base);
+		let new_end = map(base.wrapping_add(end)).wrapping_sub(new_base);
+		write_value(section, pos, address_size, form, new_begin & max_address)?;
+		write_value(section, pos + address_size, address_size, form, new_end & max_address)?;
+		pos += 2 * address_size;
+		if locations {
+			// Skip the location expression.
+			pos += 2 + read_value(section, pos, 2)? as usize;
+		}
+	}
+}
+
+/// Patch the address ranges of all sets of `.debug_aranges`.
+fn rewrite_aranges(section: &mut [u8], map: &impl Fn(u64) -> u64) -> Result<(), &'static str> {
+	let form = constants::DW_FORM_addr;
+	let mut pos = 0;
+	while pos < section.len() {
+		let set_start = pos;
+		let (length, offset_size) = match read_value(section, pos, 4)? {
+			0xffff_ffff => (read_value(section, pos + 4, 8)?, 8),
+			length => (length, 4),
+		};
+		pos += if offset_size == 8 { 12 } else { 4 };
+		let set_end = pos.checked_add(length as usize).ok_or("Invalid .debug_aranges")?;
+		// Skip the version and the offset into `.debug_info`.
+		pos += 2 + offset_size;
+		let address_size = read_value(section, pos, 1)? as usize;
+		if read_value(section, pos + 1, 1)? != 0 || !matches!(address_size, 4 | 8) {
+			return Err("Unsupported .debug_aranges")
+		}
+		// The first tuple is aligned to its size relative to the start of the set.
+		let tuple_size = 2 * address_size;
+		pos = set_start + (pos + 2 - set_start + tuple_size - 1) / tuple_size * tuple_size;
+
+		while pos + tuple_size <= set_end {
+			let address = read_value(section, pos, address_size)?;
+			let length = read_value(section, pos + address_size, address_size)?;
+			if address == 0 && length == 0 {
+				break
+			}
+			let new_address = map(address);
+			let new_length = map(address.wrapping_add(length)).saturating_sub(new_address);
+			write_value(section, pos, address_size, form, new_address)?;
+			write_value(section, pos + address_size, address_size, form, new_length)?;
+			pos += tuple_size;
+		}
+		pos = set_end;
+	}
+	Ok(())
+}
+
+/// Read the little endian value of `size` bytes at `pos` of `section`.
+fn read_value(section: &[u8], pos: usize, size: usize) -> Result<u64, &'static str> {
+	let bytes = section.get(pos..pos + size).ok_or("Unexpected end of a DWARF section")?;
+	Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | u64::from(*byte)))
+}
+
+/// Overwrite the `size` bytes at `pos` of `section` with `value` encoded as `form`.
+///
+/// LEB128 values are padded to keep their size.
+fn write_value(
+	section: &mut [u8],
+	pos: usize,
+	size: usize,
+	form: constants::DwForm,
+	value: u64,
+) -> Result<(), &'static str> {
+	let bytes = section.get_mut(pos..pos + size).ok_or("Unexpected end of a DWARF section")?;
+	let bits = match form {
+		constants::DW_FORM_udata => 7 * size,
+		constants::DW_FORM_addr |
+		constants::DW_FORM_data1 |
+		constants::DW_FORM_data2 |
+		constants::DW_FORM_data4 |
+		constants::DW_FORM_data8 |
+		constants::DW_FORM_sec_offset => 8 * size,
+		_ => return Err("Unsupported form of a DWARF address"),
+	};
+	if bits < 64 && value >> bits != 0 {
+		return Err("The rewritten DWARF address doesn't fit its form")
+	}
+
+	if form == constants::DW_FORM_udata {
+		for (i, byte) in bytes.iter_mut().enumerate() {
+			let continuation = if i + 1 < size { 0x80 } else { 0 };
+			*byte = (value >> (7 * i)) as u8 & 0x7f | continuation;
+		}
+	} else {
+		bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		debug_line::tests::*, gas_metering, stack_limiter, test_utils::parse_wat,
+		ConstantStackCostRules, StackLimiterConfig,
+	};
+
+	fn section<'a>(module: &'a elements::Module, name: &str) -> &'a [u8] {
+		module
+			.custom_sections()
+			.find(|section| section.name() == name)
+			.unwrap()
+			.payload()
+	}
+
+	/// The addresses of the first instruction and right after the end of every function body.
+	fn function_ranges(module: &elements::Module) -> Vec<(u64, u64)> {
+		instruction_addresses(module)
+			.iter()
+			.map(|body| (body[0], body[body.len() - 1] + 1))
+			.collect()
+	}
+
+	/// Add DWARF 4 debug info with a subprogram and line program for every function of `module`.
+	fn add_debug_info(module: &mut elements::Module) {
+		let functions = function_ranges(module);
+		let u32_bytes = |value: u64| (value as u32).to_le_bytes();
+
+		module.set_custom_section(
+			".debug_abbrev",
+			vec![
+				// DW_TAG_compile_unit with DW_AT_stmt_list, DW_AT_low_pc and DW_AT_ranges.
+				1, 0x11, 1, 0x10, 0x17, 0x11, 0x01, 0x55, 0x17, 0, 0,
+				// DW_TAG_subprogram with DW_AT_low_pc and DW_AT_high_pc.
+				2, 0x2e, 0, 0x11, 0x01, 0x12, 0x06, 0, 0, 0,
+			],
+		);
+
+		let mut info = vec![4, 0, 0, 0, 0, 0, 4, 1];
+		info.extend_from_slice(&[0; 8]);
+		info.extend_from_slice(&u32_bytes(0));
+		for (low_pc, end) in &functions {
+			info.push(2);
+			info.extend_from_slice(&u32_bytes(*low_pc));
+			info.extend_from_slice(&u32_bytes(end - low_pc));
+		}
+		info.push(0);
+		let mut debug_info = u32_bytes(info.len() as u64).to_vec();
+		debug_info.extend(info);
+		module.set_custom_section(".debug_info", debug_info);
+
+		let mut debug_ranges = Vec::new();
+		let mut debug_aranges = vec![0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0];
+		for (low_pc, end) in functions.iter().chain(&[(0, 0)]) {
+			debug_ranges.extend_from_slice(&u32_bytes(*low_pc));
+			debug_ranges.extend_from_slice(&u32_bytes(*end));
+			debug_aranges.extend_from_slice(&u32_bytes(*low_pc));
+			debug_aranges.extend_from_slice(&u32_bytes(end - low_pc));
+		}
+		let length = u32_bytes(debug_aranges.len() as u64 - 4);
+		debug_aranges[..4].copy_from_slice(&length);
+		module.set_custom_section(".debug_ranges", debug_ranges);
+		module.set_custom_section(".debug_aranges", debug_aranges);
+
+		let debug_line = line_program(&instruction_addresses(module));
+		module.set_custom_section(".debug_line", debug_line);
+	}
+
+	/// Check that the debug info added by [`add_debug_info`] matches the first `count` functions
+	/// of `module`.
+	fn assert_debug_info(module: &elements::Module, count: usize) {
+		let functions = &function_ranges(module)[..count];
+		let read = |name, pos| read_value(section(module, name), pos, 4).unwrap();
+
+		let subprograms = (0..count)
+			.map(|i| (read(".debug_info", 25 + 9 * i), read(".debug_info", 29 + 9 * i)))
+			.collect::<Vec<_>>();
+		let ranges = (0..count)
+			.map(|i| (read(".debug_ranges", 8 * i), read(".debug_ranges", 8 * i + 4)))
+			.collect::<Vec<_>>();
+		let aranges = (0..count)
+			.map(|i| (read(".debug_aranges", 16 + 8 * i), read(".debug_aranges", 20 + 8 * i)))
+			.collect::<Vec<_>>();
+		let lengths = functions
+			.iter()
+			.map(|(low_pc, end)| (*low_pc, end - low_pc))
+			.collect::<Vec<_>>();
+		assert_eq!(subprograms, lengths);
+		assert_eq!(aranges, lengths);
+		assert_eq!(ranges, functions);
+
+		// Every sequence of the line program starts at its function.
+		let rows = rows(section(module, ".debug_line"));
+		for (low_pc, _) in functions {
+			assert!(rows.contains(&(*low_pc, 1)));
+		}
+	}
+
+	#[test]
+	fn rewrite_all_sections() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func $f (export "f") (param i32) (result i32)
+		(if (result i32) (local.get 0)
+			(then (call $g (i32.const 1)))
+			(else (i32.const 2))
+		)
+	)
+	(func $g (param i32) (result i32)
+		(loop
+			(br_if 0 (i32.eqz (local.get 0)))
+		)
+		(i32.const 2)
+	)
+)
+"#,
+		);
+		add_debug_info(&mut module);
+		assert_debug_info(&module, 2);
+		let binary = elements::serialize(module.clone()).unwrap();
+
+		let (metered, gas_offsets) = gas_metering::inject_with_offset_map(
+			module,
+			gas_metering::host_function::Injector::new("env", "gas"),
+			&gas_metering::ConstantCostRules::default(),
+			&gas_metering::GasMeteringConfig::new(),
+		)
+		.unwrap();
+		let mut rewritten = metered.clone();
+		rewrite_debug_info(&binary, &mut rewritten, &gas_offsets).unwrap();
+		assert_debug_info(&rewritten, 2);
+
+		let (mut limited, stack_offsets) = stack_limiter::inject_with_offset_map(
+			metered,
+			&StackLimiterConfig::new(1024),
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+		rewrite_debug_info(&binary, &mut limited, &gas_offsets.chain(&stack_offsets)).unwrap();
+		assert_debug_info(&limited, 2);
+	}
+
+	#[test]
+	fn reject_invalid_debug_info() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func (export "f")
+		i32.const 1
+		drop
+	)
+)
+"#,
+		);
+		module.set_custom_section(".debug_info", vec![0xff; 4]);
+		module.set_custom_section(".debug_str", b"f\0".to_vec());
+		let binary = elements::serialize(module.clone()).unwrap();
+
+		let (mut limited, offsets) = stack_limiter::inject_with_offset_map(
+			module,
+			&StackLimiterConfig::new(1024),
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+		let unchanged = limited.clone();
+		assert_eq!(rewrite_debug_info(&binary, &mut limited, &offsets), Err("Invalid .debug_info"));
+		assert_eq!(limited, unchanged);
+
+		strip_debug_info(&mut limited);
+		assert!(!has_debug_sections(&limited));
+	}
+
+	#[test]
+	fn strip_without_offset_map() {
+		let mut module = parse_wat(
+			r#"
+(module
+	(func (export "f") (param i32) (result i32)
+		(i32.add (local.get 0) (i32.const 1))
+	)
+)
+"#,
+		);
+		add_debug_info(&mut module);
+
+		let metered = gas_metering::inject(
+			module.clone(),
+			gas_metering::host_function::Injector::new("env", "gas"),
+			&gas_metering::ConstantCostRules::default(),
+		)
+		.unwrap();
+		assert!(!has_debug_sections(&metered));
+		let limited = stack_limiter::inject(module.clone(), 1024).unwrap();
+		assert!(!has_debug_sections(&limited));
+
+		// Skipping an already applied pass leaves the module as it is.
+		let config = StackLimiterConfig::new(1024).with_manifest(true);
+		let (limited, _) = stack_limiter::inject_with_offset_map(
+			module,
+			&config,
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+		assert!(has_debug_sections(&limited));
+		let config = config.with_repeated_pass(crate::RepeatedPass::Skip);
+		let skipped = stack_limiter::inject_with_config(
+			limited.clone(),
+			&config,
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+		assert_eq!(skipped, limited);
+	}
+}
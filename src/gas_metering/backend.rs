@@ -2,7 +2,11 @@
 use parity_wasm::elements;
 
 /// Implementation details of the specific method of the gas metering.
+///
+/// New kinds of gas meters may be added in minor releases. Use [`GasMeter::imported_global`] to
+/// construct an [`ImportedGlobal`](GasMeter::ImportedGlobal) gas meter.
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum GasMeter {
 	/// Gas metering with an external function.
 	External {
@@ -20,6 +24,33 @@ pub enum GasMeter {
 		/// Cost of the gas function execution.
 		cost: u64,
 	},
+	/// Gas metering with a local function and an imported mutable global.
+	#[non_exhaustive]
+	ImportedGlobal {
+		/// Name of the module to import the global from.
+		module: &'static str,
+		/// Name of the mutable global to be imported.
+		global: &'static str,
+		/// Body of the local gas counting function to be injected.
+		func_instructions: elements::Instructions,
+		/// Cost of the gas function execution.
+		cost: u64,
+	},
+}
+
+impl GasMeter {
+	/// Create a [`GasMeter::ImportedGlobal`] importing the mutable `global` from `module`.
+	///
+	/// The local gas function with the body `func_instructions` charges from the global, which
+	/// is imported after all other imported globals. Its execution costs `cost`.
+	pub fn imported_global(
+		module: &'static str,
+		global: &'static str,
+		func_instructions: elements::Instructions,
+		cost: u64,
+	) -> Self {
+		GasMeter::ImportedGlobal { module, global, func_instructions, cost }
+	}
 }
 
 use super::Rules;
@@ -27,6 +58,40 @@ use super::Rules;
 pub trait Backend {
 	/// Provides the gas metering implementation details.  
 	fn gas_meter<R: Rules>(self, module: &elements::Module, rules: &R) -> GasMeter;
+
+	/// Whether the costs of the metered blocks are accumulated in a local and only charged at
+	/// certain points. See [`lazy`] for details.
+	fn lazy(&self) -> bool {
+		false
+	}
+
+	/// The minimal number of loops a metered block must be nested in for the body of the local
+	/// gas function to be inlined into it instead of calling the function.
+	///
+	/// `None` never inlines. This only applies to the local gas function of
+	/// [`GasMeter::Internal`] and [`GasMeter::ImportedGlobal`] if it is one of the gas functions
+	/// injected by this crate that only reads its argument, like the one of [`mutable_global`].
+	/// Any other gas function is always called. See [`inline_global`] for details.
+	fn inline_min_loop_depth(&self) -> Option<u32> {
+		None
+	}
+
+	/// Module and name of a host function to be imported, which the local gas function of
+	/// [`GasMeter::Internal`] calls when running out of gas.
+	///
+	/// The function takes no arguments. It is imported after all other imported functions, so
+	/// its index is the number of function imports of the module passed to
+	/// [`gas_meter`](Self::gas_meter). Defaults to `None`.
+	fn out_of_gas_function(&self) -> Option<(&'static str, &'static str)> {
+		None
+	}
+
+	/// The name of the backend recorded in the manifest of instrumented modules.
+	///
+	/// The backends of this crate are named after their modules. Defaults to `custom`.
+	fn name(&self) -> &'static str {
+		"custom"
+	}
 }
 
 /// Gas metering with an external host function.
@@ -51,6 +116,10 @@ pub mod host_function {
 		fn gas_meter<R: Rules>(self, _module: &Module, _rules: &R) -> GasMeter {
 			GasMeter::External { module: self.module, function: self.name }
 		}
+
+		fn name(&self) -> &'static str {
+			"host_function"
+		}
 	}
 }
 
@@ -69,7 +138,178 @@ pub mod host_function {
 /// bloat. This is a known issue to be fixed in upcoming versions.
 pub mod mutable_global {
 	use super::{Backend, GasMeter, Rules};
-	use alloc::vec;
+	use alloc::{vec, vec::Vec};
+	use parity_wasm::elements::{self, Instruction, Module};
+	/// Injects a mutable global variable and a local function to the module to track
+	/// current gas left.
+	///
+	/// The function is called in every metering block. In case of falling out of gas, the
+	/// configured [`OutOfGas`] action is performed. The execution engine should take care of
+	/// getting the current global value and setting it back in order to sync the gas left value
+	/// during an execution.
+	pub struct Injector {
+		/// The export name of the gas tracking global.
+		pub global_name: &'static str,
+		/// What to do when running out of gas.
+		out_of_gas: OutOfGas,
+	}
+
+	/// The action performed by the local gas function when running out of gas.
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub enum OutOfGas {
+		/// Set the global to the sentinel value `U64::MAX` and call the `unreachable` instruction.
+		Sentinel,
+		/// Call the imported host function `function` from `module`, which takes no arguments.
+		///
+		/// The host function is supposed to never return, e.g. by trapping with a dedicated
+		/// error. Should it return nonetheless, the `unreachable` instruction is called. The
+		/// global is left untouched.
+		HostFunction { module: &'static str, function: &'static str },
+	}
+
+	impl Injector {
+		pub fn new(global_name: &'static str) -> Self {
+			Self { global_name, out_of_gas: OutOfGas::Sentinel }
+		}
+
+		/// Set the action performed when running out of gas. Defaults to [`OutOfGas::Sentinel`].
+		pub fn with_out_of_gas(mut self, out_of_gas: OutOfGas) -> Self {
+			self.out_of_gas = out_of_gas;
+			self
+		}
+	}
+
+	impl Backend for Injector {
+		fn gas_meter<R: Rules>(self, module: &Module, rules: &R) -> GasMeter {
+			let gas_global_idx = module.globals_space() as u32;
+			let fail_instructions = match self.out_of_gas {
+				OutOfGas::Sentinel => sentinel_instructions(gas_global_idx),
+				OutOfGas::HostFunction { .. } => {
+					// The host function is imported after all other imported functions.
+					let func_idx = module.import_count(elements::ImportCountType::Function) as u32;
+					vec![Instruction::Call(func_idx), Instruction::Unreachable]
+				},
+			};
+			let func_instructions = func_instructions(gas_global_idx, &fail_instructions);
+
+			GasMeter::Internal {
+				global: self.global_name,
+				cost: super::gas_function_cost(&func_instructions, &fail_instructions, rules),
+				func_instructions: elements::Instructions::new(func_instructions),
+			}
+		}
+
+		fn out_of_gas_function(&self) -> Option<(&'static str, &'static str)> {
+			match self.out_of_gas {
+				OutOfGas::Sentinel => None,
+				OutOfGas::HostFunction { module, function } => Some((module, function)),
+			}
+		}
+
+		fn name(&self) -> &'static str {
+			"mutable_global"
+		}
+	}
+
+	/// The body of the local gas function charging from the global `gas_global_idx`.
+	///
+	/// The `fail_instructions` are executed when running out of gas.
+	pub(super) fn func_instructions(
+		gas_global_idx: u32,
+		fail_instructions: &[Instruction],
+	) -> Vec<Instruction> {
+		let mut instructions = vec![
+			Instruction::GetGlobal(gas_global_idx),
+			Instruction::GetLocal(0),
+			Instruction::I64GeU,
+			Instruction::If(elements::BlockType::NoResult),
+			Instruction::GetGlobal(gas_global_idx),
+			Instruction::GetLocal(0),
+			Instruction::I64Sub,
+			Instruction::SetGlobal(gas_global_idx),
+			Instruction::Else,
+		];
+		instructions.extend_from_slice(fail_instructions);
+		instructions.extend_from_slice(&[Instruction::End, Instruction::End]);
+		instructions
+	}
+
+	/// Set the sentinel value `U64::MAX` and trap.
+	pub(super) fn sentinel_instructions(gas_global_idx: u32) -> Vec<Instruction> {
+		vec![
+			Instruction::I64Const(-1i64),
+			Instruction::SetGlobal(gas_global_idx),
+			Instruction::Unreachable,
+		]
+	}
+}
+
+/// Gas metering with an imported mutable global.
+///
+/// # Note
+///
+/// This works like [`mutable_global`], but the global is imported from the host instead of being
+/// defined and exported by the module. Execution engines that can share a mutable global between
+/// the host and the module don't need to sync the gas left through an export then.
+///
+/// The import is added after all existing global imports. Since this shifts the indices of all
+/// globals defined by the module, all references to them are fixed up.
+pub mod imported_global {
+	use super::{mutable_global, Backend, GasMeter, Rules};
+	use parity_wasm::elements::{self, Module};
+	/// Injects an import of a mutable global variable and a local function to the module to track
+	/// current gas left.
+	///
+	/// The function is called in every metering block. In case of falling out of gas, the global is
+	/// set to the sentinel value `U64::MAX` and `unreachable` instruction is called.
+	pub struct Injector {
+		/// The name of the module to import the gas tracking global from.
+		pub module: &'static str,
+		/// The name of the gas tracking global to import.
+		pub global_name: &'static str,
+	}
+
+	impl Injector {
+		pub fn new(module: &'static str, global_name: &'static str) -> Self {
+			Self { module, global_name }
+		}
+	}
+
+	impl Backend for Injector {
+		fn gas_meter<R: Rules>(self, module: &Module, rules: &R) -> GasMeter {
+			let gas_global_idx = module.import_count(elements::ImportCountType::Global) as u32;
+			let fail_instructions = mutable_global::sentinel_instructions(gas_global_idx);
+			let func_instructions =
+				mutable_global::func_instructions(gas_global_idx, &fail_instructions);
+
+			let cost = super::gas_function_cost(&func_instructions, &fail_instructions, rules);
+			GasMeter::imported_global(
+				self.module,
+				self.global_name,
+				elements::Instructions::new(func_instructions),
+				cost,
+			)
+		}
+
+		fn name(&self) -> &'static str {
+			"imported_global"
+		}
+	}
+}
+
+/// Gas metering with a mutable global holding a signed counter.
+///
+/// # Note
+///
+/// This works like [`mutable_global`] with the same contract for syncing the gas left with the
+/// host. However, the local gas function subtracts the charged amount unconditionally and traps
+/// if the result became negative. Compared to an unsigned comparison before the subtraction, this
+/// has a shorter critical path and only a single branch.
+///
+/// As the counter is signed, the gas left set by the host must not exceed `i64::MAX`.
+pub mod signed_global {
+	use super::{mutable_global, Backend, GasMeter, Rules};
+	use alloc::{vec, vec::Vec};
 	use parity_wasm::elements::{self, Instruction, Module};
 	/// Injects a mutable global variable and a local function to the module to track
 	/// current gas left.
@@ -92,48 +332,240 @@ pub mod mutable_global {
 	impl Backend for Injector {
 		fn gas_meter<R: Rules>(self, module: &Module, rules: &R) -> GasMeter {
 			let gas_global_idx = module.globals_space() as u32;
-
-			let func_instructions = vec![
-				Instruction::GetGlobal(gas_global_idx),
-				Instruction::GetLocal(0),
-				Instruction::I64GeU,
-				Instruction::If(elements::BlockType::NoResult),
-				Instruction::GetGlobal(gas_global_idx),
-				Instruction::GetLocal(0),
-				Instruction::I64Sub,
-				Instruction::SetGlobal(gas_global_idx),
-				Instruction::Else,
-				// sentinel val u64::MAX
-				Instruction::I64Const(-1i64),           // non-charged instruction
-				Instruction::SetGlobal(gas_global_idx), // non-charged instruction
-				Instruction::Unreachable,               // non-charged instruction
-				Instruction::End,
-				Instruction::End,
-			];
-
-			// calculate gas used for the gas charging func execution itself
-			let mut gas_fn_cost = func_instructions.iter().fold(0, |cost: u64, instruction| {
-				cost.saturating_add(rules.instruction_cost(instruction).unwrap_or(u32::MAX).into())
-			});
-			// don't charge for the instructions used to fail when out of gas
-			let fail_cost = [
-				Instruction::I64Const(-1i64),           // non-charged instruction
-				Instruction::SetGlobal(gas_global_idx), // non-charged instruction
-				Instruction::Unreachable,               // non-charged instruction
-			]
-			.iter()
-			.fold(0, |cost: u64, instruction| {
-				cost.saturating_add(rules.instruction_cost(instruction).unwrap_or(u32::MAX).into())
-			});
-
-			// the fail costs are a subset of the overall costs and hence this never underflows
-			gas_fn_cost -= fail_cost;
+			let fail_instructions = mutable_global::sentinel_instructions(gas_global_idx);
+			let func_instructions = func_instructions(gas_global_idx);
 
 			GasMeter::Internal {
 				global: self.global_name,
+				cost: super::gas_function_cost(&func_instructions, &fail_instructions, rules),
 				func_instructions: elements::Instructions::new(func_instructions),
-				cost: gas_fn_cost,
 			}
 		}
+
+		fn name(&self) -> &'static str {
+			"signed_global"
+		}
+	}
+
+	/// The body of the local gas function charging from the global `gas_global_idx`.
+	pub(super) fn func_instructions(gas_global_idx: u32) -> Vec<Instruction> {
+		let mut instructions = vec![
+			Instruction::GetGlobal(gas_global_idx),
+			Instruction::GetLocal(0),
+			Instruction::I64Sub,
+			Instruction::TeeLocal(0),
+			Instruction::SetGlobal(gas_global_idx),
+			Instruction::GetLocal(0),
+			Instruction::I64Const(0),
+			Instruction::I64LtS,
+			Instruction::If(elements::BlockType::NoResult),
+		];
+		instructions.extend_from_slice(&mutable_global::sentinel_instructions(gas_global_idx));
+		instructions.extend_from_slice(&[Instruction::End, Instruction::End]);
+		instructions
+	}
+}
+
+/// Recognize the body of a local gas function injected by one of the built-in backends.
+///
+/// Returns the index of the gas global it charges from and the index of the host function it
+/// calls when running out of gas, if any.
+pub(super) fn gas_function_global(body: &[elements::Instruction]) -> Option<(u32, Option<u32>)> {
+	let gas_global_idx = match body.first() {
+		Some(elements::Instruction::GetGlobal(global_idx)) => *global_idx,
+		_ => return None,
+	};
+	let sentinel = mutable_global::sentinel_instructions(gas_global_idx);
+	if body == mutable_global::func_instructions(gas_global_idx, &sentinel) ||
+		body == signed_global::func_instructions(gas_global_idx)
+	{
+		return Some((gas_global_idx, None))
+	}
+	match body.get(9) {
+		Some(elements::Instruction::Call(func_idx)) => {
+			let fail_instructions =
+				[elements::Instruction::Call(*func_idx), elements::Instruction::Unreachable];
+			(body == mutable_global::func_instructions(gas_global_idx, &fail_instructions))
+				.then(|| (gas_global_idx, Some(*func_idx)))
+		},
+		_ => None,
+	}
+}
+
+/// Whether the local gas function with the given `body` can be inlined into metered blocks.
+///
+/// Only the gas functions recognized by [`gas_function_global`] which don't write their argument
+/// are inlined, as the inlined body shares the locals of the function it is inlined into.
+pub(super) fn is_inlinable_gas_function(body: &[elements::Instruction]) -> bool {
+	gas_function_global(body).is_some() &&
+		!body.iter().any(|instruction| {
+			matches!(
+				instruction,
+				elements::Instruction::SetLocal(_) | elements::Instruction::TeeLocal(_)
+			)
+		})
+}
+
+/// Calculate the gas used for the execution of the local gas function with the given `body`, as
+/// recognized by [`gas_function_global`].
+pub(super) fn local_gas_function_cost<R: Rules>(
+	body: &[elements::Instruction],
+	rules: &R,
+) -> Option<u64> {
+	let fail_instructions = match gas_function_global(body)? {
+		(gas_global_idx, None) => mutable_global::sentinel_instructions(gas_global_idx),
+		(_, Some(func_idx)) =>
+			alloc::vec![elements::Instruction::Call(func_idx), elements::Instruction::Unreachable],
+	};
+	Some(gas_function_cost(body, &fail_instructions, rules))
+}
+
+/// Calculate the gas used for the execution of the local gas function itself.
+///
+/// This doesn't include the `fail_instructions` used to fail when out of gas, which must be part
+/// of `func_instructions`.
+fn gas_function_cost<R: Rules>(
+	func_instructions: &[elements::Instruction],
+	fail_instructions: &[elements::Instruction],
+	rules: &R,
+) -> u64 {
+	let gas_fn_cost = func_instructions.iter().fold(0, |cost: u64, instruction| {
+		cost.saturating_add(rules.instruction_cost(instruction).unwrap_or(u32::MAX).into())
+	});
+	// don't charge for the instructions used to fail when out of gas
+	let fail_cost = fail_instructions.iter().fold(0, |cost: u64, instruction| {
+		cost.saturating_add(rules.instruction_cost(instruction).unwrap_or(u32::MAX).into())
+	});
+
+	// the fail costs are a subset of the overall costs and hence this never underflows
+	gas_fn_cost - fail_cost
+}
+
+/// Gas metering with a mutable global and inlined charging code.
+///
+/// This works exactly like [`mutable_global`] and uses the same local gas function and global.
+/// However, the body of the gas function is inlined at the start of metered blocks instead of
+/// calling the function. This avoids the overhead of a call, which dominates the costs of
+/// charging gas in interpreters, at the cost of a larger module.
+///
+/// In order to balance size and speed, only metered blocks which are nested in a certain
+/// number of loops are inlined, as those are likely to be executed most often. The other blocks
+/// still call the gas function.
+pub mod inline_global {
+	use super::{mutable_global, Backend, GasMeter, Rules};
+	use parity_wasm::elements::Module;
+
+	/// Injects a mutable global variable and a local function to the module to track current gas
+	/// left and inlines the body of the function into metered blocks within loops.
+	pub struct Injector {
+		/// The export name of the gas tracking global.
+		pub global_name: &'static str,
+		/// The minimal number of loops a metered block must be nested in to be inlined.
+		min_loop_depth: u32,
+		/// What to do when running out of gas.
+		out_of_gas: mutable_global::OutOfGas,
+	}
+
+	impl Injector {
+		/// Create a new [`Injector`] which only inlines into metered blocks within loops.
+		pub fn new(global_name: &'static str) -> Self {
+			Self { global_name, min_loop_depth: 1, out_of_gas: mutable_global::OutOfGas::Sentinel }
+		}
+
+		/// Set the action performed when running out of gas, see
+		/// [`mutable_global::Injector::with_out_of_gas`].
+		pub fn with_out_of_gas(mut self, out_of_gas: mutable_global::OutOfGas) -> Self {
+			self.out_of_gas = out_of_gas;
+			self
+		}
+
+		/// Only inline into metered blocks nested in at least `depth` loops.
+		///
+		/// Zero inlines into every metered block, favouring speed. Higher values favour size.
+		pub fn with_min_loop_depth(mut self, depth: u32) -> Self {
+			self.min_loop_depth = depth;
+			self
+		}
+	}
+
+	impl Backend for Injector {
+		fn gas_meter<R: Rules>(self, module: &Module, rules: &R) -> GasMeter {
+			mutable_global::Injector::new(self.global_name)
+				.with_out_of_gas(self.out_of_gas)
+				.gas_meter(module, rules)
+		}
+
+		fn out_of_gas_function(&self) -> Option<(&'static str, &'static str)> {
+			mutable_global::Injector::new(self.global_name)
+				.with_out_of_gas(self.out_of_gas)
+				.out_of_gas_function()
+		}
+
+		fn inline_min_loop_depth(&self) -> Option<u32> {
+			Some(self.min_loop_depth)
+		}
+
+		fn name(&self) -> &'static str {
+			"inline_global"
+		}
+	}
+}
+
+/// Gas metering that charges the gas lazily.
+///
+/// Instead of charging the cost of every metered block right away, the costs are added to an
+/// `i64` local that is injected into every function. The accumulated costs are only charged
+/// through the wrapped backend and the local reset to zero:
+///
+/// - before every call, so that the gas of a caller is charged before the callee runs,
+/// - before every branch to a loop, so that every iteration of a loop is charged,
+/// - before every return, including branches to the function body and its final `end`.
+///
+/// Straight-line code consisting of many metered blocks therefore only charges once. The
+/// execution still runs out of gas deterministically since the charges happen at fixed points.
+/// However, the costs accumulated since the last charge aren't charged in case execution traps.
+/// Those are bounded by the size of the function, as no loop can be iterated without charging.
+/// Counted loops hoisted by
+/// [`GasMeteringConfig::with_loop_hoisting`](crate::gas_metering::GasMeteringConfig::with_loop_hoisting)
+/// are still charged right away.
+///
+/// If the wrapped backend inlines the gas function, like [`inline_global`] does, the charges
+/// within loops are inlined as well.
+pub mod lazy {
+	use super::{Backend, GasMeter, Rules};
+	use parity_wasm::elements::Module;
+
+	/// Wraps another backend in order to charge the gas lazily through it.
+	pub struct Injector<B> {
+		/// The backend used to charge the accumulated gas.
+		backend: B,
+	}
+
+	impl<B: Backend> Injector<B> {
+		pub fn new(backend: B) -> Self {
+			Self { backend }
+		}
+	}
+
+	impl<B: Backend> Backend for Injector<B> {
+		fn gas_meter<R: Rules>(self, module: &Module, rules: &R) -> GasMeter {
+			self.backend.gas_meter(module, rules)
+		}
+
+		fn lazy(&self) -> bool {
+			true
+		}
+
+		fn inline_min_loop_depth(&self) -> Option<u32> {
+			self.backend.inline_min_loop_depth()
+		}
+
+		fn out_of_gas_function(&self) -> Option<(&'static str, &'static str)> {
+			self.backend.out_of_gas_function()
+		}
+
+		fn name(&self) -> &'static str {
+			self.backend.name()
+		}
 	}
 }
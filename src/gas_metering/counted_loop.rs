@@ -0,0 +1,177 @@
+//! Detection of counted loops.
+//!
+//! A counted loop is a `loop` whose number of iterations can be computed right before it is
+//! entered. This allows to charge the gas for all iterations at once instead of once per
+//! iteration.
+
+use alloc::vec::Vec;
+use parity_wasm::elements::{BlockType, Instruction};
+
+/// The loop invariant value the induction variable is compared against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bound {
+	Local(u32),
+	Const(i32),
+}
+
+/// The comparison of the induction variable with the [`Bound`] that continues the loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+	NotEqual,
+	LessThanUnsigned,
+	LessThanSigned,
+}
+
+/// A loop that is executed a number of times only depending on the values of locals upon entry.
+///
+/// The body of such a loop doesn't contain any control flow instructions besides the `br_if`
+/// that continues the loop at its very end. It also doesn't write the locals the number of
+/// iterations depends on other than through the update of the induction variable right before
+/// that `br_if`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountedLoop {
+	/// The loop ends with:
+	///
+	/// ```wasm
+	/// local.get $i
+	/// i32.const 1
+	/// i32.add
+	/// local.tee $i
+	/// local.get $n ;; or i32.const $n
+	/// i32.ne ;; or i32.lt_u or i32.lt_s
+	/// br_if 0
+	/// ```
+	Increment { local: u32, bound: Bound, condition: Condition },
+	/// The loop ends with:
+	///
+	/// ```wasm
+	/// local.get $i
+	/// i32.const -1
+	/// i32.add
+	/// local.tee $i
+	/// br_if 0
+	/// ```
+	Decrement { local: u32 },
+}
+
+impl CountedLoop {
+	/// Returns all counted loops in `instructions` together with the position of their `loop`
+	/// instruction.
+	pub fn find(instructions: &[Instruction]) -> Vec<(usize, CountedLoop)> {
+		instructions
+			.iter()
+			.enumerate()
+			.filter(|(_, instruction)| {
+				matches!(instruction, Instruction::Loop(BlockType::NoResult | BlockType::Value(_)))
+			})
+			.filter_map(|(loop_pos, _)| {
+				Self::analyze(&instructions[loop_pos + 1..])
+					.map(|counted_loop| (loop_pos, counted_loop))
+			})
+			.collect()
+	}
+
+	/// Analyze the loop whose body starts with `instructions`.
+	fn analyze(instructions: &[Instruction]) -> Option<Self> {
+		use Instruction::*;
+
+		// The first control flow instruction must be the `br_if` ending the loop.
+		let br_pos = instructions.iter().position(|instruction| {
+			matches!(
+				instruction,
+				Block(_) | Loop(_) | If(_) | Else | End | Br(_) | BrIf(_) | BrTable(_) | Return
+			)
+		})?;
+		if instructions.get(br_pos + 1) != Some(&End) {
+			return None
+		}
+
+		let (body, counted_loop) = match &instructions[..=br_pos] {
+			[body @ .., GetLocal(local), I32Const(1), I32Add, TeeLocal(tee_local), bound, condition, BrIf(0)]
+				if local == tee_local =>
+			{
+				let bound = match bound {
+					// Comparing the induction variable with itself isn't loop invariant.
+					GetLocal(bound) if bound != local => Bound::Local(*bound),
+					I32Const(bound) => Bound::Const(*bound),
+					_ => return None,
+				};
+				let condition = match condition {
+					I32Ne => Condition::NotEqual,
+					I32LtU => Condition::LessThanUnsigned,
+					I32LtS => Condition::LessThanSigned,
+					_ => return None,
+				};
+				(body, Self::Increment { local: *local, bound, condition })
+			},
+			[body @ .., GetLocal(local), I32Const(-1), I32Add, TeeLocal(tee_local), BrIf(0)]
+				if local == tee_local =>
+				(body, Self::Decrement { local: *local }),
+			_ => return None,
+		};
+
+		let writes_used_local = body.iter().any(|instruction| match instruction {
+			SetLocal(idx) | TeeLocal(idx) => counted_loop.uses_local(*idx),
+			_ => false,
+		});
+		if writes_used_local {
+			return None
+		}
+
+		Some(counted_loop)
+	}
+
+	/// Whether the number of iterations depends on the local `idx`.
+	fn uses_local(&self, idx: u32) -> bool {
+		match self {
+			Self::Increment { local, bound: Bound::Local(bound), .. } =>
+				idx == *local || idx == *bound,
+			Self::Increment { local, .. } | Self::Decrement { local } => idx == *local,
+		}
+	}
+
+	/// Push instructions that compute the number of iterations of the loop.
+	///
+	/// They must be executed right before the loop is entered and leave the number as an `i64` on
+	/// the stack. The body is executed at least once, so the number is at least one. It is at most
+	/// `2^32`.
+	pub fn push_iterations(&self, instructions: &mut Vec<Instruction>) {
+		use Instruction::*;
+
+		match *self {
+			Self::Increment { local, bound, condition } => {
+				let bound = match bound {
+					Bound::Local(idx) => GetLocal(idx),
+					Bound::Const(value) => I32Const(value),
+				};
+				// The value of the induction variable after the first iteration.
+				let first = [GetLocal(local), I32Const(1), I32Add];
+
+				// The loop is continued until the induction variable reaches the bound.
+				instructions.push(bound.clone());
+				instructions.extend_from_slice(&first);
+				instructions.push(I32Sub);
+				// With `lt` the loop isn't continued at all if the bound is already reached after
+				// the first iteration.
+				let less_than = match condition {
+					Condition::NotEqual => None,
+					Condition::LessThanUnsigned => Some(I32LtU),
+					Condition::LessThanSigned => Some(I32LtS),
+				};
+				if let Some(less_than) = less_than {
+					instructions.push(I32Const(0));
+					instructions.extend_from_slice(&first);
+					instructions.push(bound);
+					instructions.push(less_than);
+					instructions.push(Select);
+				}
+			},
+			// The loop is continued until the induction variable is zero.
+			Self::Decrement { local } =>
+				instructions.extend_from_slice(&[GetLocal(local), I32Const(-1), I32Add]),
+		}
+
+		// Add the first iteration.
+		instructions.extend_from_slice(&[I64ExtendUI32, I64Const(1), I64Add]);
+	}
+}
@@ -0,0 +1,149 @@
+//! Static costs of instantiating a module.
+
+use super::Rules;
+use parity_wasm::elements::{self, Instruction};
+
+/// Calculate the gas to be charged for instantiating `module`.
+///
+/// Instantiating a module evaluates the initializer expressions of its globals and segments and
+/// copies the contents of its segments into memories and tables. None of this is covered by the
+/// metering injected by [`inject`](super::inject), so the embedder should charge the returned
+/// amount before instantiating the module. It consists of:
+///
+/// - the [`Rules::instruction_cost`] of every instruction of an initializer expression except the
+///   final `end`,
+/// - the [`Rules::segment_cost`] for every data and element segment,
+/// - the [`Rules::data_segment_byte_cost`] for every byte of a data segment,
+/// - the [`Rules::element_segment_entry_cost`] for every entry of an element segment.
+///
+/// The start function doesn't need to be accounted for, as its body is metered like any other
+/// function.
+///
+/// Returns `None` if an initializer expression contains an instruction forbidden by `rules`. The
+/// result saturates at `u64::MAX`.
+pub fn instantiation_cost<R: Rules>(module: &elements::Module, rules: &R) -> Option<u64> {
+	let init_expr_cost = |init_expr: &elements::InitExpr| {
+		init_expr
+			.code()
+			.iter()
+			.filter(|instruction| !matches!(instruction, Instruction::End))
+			.try_fold(0u64, |cost, instruction| {
+				Some(cost.saturating_add(rules.instruction_cost(instruction)?.into()))
+			})
+	};
+	let segment_cost = |offset: Option<&elements::InitExpr>, len: usize, entry_cost: u32| {
+		let offset_cost = offset.map_or(Some(0), init_expr_cost)?;
+		Some(
+			u64::from(rules.segment_cost())
+				.saturating_add(offset_cost)
+				.saturating_add((len as u64).saturating_mul(entry_cost.into())),
+		)
+	};
+
+	let globals = module.global_section().map(|gs| gs.entries()).unwrap_or(&[]);
+	let data_segments = module.data_section().map(|ds| ds.entries()).unwrap_or(&[]);
+	let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+
+	let global_costs = globals.iter().map(|global| init_expr_cost(global.init_expr()));
+	let data_costs = data_segments.iter().map(|segment| {
+		segment_cost(
+			segment.offset().as_ref(),
+			segment.value().len(),
+			rules.data_segment_byte_cost(),
+		)
+	});
+	let elem_costs = elem_segments.iter().map(|segment| {
+		segment_cost(
+			segment.offset().as_ref(),
+			segment.members().len(),
+			rules.element_segment_entry_cost(),
+		)
+	});
+
+	global_costs
+		.chain(data_costs)
+		.chain(elem_costs)
+		.try_fold(0u64, |total, cost| Some(total.saturating_add(cost?)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		gas_metering::{ConstantCostRules, MemoryGrowCost},
+		test_utils::parse_wat,
+	};
+
+	struct SegmentRules;
+
+	impl Rules for SegmentRules {
+		fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+			match instruction {
+				Instruction::GetGlobal(_) => None,
+				_ => Some(1),
+			}
+		}
+
+		fn memory_grow_cost(&self) -> MemoryGrowCost {
+			MemoryGrowCost::Free
+		}
+
+		fn call_per_local_cost(&self) -> u32 {
+			0
+		}
+
+		fn segment_cost(&self) -> u32 {
+			100
+		}
+
+		fn data_segment_byte_cost(&self) -> u32 {
+			10
+		}
+
+		fn element_segment_entry_cost(&self) -> u32 {
+			1000
+		}
+	}
+
+	#[test]
+	fn segments_and_globals() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "base" (global $base i32))
+	(global i64 (i64.const 0))
+	(memory 1)
+	(data (i32.const 0) "abc")
+	(data (i32.const 8) "de")
+	(table 2 funcref)
+	(elem (i32.const 0) $f $f)
+	(func $f)
+)
+"#,
+		);
+
+		// 1 global + 2 data segments with 5 bytes + 1 element segment with 2 entries
+		assert_eq!(
+			instantiation_cost(&module, &SegmentRules),
+			Some(1 + 200 + 2 + 50 + 100 + 1 + 2000)
+		);
+		// All the segment related costs default to zero.
+		assert_eq!(instantiation_cost(&module, &ConstantCostRules::default()), Some(4));
+	}
+
+	#[test]
+	fn forbidden_init_expr() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "base" (global $base i32))
+	(memory 1)
+	(data (global.get $base) "abc")
+)
+"#,
+		);
+
+		assert_eq!(instantiation_cost(&module, &SegmentRules), None);
+		assert_eq!(instantiation_cost(&module, &ConstantCostRules::default()), Some(1));
+	}
+}
@@ -2,17 +2,37 @@
 //!
 //! The primary public interface is the [`inject`] function which transforms a given
 //! module into one that charges gas for code to be executed. See function documentation for usage
-//! and details.
+//! and details. The gas for instantiating a module can be computed with [`instantiation_cost`].
+//! The metering of an already instrumented module can be checked with [`verify_metering`] and
+//! the instrumentation removed again with [`remove`]. A different cost schedule can be applied to
+//! it with [`reprice`].
 
 mod backend;
+mod counted_loop;
+mod instantiation;
+mod removal;
+mod repricing;
+mod schedule;
+
+pub use backend::{
+	host_function, imported_global, inline_global, lazy, mutable_global, signed_global, Backend,
+	GasMeter,
+};
+pub use instantiation::instantiation_cost;
+pub use removal::remove;
+pub use repricing::{reprice, RepriceError};
+pub use schedule::{schedule_hash, supported_instructions};
+pub use validation::verify_metering;
 
-pub use backend::{host_function, mutable_global, Backend, GasMeter};
-
-#[cfg(test)]
 mod validation;
 
-use alloc::{vec, vec::Vec};
+use crate::{
+	manifest::{self, RepeatedPass},
+	OffsetMap,
+};
+use alloc::{format, vec, vec::Vec};
 use core::{cmp::min, mem, num::NonZeroU32};
+use counted_loop::CountedLoop;
 use parity_wasm::{
 	builder,
 	elements::{self, IndexMap, Instruction, ValueType},
@@ -39,6 +59,32 @@ pub trait Rules {
 
 	/// A surcharge cost to calling a function that is added per local of that function.
 	fn call_per_local_cost(&self) -> u32;
+
+	/// Returns the cost for the passed `call` instruction calling the function `field` imported
+	/// from `module`.
+	///
+	/// This allows to price host functions by their name, which unlike their index isn't
+	/// changed by the instrumentation. Defaults to the `instruction_cost` of the instruction.
+	fn import_call_cost(&self, module: &str, field: &str, call: &Instruction) -> Option<u32> {
+		let _ = (module, field);
+		self.instruction_cost(call)
+	}
+
+	/// A cost charged by [`instantiation_cost`] for every data and element segment.
+	fn segment_cost(&self) -> u32 {
+		0
+	}
+
+	/// A cost charged by [`instantiation_cost`] for every byte initialized by a data segment.
+	fn data_segment_byte_cost(&self) -> u32 {
+		0
+	}
+
+	/// A cost charged by [`instantiation_cost`] for every table entry initialized by an element
+	/// segment.
+	fn element_segment_entry_cost(&self) -> u32 {
+		0
+	}
 }
 
 /// Dynamic costs for memory growth.
@@ -113,6 +159,62 @@ impl Rules for ConstantCostRules {
 	}
 }
 
+/// Configuration of the gas metering instrumentation.
+///
+/// Use [`inject_with_config`] to apply it to a module.
+#[derive(Debug, Clone, Default)]
+pub struct GasMeteringConfig {
+	loop_hoisting: bool,
+	manifest: bool,
+	repeated_pass: Option<RepeatedPass>,
+}
+
+impl GasMeteringConfig {
+	/// Create a new [`GasMeteringConfig`] with the same options [`inject`] uses.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Whether to charge the gas for all iterations of counted loops before entering them.
+	///
+	/// Disabled by default. A counted loop is a `loop` without any nested control flow that is
+	/// continued by a `br_if` at its end as long as an `i32` local, which is incremented or
+	/// decremented by one right before, hasn't reached a loop invariant bound. For such loops the
+	/// number of iterations is computed right before the loop and the gas for all of them is
+	/// charged at once instead of calling the gas function in every iteration. All other loops
+	/// are metered as usual.
+	///
+	/// The gas for all iterations is charged up front. Execution therefore runs out of gas
+	/// before entering a loop it can't afford to finish and the whole loop is charged for if
+	/// execution traps within it.
+	pub fn with_loop_hoisting(mut self, enabled: bool) -> Self {
+		self.loop_hoisting = enabled;
+		self
+	}
+
+	/// Whether to record the gas metering in the `wasm-instrument` custom section of the module.
+	///
+	/// Disabled by default. The record contains the backend, the names of the imported or
+	/// exported gas function or global and of the imported out-of-gas function, whether the gas
+	/// is charged lazily and loops are hoisted, and the [`schedule_hash`] of the rules. It can be
+	/// read with [`read_manifest`](crate::read_manifest).
+	pub fn with_manifest(mut self, enabled: bool) -> Self {
+		self.manifest = enabled;
+		self
+	}
+
+	/// Record the gas metering in the `wasm-instrument` custom section of the module and handle
+	/// modules that are already metered according to `repeated_pass`.
+	///
+	/// Not recorded by default. With [`RepeatedPass::Error`] an already metered module is
+	/// returned as [`InjectError::AlreadyMetered`]. Applying [`mutable_global`] again with
+	/// [`RepeatedPass::Instrument`] requires a different name for the gas global.
+	pub fn with_repeated_pass(mut self, repeated_pass: RepeatedPass) -> Self {
+		self.repeated_pass = Some(repeated_pass);
+		self
+	}
+}
+
 /// Transforms a given module into one that tracks the gas charged during its execution.
 ///
 /// The output module uses the `gas` function to track the gas spent. The function could be either
@@ -143,28 +245,143 @@ impl Rules for ConstantCostRules {
 /// function also rewrites all function indices references by code, table elements, etc., since
 /// the addition of an imported functions changes the indices of module-defined functions. If
 /// the module has a `NameSection`, added by calling `parse_names`, the indices will also be
-/// updated.
+/// updated. The addresses of DWARF debug info can be updated using the [`OffsetMap`] returned by
+/// [`inject_with_offset_map`].
 ///
 /// Syncronizing the amount of gas charged with the execution engine can be done in two ways. The
 /// first way is by calling the imported `gas` host function, see [`host_function`] for details. The
 /// second way is by using a local `gas` function together with a mutable global, see
-/// [`mutable_global`] for details.
+/// [`mutable_global`] for details. The [`inline_global`] backend works the same but inlines the
+/// body of the local `gas` function into metered blocks within loops. With [`imported_global`] the
+/// mutable global is imported from the host instead of being defined and exported by the module.
+/// Either way can be wrapped by [`lazy`] to accumulate the costs of the metered blocks
+/// in a local and charge them less often.
 ///
 /// This routine runs in time linear in the size of the input module.
 ///
-/// The function fails if the module contains any operation forbidden by gas rule set, returning
-/// the original module as an `Err`.
+/// The function fails if the module contains any operation forbidden by gas rule set or if it
+/// already exports the name the gas global is exported under, returning the original module as an
+/// `Err`. Use [`inject_with_config`] to tell these cases apart.
 pub fn inject<R: Rules, B: Backend>(
 	module: elements::Module,
 	backend: B,
 	rules: &R,
 ) -> Result<elements::Module, elements::Module> {
+	inject_with_config(module, backend, rules, &GasMeteringConfig::new())
+		.map_err(InjectError::into_module)
+}
+
+/// The error returned by [`inject_with_config`] and [`inject_with_offset_map`].
+///
+/// Every variant holds the original module.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum InjectError {
+	/// The module contains an instruction forbidden by the rules.
+	ForbiddenInstruction(elements::Module),
+	/// The module is already metered and [`RepeatedPass::Error`] is configured.
+	AlreadyMetered(elements::Module),
+	/// The module already exports the name the backend exports the gas global under. This is
+	/// the case when applying [`mutable_global`] a second time with [`RepeatedPass::Instrument`].
+	DuplicateExport(elements::Module),
+}
+
+impl InjectError {
+	/// Returns the original module.
+	pub fn into_module(self) -> elements::Module {
+		match self {
+			InjectError::ForbiddenInstruction(module) |
+			InjectError::AlreadyMetered(module) |
+			InjectError::DuplicateExport(module) => module,
+		}
+	}
+}
+
+/// Same as [`inject`] but allows to customize the instrumentation using a [`GasMeteringConfig`].
+///
+/// With the `dwarf` feature enabled the DWARF custom sections are removed from the instrumented
+/// module, as their addresses would be stale. Use [`inject_with_offset_map`] to keep and rewrite
+/// them.
+pub fn inject_with_config<R: Rules, B: Backend>(
+	module: elements::Module,
+	backend: B,
+	rules: &R,
+	config: &GasMeteringConfig,
+) -> Result<elements::Module, InjectError> {
+	inject_impl(module, backend, rules, config, None)
+}
+
+/// Same as [`inject_with_config`] but additionally returns an [`OffsetMap`] mapping the
+/// instructions of the instrumented functions back to the original ones.
+///
+/// The functions added by the instrumentation aren't part of the map. The DWARF custom sections
+/// are left as they are, for the map to be applied to them with `rewrite_debug_info`.
+pub fn inject_with_offset_map<R: Rules, B: Backend>(
+	module: elements::Module,
+	backend: B,
+	rules: &R,
+	config: &GasMeteringConfig,
+) -> Result<(elements::Module, OffsetMap), InjectError> {
+	let mut offsets = OffsetMap::default();
+	let module = inject_impl(module, backend, rules, config, Some(&mut offsets))?;
+	Ok((module, offsets))
+}
+
+fn inject_impl<R: Rules, B: Backend>(
+	module: elements::Module,
+	backend: B,
+	rules: &R,
+	config: &GasMeteringConfig,
+	mut offsets: Option<&mut OffsetMap>,
+) -> Result<elements::Module, InjectError> {
+	if let Some(repeated_pass) = config.repeated_pass {
+		if manifest::contains_pass(&module, manifest::GAS_METERING) {
+			match repeated_pass {
+				RepeatedPass::Instrument => {},
+				RepeatedPass::Skip => return Ok(module),
+				RepeatedPass::Error => return Err(InjectError::AlreadyMetered(module)),
+			}
+		}
+	}
+
 	// Prepare module and return the gas function
+	let backend_name = backend.name();
+	let lazy = backend.lazy();
+	let inline_min_loop_depth = backend.inline_min_loop_depth();
+	let out_of_gas_function = backend.out_of_gas_function();
 	let gas_meter = backend.gas_meter(&module, rules);
+	let block_rules = ImportCallRules::new(&module, rules);
+	let schedule = (config.manifest || config.repeated_pass.is_some())
+		.then(|| format!("{:016x}", schedule_hash(&module, rules)));
+
+	// A second export of the same name would render the module invalid.
+	if let GasMeter::Internal { global, .. } = gas_meter {
+		let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+		if exports.iter().any(|entry| entry.field() == global) {
+			return Err(InjectError::DuplicateExport(module))
+		}
+	}
 
 	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
 	let functions_space = module.functions_space() as u32;
 	let gas_global_idx = module.globals_space() as u32;
+	let out_of_gas_function = match gas_meter {
+		GasMeter::Internal { .. } => out_of_gas_function,
+		_ => None,
+	};
+	// An imported function goes after all other imported functions, which shifts the indices of
+	// the functions defined by the module.
+	let shifted_funcs = match gas_meter {
+		GasMeter::External { .. } => Some(import_count),
+		_ => out_of_gas_function.map(|_| import_count),
+	};
+	// An imported gas global goes after all other imported globals, which shifts the indices of
+	// the globals defined by the module.
+	let shifted_globals = match gas_meter {
+		GasMeter::ImportedGlobal { .. } =>
+			Some(module.import_count(elements::ImportCountType::Global) as u32),
+		_ => None,
+	};
 
 	let mut mbuilder = builder::from_module(module.clone());
 
@@ -187,6 +404,19 @@ pub fn inject<R: Rules, B: Backend>(
 			(import_count, functions_space + 1, 0)
 		},
 		GasMeter::Internal { global, ref func_instructions, cost } => {
+			// Inject the import of the function called when running out of gas
+			if let Some((oog_module, function)) = out_of_gas_function {
+				let import_sig = mbuilder.push_signature(builder::signature().build_sig());
+				mbuilder.push_import(
+					builder::import()
+						.module(oog_module)
+						.field(function)
+						.external()
+						.func(import_sig)
+						.build(),
+				);
+			}
+
 			// Inject the gas counting global
 			mbuilder.push_global(
 				builder::global()
@@ -203,34 +433,71 @@ pub fn inject<R: Rules, B: Backend>(
 				.build();
 			mbuilder.push_export(global_export);
 
-			let func_idx = functions_space;
-
-			// Build local gas function
-			let gas_func_sig =
-				builder::SignatureBuilder::new().with_param(ValueType::I64).build_sig();
+			// Inject local gas function
+			mbuilder.push_function(gas_function(func_instructions));
 
-			let function = builder::FunctionBuilder::new()
-				.with_signature(gas_func_sig)
-				.body()
-				.with_instructions(func_instructions.clone())
-				.build()
-				.build();
+			let func_idx = functions_space + out_of_gas_function.map_or(0, |_| 1);
+			(func_idx, func_idx + 1, cost)
+		},
+		GasMeter::ImportedGlobal { module: gas_module, global, ref func_instructions, cost } => {
+			// Inject the import of the gas counting global
+			mbuilder.push_import(
+				builder::import()
+					.module(gas_module)
+					.field(global)
+					.external()
+					.global(ValueType::I64, true)
+					.build(),
+			);
 
 			// Inject local gas function
-			mbuilder.push_function(function);
+			mbuilder.push_function(gas_function(func_instructions));
 
-			(func_idx, func_idx + 1, cost)
+			(functions_space, functions_space + 1, cost)
 		},
 	};
 
 	// We need the built the module for making injections to its blocks
 	let mut resulting_module = mbuilder.build();
 
+	// Lazily charged functions need to know where their locals start to add the accumulator.
+	let param_counts: Vec<u32> = {
+		let types = resulting_module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+		let functions = resulting_module.function_section().map(|fs| fs.entries()).unwrap_or(&[]);
+		functions
+			.iter()
+			.map(|func| {
+				types
+					.get(func.type_ref() as usize)
+					.map_or(0, |elements::Type::Function(ty)| ty.params().len() as u32)
+			})
+			.collect()
+	};
+
+	// Only the body of a known local gas function can be inlined.
+	let inline = match (&gas_meter, inline_min_loop_depth) {
+		(
+			GasMeter::Internal { func_instructions, .. } |
+			GasMeter::ImportedGlobal { func_instructions, .. },
+			Some(min_loop_depth),
+		) if backend::is_inlinable_gas_function(func_instructions.elements()) => {
+			// Strip the final `end` of the function.
+			let (_, instructions) = func_instructions
+				.elements()
+				.split_last()
+				.ok_or_else(|| InjectError::ForbiddenInstruction(module.clone()))?;
+			Some(InlineCharging { instructions, min_loop_depth })
+		},
+		_ => None,
+	};
+
 	let mut need_grow_counter = false;
 	let mut result = Ok(());
 	// Iterate over module sections and perform needed transformations.
 	// Indexes are needed to be fixed up in `GasMeter::External` case, as it adds an imported
-	// function, which goes to the beginning of the module's functions space.
+	// function, which goes to the beginning of the module's functions space. This is also the case
+	// for `GasMeter::Internal` with a function to call when running out of gas. The same applies to
+	// the globals defined by the module in `GasMeter::ImportedGlobal` case.
 	'outer: for section in resulting_module.sections_mut() {
 		match section {
 			elements::Section::Code(code_section) => {
@@ -239,40 +506,68 @@ pub fn inject<R: Rules, B: Backend>(
 					// Don't inject counters to the local gas function, which is the last one as
 					// it's just added. Cost for its execution is added statically before each
 					// invocation (see `inject_counter()`).
-					GasMeter::Internal { .. } => {
+					GasMeter::Internal { .. } | GasMeter::ImportedGlobal { .. } => {
 						let len = code_section.bodies().len();
 						&mut code_section.bodies_mut()[..len - 1]
 					},
 				};
 
-				for func_body in injection_targets {
+				// Only the function imports added before the defined functions shift them.
+				let instrumented_imports = import_count + shifted_funcs.map_or(0, |_| 1);
+				let mut positions = Vec::new();
+				for (defined_idx, (func_body, params_count)) in
+					(0..).zip(injection_targets.iter_mut().zip(&param_counts))
+				{
 					// Increment calling addresses if needed
-					if let GasMeter::External { .. } = gas_meter {
+					if let Some(first_shifted) = shifted_funcs {
 						for instruction in func_body.code_mut().elements_mut().iter_mut() {
 							if let Instruction::Call(call_index) = instruction {
-								if *call_index >= gas_func_idx {
+								if *call_index >= first_shifted {
 									*call_index += 1
 								}
 							}
 						}
 					}
+					if let Some(first_shifted) = shifted_globals {
+						shift_global_indices(func_body.code_mut().elements_mut(), first_shifted);
+					}
 					result = func_body
 						.locals()
 						.iter()
 						.try_fold(0u32, |count, val_type| count.checked_add(val_type.count()))
 						.ok_or(())
 						.and_then(|locals_count| {
+							// The accumulator is added after all the other locals.
+							let accumulator = if lazy {
+								let idx = params_count.checked_add(locals_count).ok_or(())?;
+								func_body
+									.locals_mut()
+									.push(elements::Local::new(1, ValueType::I64));
+								Some(idx)
+							} else {
+								None
+							};
 							inject_counter(
 								func_body.code_mut(),
 								gas_fn_cost,
 								locals_count,
-								rules,
+								&block_rules,
 								gas_func_idx,
+								config,
+								BlockCharging { accumulator, inline: inline.as_ref() },
+								offsets.is_some().then(|| &mut positions),
 							)
 						});
 					if result.is_err() {
 						break 'outer
 					}
+					if let Some(offsets) = offsets.as_deref_mut() {
+						offsets.insert(
+							instrumented_imports + defined_idx,
+							import_count + defined_idx,
+							mem::take(&mut positions),
+						);
+					}
 					if rules.memory_grow_cost().enabled() &&
 						inject_grow_counter(func_body.code_mut(), total_func) > 0
 					{
@@ -280,42 +575,73 @@ pub fn inject<R: Rules, B: Backend>(
 					}
 				}
 			},
-			elements::Section::Export(export_section) =>
-				if let GasMeter::External { module: _, function: _ } = gas_meter {
+			elements::Section::Export(export_section) => {
+				if let Some(first_shifted) = shifted_funcs {
 					for export in export_section.entries_mut() {
 						if let elements::Internal::Function(func_index) = export.internal_mut() {
-							if *func_index >= gas_func_idx {
+							if *func_index >= first_shifted {
 								*func_index += 1
 							}
 						}
 					}
+				}
+				if let Some(first_shifted) = shifted_globals {
+					for export in export_section.entries_mut() {
+						if let elements::Internal::Global(global_index) = export.internal_mut() {
+							if *global_index >= first_shifted {
+								*global_index += 1
+							}
+						}
+					}
+				}
+			},
+			elements::Section::Global(global_section) =>
+				if let Some(first_shifted) = shifted_globals {
+					for global in global_section.entries_mut() {
+						shift_global_indices(global.init_expr_mut().code_mut(), first_shifted);
+					}
+				},
+			elements::Section::Data(data_section) =>
+				if let Some(first_shifted) = shifted_globals {
+					for segment in data_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							shift_global_indices(offset.code_mut(), first_shifted);
+						}
+					}
 				},
 			elements::Section::Element(elements_section) => {
 				// Note that we do not need to check the element type referenced because in the
 				// WebAssembly 1.0 spec, the only allowed element type is funcref.
-				if let GasMeter::External { .. } = gas_meter {
+				if let Some(first_shifted) = shifted_funcs {
 					for segment in elements_section.entries_mut() {
 						// update all indirect call addresses initial values
 						for func_index in segment.members_mut() {
-							if *func_index >= gas_func_idx {
+							if *func_index >= first_shifted {
 								*func_index += 1
 							}
 						}
 					}
 				}
+				if let Some(first_shifted) = shifted_globals {
+					for segment in elements_section.entries_mut() {
+						if let Some(offset) = segment.offset_mut() {
+							shift_global_indices(offset.code_mut(), first_shifted);
+						}
+					}
+				}
 			},
 			elements::Section::Start(start_idx) =>
-				if let GasMeter::External { .. } = gas_meter {
-					if *start_idx >= gas_func_idx {
+				if let Some(first_shifted) = shifted_funcs {
+					if *start_idx >= first_shifted {
 						*start_idx += 1
 					}
 				},
 			elements::Section::Name(s) =>
-				if let GasMeter::External { .. } = gas_meter {
-					for functions in s.functions_mut() {
+				if let Some(first_shifted) = shifted_funcs {
+					if let Some(functions) = s.functions_mut() {
 						*functions.names_mut() =
 							IndexMap::from_iter(functions.names().iter().map(|(mut idx, name)| {
-								if idx >= gas_func_idx {
+								if idx >= first_shifted {
 									idx += 1;
 								}
 
@@ -327,12 +653,116 @@ pub fn inject<R: Rules, B: Backend>(
 		}
 	}
 
-	result.map_err(|_| module)?;
+	result.map_err(|_| InjectError::ForbiddenInstruction(module))?;
 
-	if need_grow_counter {
-		Ok(add_grow_counter(resulting_module, rules, gas_func_idx))
+	let mut resulting_module = if need_grow_counter {
+		add_grow_counter(resulting_module, rules, gas_func_idx)
 	} else {
-		Ok(resulting_module)
+		resulting_module
+	};
+
+	if let Some(schedule) = schedule {
+		let inline_min_loop_depth =
+			inline.as_ref().map(|inline| format!("{}", inline.min_loop_depth));
+		let mut params = vec![("backend", backend_name)];
+		match gas_meter {
+			GasMeter::External { module, function } => params.extend_from_slice(&[
+				("meter", "external"),
+				("module", module),
+				("function", function),
+			]),
+			GasMeter::Internal { global, .. } =>
+				params.extend_from_slice(&[("meter", "internal"), ("global", global)]),
+			GasMeter::ImportedGlobal { module, global, .. } => params.extend_from_slice(&[
+				("meter", "imported_global"),
+				("module", module),
+				("global", global),
+			]),
+		}
+		if let Some((module, function)) = out_of_gas_function {
+			params.extend_from_slice(&[
+				("out_of_gas_module", module),
+				("out_of_gas_function", function),
+			]);
+		}
+		params.push(("lazy", if lazy { "true" } else { "false" }));
+		params.push(("loop_hoisting", if config.loop_hoisting { "true" } else { "false" }));
+		if let Some(min_loop_depth) = &inline_min_loop_depth {
+			params.push(("inline_min_loop_depth", min_loop_depth));
+		}
+		params.push(("schedule", &schedule));
+		manifest::record_pass(&mut resulting_module, manifest::GAS_METERING, &params);
+	}
+
+	// Without an offset map the DWARF debug info can't be rewritten and would be stale.
+	#[cfg(feature = "dwarf")]
+	if offsets.is_none() {
+		crate::strip_debug_info(&mut resulting_module);
+	}
+
+	Ok(resulting_module)
+}
+
+/// Applies [`Rules::import_call_cost`] to the calls of imported functions.
+struct ImportCallRules<'a, R> {
+	rules: &'a R,
+	/// Module and field names of the imported functions in the function index space.
+	imports: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a, R: Rules> ImportCallRules<'a, R> {
+	fn new(module: &'a elements::Module, rules: &'a R) -> Self {
+		let imports = module
+			.import_section()
+			.map(|is| is.entries())
+			.unwrap_or(&[])
+			.iter()
+			.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+			.map(|entry| (entry.module(), entry.field()))
+			.collect();
+		Self { rules, imports }
+	}
+}
+
+impl<R: Rules> Rules for ImportCallRules<'_, R> {
+	fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+		match instruction {
+			Instruction::Call(func_idx) => match self.imports.get(*func_idx as usize) {
+				Some((module, field)) => self.rules.import_call_cost(module, field, instruction),
+				None => self.rules.instruction_cost(instruction),
+			},
+			_ => self.rules.instruction_cost(instruction),
+		}
+	}
+
+	fn memory_grow_cost(&self) -> MemoryGrowCost {
+		self.rules.memory_grow_cost()
+	}
+
+	fn call_per_local_cost(&self) -> u32 {
+		self.rules.call_per_local_cost()
+	}
+}
+
+/// Build the local gas function with the given body.
+fn gas_function(func_instructions: &elements::Instructions) -> builder::FunctionDefinition {
+	builder::FunctionBuilder::new()
+		.with_signature(builder::SignatureBuilder::new().with_param(ValueType::I64).build_sig())
+		.body()
+		.with_instructions(func_instructions.clone())
+		.build()
+		.build()
+}
+
+/// Increment the indices of all globals starting at `first_shifted` referenced by `instructions`.
+fn shift_global_indices(instructions: &mut [Instruction], first_shifted: u32) {
+	for instruction in instructions {
+		match instruction {
+			Instruction::GetGlobal(global_index) | Instruction::SetGlobal(global_index)
+				if *global_index >= first_shifted =>
+				*global_index += 1,
+			_ => {},
+		}
 	}
 }
 
@@ -382,6 +812,15 @@ struct MeteredBlock {
 	start_pos: usize,
 	/// Sum of costs of all instructions until end of the block.
 	cost: u64,
+	/// The counted loop whose body is this block if the costs of all its iterations are charged
+	/// at once. In this case the start position is the one of the `loop` instruction.
+	counted_loop: Option<CountedLoop>,
+}
+
+impl MeteredBlock {
+	fn new(start_pos: usize) -> Self {
+		Self { start_pos, cost: 0, counted_loop: None }
+	}
 }
 
 /// Counter is used to manage state during the gas metering algorithm implemented by
@@ -408,7 +847,7 @@ impl Counter {
 		let index = self.stack.len();
 		self.stack.push(ControlBlock {
 			lowest_forward_br_target: index,
-			active_metered_block: MeteredBlock { start_pos: cursor, cost: 0 },
+			active_metered_block: MeteredBlock::new(cursor),
 			is_loop,
 		})
 	}
@@ -453,10 +892,7 @@ impl Counter {
 	fn finalize_metered_block(&mut self, cursor: usize) -> Result<(), ()> {
 		let closing_metered_block = {
 			let control_block = self.stack.last_mut().ok_or(())?;
-			mem::replace(
-				&mut control_block.active_metered_block,
-				MeteredBlock { start_pos: cursor + 1, cost: 0 },
-			)
+			mem::replace(&mut control_block.active_metered_block, MeteredBlock::new(cursor + 1))
 		};
 
 		// If the block was opened with a `block`, then its start position will be set to that of
@@ -579,6 +1015,30 @@ fn add_grow_counter<R: Rules>(
 	b.build()
 }
 
+/// Returns the start position and cost of the metered blocks of every function body of `module`
+/// as determined by [`inject`].
+///
+/// Fails if a function contains an instruction forbidden by `rules`.
+pub(crate) fn metered_blocks<R: Rules>(
+	module: &elements::Module,
+	rules: &R,
+) -> Result<Vec<Vec<(usize, u64)>>, ()> {
+	let rules = ImportCallRules::new(module, rules);
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	bodies
+		.iter()
+		.map(|body| {
+			let locals_count = body
+				.locals()
+				.iter()
+				.try_fold(0u32, |count, local| count.checked_add(local.count()))
+				.ok_or(())?;
+			let blocks = determine_metered_blocks(body.code(), &rules, locals_count)?;
+			Ok(blocks.iter().map(|block| (block.start_pos, block.cost)).collect())
+		})
+		.collect()
+}
+
 fn determine_metered_blocks<R: Rules>(
 	instructions: &elements::Instructions,
 	rules: &R,
@@ -657,23 +1117,178 @@ fn determine_metered_blocks<R: Rules>(
 	Ok(counter.finalized_blocks)
 }
 
+/// Charge the costs of all iterations of counted loops before entering them.
+///
+/// The body of a counted loop is a single metered block. It is moved in front of the loop and
+/// marked to be charged once per iteration.
+fn hoist_counted_loops(instructions: &elements::Instructions, blocks: &mut [MeteredBlock]) {
+	for (loop_pos, counted_loop) in CountedLoop::find(instructions.elements()) {
+		if let Ok(idx) = blocks.binary_search_by_key(&(loop_pos + 1), |block| block.start_pos) {
+			let block = &mut blocks[idx];
+			// The cost of all iterations must not overflow the gas function argument.
+			if block.cost <= i32::MAX as u64 {
+				// Blocks stay sorted since no other block can start between the `loop` and its
+				// body.
+				block.start_pos = loop_pos;
+				block.counted_loop = Some(counted_loop);
+			}
+		}
+	}
+}
+
+/// Determine the positions of the instructions before which the accumulated costs are charged
+/// when charging lazily.
+///
+/// These are all calls, returns, branches to loops or to the function body and the final `end`.
+fn determine_flush_points(instructions: &elements::Instructions) -> Result<Vec<usize>, ()> {
+	use parity_wasm::elements::Instruction::*;
+
+	// Whether a branch to the respective control block needs a flush. The first control block
+	// is the function body.
+	let mut stack = vec![true];
+	let mut flush_points = Vec::new();
+
+	for (cursor, instruction) in instructions.elements().iter().enumerate() {
+		let target_needs_flush = |label: &u32| -> Result<bool, ()> {
+			let index = stack.len().checked_sub(*label as usize + 1).ok_or(())?;
+			Ok(stack[index])
+		};
+		let needs_flush = match instruction {
+			Call(_) | CallIndirect(..) | Return => true,
+			Br(label) | BrIf(label) => target_needs_flush(label)?,
+			BrTable(br_table_data) => [br_table_data.default]
+				.iter()
+				.chain(br_table_data.table.iter())
+				.try_fold(false, |needs_flush, label| {
+					Ok(needs_flush || target_needs_flush(label)?)
+				})?,
+			Block(_) | If(_) => {
+				stack.push(false);
+				false
+			},
+			Loop(_) => {
+				stack.push(true);
+				false
+			},
+			End => {
+				stack.pop().ok_or(())?;
+				stack.is_empty()
+			},
+			_ => false,
+		};
+		if needs_flush {
+			flush_points.push(cursor);
+		}
+	}
+
+	Ok(flush_points)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn inject_counter<R: Rules>(
 	instructions: &mut elements::Instructions,
 	gas_function_cost: u64,
 	locals_count: u32,
 	rules: &R,
 	gas_func: u32,
+	config: &GasMeteringConfig,
+	charging: BlockCharging,
+	positions: Option<&mut Vec<u32>>,
 ) -> Result<(), ()> {
-	let blocks = determine_metered_blocks(instructions, rules, locals_count)?;
-	insert_metering_calls(instructions, gas_function_cost, blocks, gas_func)
+	let mut blocks = determine_metered_blocks(instructions, rules, locals_count)?;
+	if config.loop_hoisting {
+		hoist_counted_loops(instructions, &mut blocks);
+	}
+	let lazy = match charging.accumulator {
+		Some(accumulator) => {
+			// Every metered block is executed at most once between two flushes. Hence, the sum
+			// of all their costs is the maximal value of the accumulator.
+			let max_accumulated = blocks
+				.iter()
+				.filter(|block| block.counted_loop.is_none())
+				.try_fold(0u64, |sum, block| sum.checked_add(block.cost))
+				.ok_or(())?;
+			if max_accumulated > i64::MAX as u64 {
+				return Err(())
+			}
+
+			// The bodies of hoisted counted loops are already paid for. There is nothing to
+			// charge at their back edges, which are the first branches after their start.
+			let hoisted_back_edges: Vec<usize> = blocks
+				.iter()
+				.filter(|block| block.counted_loop.is_some())
+				.filter_map(|block| {
+					instructions.elements()[block.start_pos..]
+						.iter()
+						.position(|instruction| matches!(instruction, Instruction::BrIf(_)))
+						.map(|offset| block.start_pos + offset)
+				})
+				.collect();
+			let mut flush_points = determine_flush_points(instructions)?;
+			flush_points.retain(|pos| hoisted_back_edges.binary_search(pos).is_err());
+
+			Some(LazyCharging { accumulator, flush_points })
+		},
+		None => None,
+	};
+	insert_metering_calls(
+		instructions,
+		gas_function_cost,
+		blocks,
+		gas_func,
+		lazy,
+		charging.inline,
+		positions,
+	)
+}
+
+/// How the costs of the metered blocks of a function are charged.
+struct BlockCharging<'a> {
+	/// Index of the local accumulating the costs when charging lazily.
+	accumulator: Option<u32>,
+	/// Which metered blocks to charge by inlining the body of the local gas function.
+	inline: Option<&'a InlineCharging<'a>>,
+}
+
+/// Where and how to charge the accumulated costs when charging lazily.
+struct LazyCharging {
+	/// Index of the local accumulating the costs.
+	accumulator: u32,
+	/// Positions of the instructions before which the accumulated costs are charged.
+	flush_points: Vec<usize>,
+}
+
+/// Which metered blocks to charge by inlining the body of the local gas function.
+struct InlineCharging<'a> {
+	/// The body of the local gas function without its final `end`.
+	instructions: &'a [Instruction],
+	/// The minimal number of loops a metered block must be nested in to be inlined.
+	min_loop_depth: u32,
+}
+
+impl InlineCharging<'_> {
+	/// Push the body of the gas function with every read of its argument replaced by the
+	/// instructions computing the `amount`.
+	fn push_charge(&self, amount: &[Instruction], instructions: &mut Vec<Instruction>) {
+		for instruction in self.instructions {
+			match instruction {
+				Instruction::GetLocal(0) => instructions.extend_from_slice(amount),
+				instruction => instructions.push(instruction.clone()),
+			}
+		}
+	}
 }
 
 // Then insert metering calls into a sequence of instructions given the block locations and costs.
+// The original position of every resulting instruction is recorded in `positions` if provided.
 fn insert_metering_calls(
 	instructions: &mut elements::Instructions,
 	gas_function_cost: u64,
 	blocks: Vec<MeteredBlock>,
 	gas_func: u32,
+	lazy: Option<LazyCharging>,
+	inline: Option<&InlineCharging>,
+	mut positions: Option<&mut Vec<u32>>,
 ) -> Result<(), ()> {
 	use parity_wasm::elements::Instruction::*;
 
@@ -684,31 +1299,93 @@ fn insert_metering_calls(
 		mem::replace(instructions.elements_mut(), Vec::with_capacity(new_instrs_len));
 	let new_instrs = instructions.elements_mut();
 
+	let (accumulator, flush_points) = match lazy {
+		Some(lazy) => (Some(lazy.accumulator), lazy.flush_points),
+		None => (None, Vec::new()),
+	};
+
+	// The loop depth outside of each open control block, to keep track of the current one.
+	let mut control_stack = Vec::new();
+	let mut loop_depth = 0;
+
 	let mut block_iter = blocks.into_iter().peekable();
+	let mut flush_iter = flush_points.into_iter().peekable();
 	for (original_pos, instr) in original_instrs.into_iter().enumerate() {
-		// If there the next block starts at this position, inject metering instructions.
-		let used_block = if let Some(block) = block_iter.peek() {
-			if block.start_pos == original_pos {
-				new_instrs
-					.push(I64Const((block.cost.checked_add(gas_function_cost).ok_or(())?) as i64));
-				new_instrs.push(Call(gas_func));
-				true
-			} else {
-				false
+		// If there the next blocks start at this position, inject metering instructions. Only a
+		// hoisted counted loop can start at the same position as another block.
+		while let Some(block) = block_iter.next_if(|block| block.start_pos == original_pos) {
+			match (block.counted_loop, accumulator) {
+				(None, Some(accumulator)) => {
+					new_instrs.push(GetLocal(accumulator));
+					new_instrs.push(I64Const(block.cost as i64));
+					new_instrs.push(I64Add);
+					new_instrs.push(SetLocal(accumulator));
+					continue
+				},
+				(None, None) => {
+					let amount = block.cost.checked_add(gas_function_cost).ok_or(())?;
+					match inline {
+						Some(inline) if loop_depth >= inline.min_loop_depth => {
+							inline.push_charge(&[I64Const(amount as i64)], new_instrs);
+							continue
+						},
+						_ => new_instrs.push(I64Const(amount as i64)),
+					}
+				},
+				(Some(counted_loop), _) => {
+					counted_loop.push_iterations(new_instrs);
+					new_instrs.push(I64Const(block.cost as i64));
+					new_instrs.push(I64Mul);
+					if gas_function_cost > 0 {
+						new_instrs.push(I64Const(gas_function_cost as i64));
+						new_instrs.push(I64Add);
+					}
+				},
 			}
-		} else {
-			false
-		};
+			new_instrs.push(Call(gas_func));
+		}
+
+		// Charge the accumulated costs and reset the accumulator.
+		if let (Some(accumulator), Some(_)) =
+			(accumulator, flush_iter.next_if(|pos| *pos == original_pos))
+		{
+			let mut amount = vec![GetLocal(accumulator)];
+			if gas_function_cost > 0 {
+				amount.push(I64Const(gas_function_cost as i64));
+				amount.push(I64Add);
+			}
+			match inline {
+				Some(inline) if loop_depth >= inline.min_loop_depth =>
+					inline.push_charge(&amount, new_instrs),
+				_ => {
+					new_instrs.extend(amount);
+					new_instrs.push(Call(gas_func));
+				},
+			}
+			new_instrs.push(I64Const(0));
+			new_instrs.push(SetLocal(accumulator));
+		}
 
-		if used_block {
-			block_iter.next();
+		match instr {
+			Block(_) | If(_) => control_stack.push(loop_depth),
+			Loop(_) => {
+				control_stack.push(loop_depth);
+				loop_depth += 1;
+			},
+			End => loop_depth = control_stack.pop().unwrap_or(0),
+			_ => {},
 		}
 
 		// Copy over the original instruction.
 		new_instrs.push(instr);
+
+		// The inserted instructions belong to the original instruction following them.
+		if let Some(positions) = positions.as_deref_mut() {
+			positions.resize(new_instrs.len(), original_pos as u32);
+		}
 	}
 
-	if block_iter.next().is_some() {
+	if block_iter.next().is_some() || flush_iter.next().is_some() {
 		return Err(())
 	}
 
@@ -825,6 +1502,33 @@ mod tests {
 		wasmparser::validate(&binary).unwrap();
 	}
 
+	#[test]
+	fn injected_functions_reuse_existing_types() {
+		let source = r#"(module
+			(type (func (param i32) (result i32)))
+			(type (func (param i64)))
+			(func (type 0)
+			  local.get 0
+			  memory.grow)
+			(memory 0 1)
+			)"#;
+
+		let backend = host_function::Injector::new("env", "gas");
+		let injected_module =
+			super::inject(parse_wat(source), backend, &ConstantCostRules::new(1, 10_000, 1))
+				.unwrap();
+		assert_eq!(injected_module.type_section().unwrap().types().len(), 2);
+
+		let backend = mutable_global::Injector::new("gas_left");
+		let injected_module =
+			super::inject(parse_wat(source), backend, &ConstantCostRules::new(1, 10_000, 1))
+				.unwrap();
+		assert_eq!(injected_module.type_section().unwrap().types().len(), 2);
+
+		let binary = serialize(injected_module).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+	}
+
 	#[test]
 	fn grow_no_gas_no_track_host_fn() {
 		let module = parse_wat(
@@ -1009,6 +1713,642 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn hoisted_counted_loops() {
+		let body = |condition: &str| {
+			format!(
+				r#"
+				loop
+					local.get 2
+					i32.const 3
+					i32.mul
+					local.set 2
+					{}
+					br_if 0
+				end"#,
+				condition
+			)
+		};
+		let source = format!(
+			r#"(module
+			(import "env" "gas" (func (param i64)))
+			(func (export "ne") (param i32 i32) (local i32) {})
+			(func (export "lt_u") (param i32 i32) (local i32) {})
+			(func (export "lt_s") (param i32 i32) (local i32) {})
+			(func (export "const") (param i32 i32) (local i32) {})
+			(func (export "dec") (param i32 i32) (local i32) {})
+			)"#,
+			body("local.get 0 i32.const 1 i32.add local.tee 0 local.get 1 i32.ne"),
+			body("local.get 0 i32.const 1 i32.add local.tee 0 local.get 1 i32.lt_u"),
+			body("local.get 0 i32.const 1 i32.add local.tee 0 local.get 1 i32.lt_s"),
+			body("local.get 0 i32.const 1 i32.add local.tee 0 i32.const 10 i32.lt_u"),
+			body("local.get 0 i32.const -1 i32.add local.tee 0"),
+		);
+
+		let run = |config: &GasMeteringConfig, name: &str, params: (i32, i32)| -> u64 {
+			let module = inject_with_config(
+				parse_wat(&source),
+				host_function::Injector::new("env", "gas"),
+				&ConstantCostRules::default(),
+				config,
+			)
+			.unwrap();
+
+			// All charges are made before entering the loop.
+			if config.loop_hoisting {
+				for idx in 0..5 {
+					let body = get_function_body(&module, idx).unwrap();
+					let loop_pos = body.iter().position(|i| matches!(i, Loop(_))).unwrap();
+					assert!(!body[loop_pos..].contains(&Call(0)));
+				}
+			}
+
+			let engine = wasmi::Engine::default();
+			let binary = serialize(module).unwrap();
+			let module = wasmi::Module::new(&engine, &mut &binary[..]).unwrap();
+			let mut linker = wasmi::Linker::new(&engine);
+			linker
+				.func_wrap("env", "gas", |mut caller: wasmi::Caller<'_, u64>, amount: u64| {
+					*caller.data_mut() += amount;
+				})
+				.unwrap();
+			let mut store = wasmi::Store::new(&engine, 0);
+			let instance =
+				linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+			instance
+				.get_typed_func::<(i32, i32), ()>(&store, name)
+				.unwrap()
+				.call(&mut store, params)
+				.unwrap();
+			*store.data()
+		};
+
+		let cases: &[(&str, (i32, i32))] = &[
+			("ne", (0, 5)),
+			("ne", (4, 5)),
+			("ne", (-3, 2)),
+			("lt_u", (0, 5)),
+			("lt_u", (5, 5)),
+			("lt_u", (10, 5)),
+			("lt_u", (-1, 3)),
+			("lt_u", (-2, 3)),
+			("lt_s", (-3, 2)),
+			("lt_s", (5, 5)),
+			("lt_s", (10, -5)),
+			("const", (0, 0)),
+			("const", (20, 0)),
+			("dec", (1, 0)),
+			("dec", (5, 0)),
+		];
+		let hoisting = GasMeteringConfig::new().with_loop_hoisting(true);
+		for (name, params) in cases {
+			assert_eq!(
+				run(&hoisting, name, *params),
+				run(&GasMeteringConfig::new(), name, *params),
+				"{} {:?}",
+				name,
+				params
+			);
+		}
+	}
+
+	#[test]
+	fn lazy_charging() {
+		let source = r#"(module
+			(func $f (param i32) (result i32)
+				(local i32)
+				local.get 0
+				if
+					i32.const 1
+					local.set 1
+				end
+				block
+					local.get 0
+					i32.const 2
+					i32.gt_u
+					br_if 0
+					i32.const 3
+					local.set 1
+				end
+				loop
+					local.get 1
+					call $g
+					local.set 1
+					local.get 0
+					i32.const -1
+					i32.add
+					local.tee 0
+					br_if 0
+				end
+				local.get 1
+			)
+			(func $g (param i32) (result i32)
+				local.get 0
+				i32.const 1
+				i32.add
+			)
+			(export "f" (func $f))
+		)"#;
+
+		let run = |module: elements::Module, param: i32| -> (u64, usize) {
+			let gas_calls = get_function_body(&module, 0)
+				.unwrap()
+				.iter()
+				.filter(|instr| **instr == Call(0))
+				.count();
+
+			let engine = wasmi::Engine::default();
+			let binary = serialize(module).unwrap();
+			let module = wasmi::Module::new(&engine, &mut &binary[..]).unwrap();
+			let mut linker = wasmi::Linker::new(&engine);
+			linker
+				.func_wrap("env", "gas", |mut caller: wasmi::Caller<'_, u64>, amount: u64| {
+					*caller.data_mut() += amount;
+				})
+				.unwrap();
+			let mut store = wasmi::Store::new(&engine, 0);
+			let instance =
+				linker.instantiate(&mut store, &module).unwrap().start(&mut store).unwrap();
+			instance
+				.get_typed_func::<i32, i32>(&store, "f")
+				.unwrap()
+				.call(&mut store, param)
+				.unwrap();
+			(*store.data(), gas_calls)
+		};
+
+		for param in 1..5 {
+			let backend = host_function::Injector::new("env", "gas");
+			let eager =
+				super::inject(parse_wat(source), backend, &ConstantCostRules::default()).unwrap();
+			let backend = lazy::Injector::new(host_function::Injector::new("env", "gas"));
+			let lazy =
+				super::inject(parse_wat(source), backend, &ConstantCostRules::default()).unwrap();
+
+			let binary = serialize(lazy.clone()).expect("serialization failed");
+			wasmparser::validate(&binary).unwrap();
+
+			let (eager_gas, eager_calls) = run(eager, param);
+			let (lazy_gas, lazy_calls) = run(lazy, param);
+			assert_eq!(eager_gas, lazy_gas);
+			// Once before the call, once at the back edge and once at the end.
+			assert_eq!(lazy_calls, 3);
+			assert!(eager_calls > lazy_calls);
+		}
+	}
+
+	/// Call the function exported as `f` with `10` after setting the global exported as `gas_left`.
+	///
+	/// Returns whether the call succeeded and the value of the global afterwards.
+	fn run_with_gas_global(module: elements::Module, gas_left: u64) -> (bool, u64) {
+		let engine = wasmi::Engine::default();
+		let binary = serialize(module).unwrap();
+		let module = wasmi::Module::new(&engine, &mut &binary[..]).unwrap();
+		let mut store = wasmi::Store::new(&engine, ());
+		let instance = wasmi::Linker::new(&engine)
+			.instantiate(&mut store, &module)
+			.unwrap()
+			.start(&mut store)
+			.unwrap();
+		let global = instance.get_global(&store, "gas_left").unwrap();
+		global.set(&mut store, wasmi::Value::I64(gas_left as i64)).unwrap();
+		let ok = instance
+			.get_typed_func::<i32, ()>(&store, "f")
+			.unwrap()
+			.call(&mut store, 10)
+			.is_ok();
+		(ok, global.get(&store).i64().unwrap() as u64)
+	}
+
+	#[test]
+	fn inline_global() {
+		let source = r#"(module
+			(func (export "f") (param i32)
+				(local i32)
+				i32.const 5
+				local.set 1
+				loop
+					local.get 1
+					i32.const 2
+					i32.add
+					local.set 1
+					local.get 0
+					i32.const -1
+					i32.add
+					local.tee 0
+					br_if 0
+				end
+			)
+		)"#;
+
+		let run = |module: elements::Module, gas_left: u64| run_with_gas_global(module, gas_left);
+
+		let rules = ConstantCostRules::default();
+		let backend = mutable_global::Injector::new("gas_left");
+		let called = super::inject(parse_wat(source), backend, &rules).unwrap();
+		let backend = inline_global::Injector::new("gas_left");
+		let inlined_loops = super::inject(parse_wat(source), backend, &rules).unwrap();
+		let backend = inline_global::Injector::new("gas_left").with_min_loop_depth(0);
+		let inlined = super::inject(parse_wat(source), backend, &rules).unwrap();
+
+		// The gas function is only called outside of the loop.
+		let body = get_function_body(&inlined_loops, 0).unwrap();
+		let loop_pos = body.iter().position(|instr| matches!(instr, Loop(_))).unwrap();
+		assert!(body[..loop_pos].contains(&Call(1)));
+		assert!(!body[loop_pos..].contains(&Call(1)));
+		assert!(!get_function_body(&inlined, 0).unwrap().contains(&Call(1)));
+
+		for module in [&inlined_loops, &inlined] {
+			let binary = serialize(module.clone()).expect("serialization failed");
+			wasmparser::validate(&binary).unwrap();
+
+			assert_eq!(run(module.clone(), 1_000_000), run(called.clone(), 1_000_000));
+			// Running out of gas sets the sentinel.
+			assert_eq!(run(module.clone(), 100), (false, u64::MAX));
+			assert_eq!(run(called.clone(), 100), (false, u64::MAX));
+		}
+	}
+
+	#[test]
+	fn lazy_inline_global() {
+		let source = r#"(module
+			(func (export "f") (param i32)
+				(local i32)
+				i32.const 5
+				local.set 1
+				loop
+					local.get 1
+					i32.const 2
+					i32.add
+					local.set 1
+					local.get 0
+					i32.const -1
+					i32.add
+					local.tee 0
+					br_if 0
+				end
+			)
+		)"#;
+
+		let rules = ConstantCostRules::default();
+		let backend = lazy::Injector::new(mutable_global::Injector::new("gas_left"));
+		let called = super::inject(parse_wat(source), backend, &rules).unwrap();
+		let backend = lazy::Injector::new(inline_global::Injector::new("gas_left"));
+		let inlined = super::inject(parse_wat(source), backend, &rules).unwrap();
+
+		// The accumulated costs are only charged by calling the gas function outside of the loop.
+		let body = get_function_body(&inlined, 0).unwrap();
+		let loop_pos = body.iter().position(|instr| matches!(instr, Loop(_))).unwrap();
+		let loop_end = loop_pos + body[loop_pos..].iter().position(|instr| *instr == End).unwrap();
+		assert!(!body[loop_pos..loop_end].contains(&Call(1)));
+		assert!(body[loop_pos..loop_end].contains(&GetGlobal(0)));
+		assert!(body[loop_end..].contains(&Call(1)));
+
+		let binary = serialize(inlined.clone()).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+
+		assert_eq!(
+			run_with_gas_global(inlined.clone(), 1_000_000),
+			run_with_gas_global(called.clone(), 1_000_000)
+		);
+		assert_eq!(run_with_gas_global(inlined, 100), (false, u64::MAX));
+		assert_eq!(run_with_gas_global(called, 100), (false, u64::MAX));
+	}
+
+	#[test]
+	fn inline_only_known_gas_functions() {
+		// The gas function of `signed_global` writes its argument.
+		struct InlineSigned;
+
+		impl Backend for InlineSigned {
+			fn gas_meter<R: Rules>(self, module: &elements::Module, rules: &R) -> GasMeter {
+				signed_global::Injector::new("gas_left").gas_meter(module, rules)
+			}
+
+			fn inline_min_loop_depth(&self) -> Option<u32> {
+				Some(0)
+			}
+		}
+
+		let source = r#"(module
+			(func (export "f") (param i32)
+				loop
+					local.get 0
+					i32.const -1
+					i32.add
+					local.tee 0
+					br_if 0
+				end
+			)
+		)"#;
+
+		let rules = ConstantCostRules::default();
+		let called =
+			super::inject(parse_wat(source), signed_global::Injector::new("gas_left"), &rules)
+				.unwrap();
+		let injected = super::inject(parse_wat(source), InlineSigned, &rules).unwrap();
+		assert_eq!(injected, called);
+	}
+
+	#[test]
+	fn signed_global() {
+		let source = r#"(module
+			(func (export "f") (param i32)
+				loop
+					local.get 0
+					i32.const -1
+					i32.add
+					local.tee 0
+					br_if 0
+				end
+			)
+		)"#;
+
+		let rules = ConstantCostRules::default();
+		let backend = mutable_global::Injector::new("gas_left");
+		let unsigned = super::inject(parse_wat(source), backend, &rules).unwrap();
+		let backend = signed_global::Injector::new("gas_left");
+		let signed = super::inject(parse_wat(source), backend, &rules).unwrap();
+
+		let binary = serialize(signed.clone()).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+
+		// Both charge the same amount of gas and report running out of gas the same way.
+		for gas_left in [0, 10, 100, 1_000, 1_000_000, i64::MAX as u64] {
+			assert_eq!(
+				run_with_gas_global(signed.clone(), gas_left),
+				run_with_gas_global(unsigned.clone(), gas_left)
+			);
+		}
+		assert_eq!(run_with_gas_global(signed, 10), (false, u64::MAX));
+	}
+
+	#[test]
+	fn repeated_pass() {
+		let module = parse_wat(
+			r#"(module
+			(func (export "f") (param i32)
+				local.get 0
+				drop
+			)
+		)"#,
+		);
+		let rules = ConstantCostRules::default();
+		let backend = || host_function::Injector::new("env", "gas name");
+
+		let config = GasMeteringConfig::new().with_repeated_pass(RepeatedPass::Error);
+		let metered = inject_with_config(module.clone(), backend(), &rules, &config).unwrap();
+		assert_eq!(
+			inject_with_config(metered.clone(), backend(), &rules, &config),
+			Err(InjectError::AlreadyMetered(metered.clone()))
+		);
+
+		let config = GasMeteringConfig::new().with_repeated_pass(RepeatedPass::Skip);
+		let skipped = inject_with_config(metered.clone(), backend(), &rules, &config).unwrap();
+		assert_eq!(skipped, metered);
+
+		let config = GasMeteringConfig::new().with_repeated_pass(RepeatedPass::Instrument);
+		let repeated = inject_with_config(metered, backend(), &rules, &config).unwrap();
+		assert_eq!(repeated.import_count(elements::ImportCountType::Function), 2);
+		let record = |module: &elements::Module| {
+			crate::PassRecord::GasMetering(crate::GasMeteringRecord {
+				version: env!("CARGO_PKG_VERSION").into(),
+				backend: "host_function".into(),
+				meter: "external".into(),
+				module: Some("env".into()),
+				function: Some("gas name".into()),
+				global: None,
+				out_of_gas_module: None,
+				out_of_gas_function: None,
+				lazy: false,
+				loop_hoisting: false,
+				inline_min_loop_depth: None,
+				schedule_hash: schedule_hash(module, &rules),
+			})
+		};
+		// The second pass hashes the cost of calling the gas function imported by the first.
+		assert_eq!(
+			crate::read_manifest(&repeated).unwrap().passes,
+			vec![record(&module), record(&skipped)]
+		);
+		let binary = serialize(repeated).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+
+		// Exporting the gas global a second time under the same name is rejected.
+		let metered =
+			inject_with_config(module, mutable_global::Injector::new("gas_left"), &rules, &config)
+				.unwrap();
+		assert_eq!(
+			inject_with_config(
+				metered.clone(),
+				mutable_global::Injector::new("gas_left"),
+				&rules,
+				&config
+			),
+			Err(InjectError::DuplicateExport(metered.clone()))
+		);
+		let repeated = inject_with_config(
+			metered,
+			mutable_global::Injector::new("gas_left_2"),
+			&rules,
+			&config,
+		)
+		.unwrap();
+		let binary = serialize(repeated).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+	}
+
+	#[test]
+	fn import_call_cost() {
+		struct ImportRules;
+
+		impl Rules for ImportRules {
+			fn instruction_cost(&self, _: &Instruction) -> Option<u32> {
+				Some(1)
+			}
+
+			fn memory_grow_cost(&self) -> MemoryGrowCost {
+				MemoryGrowCost::Free
+			}
+
+			fn call_per_local_cost(&self) -> u32 {
+				0
+			}
+
+			fn import_call_cost(&self, module: &str, field: &str, _: &Instruction) -> Option<u32> {
+				match (module, field) {
+					("env", "expensive") => Some(100),
+					("env", "forbidden") => None,
+					_ => Some(1),
+				}
+			}
+		}
+
+		let module = parse_wat(
+			r#"(module
+			(import "env" "cheap" (func $cheap))
+			(import "env" "expensive" (func $expensive))
+			(func (export "f")
+				call $cheap
+				call $expensive
+				call $f
+			)
+			(func $f)
+		)"#,
+		);
+		let injected = inject(module, host_function::Injector::new("env", "gas"), &ImportRules)
+			.expect("inject_gas_counter call failed");
+
+		// The gas function is imported after the other imports and doesn't change their prices.
+		assert_eq!(
+			get_function_body(&injected, 0).unwrap(),
+			&vec![I64Const(102), Call(2), Call(0), Call(1), Call(4), End][..]
+		);
+
+		let module = parse_wat(
+			r#"(module
+			(import "env" "forbidden" (func $forbidden))
+			(func (export "f")
+				call $forbidden
+			)
+		)"#,
+		);
+		assert!(inject(module, host_function::Injector::new("env", "gas"), &ImportRules).is_err());
+	}
+
+	#[test]
+	fn out_of_gas_host_function() {
+		let source = r#"(module
+			(import "env" "nop" (func $nop))
+			(func $f (export "f") (param i32)
+				call $nop
+				local.get 0
+				drop
+			)
+			(table 1 funcref)
+			(elem (i32.const 0) $f)
+		)"#;
+
+		let backend = mutable_global::Injector::new("gas_left").with_out_of_gas(
+			mutable_global::OutOfGas::HostFunction { module: "env", function: "out_of_gas" },
+		);
+		let module = inject(parse_wat(source), backend, &ConstantCostRules::default()).unwrap();
+		let binary = serialize(module.clone()).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+
+		// The host function is imported after `$nop`, which shifts `$f` to index 2.
+		assert_eq!(module.import_count(elements::ImportCountType::Function), 2);
+		assert_eq!(
+			module.export_section().unwrap().entries()[0].internal(),
+			&elements::Internal::Function(2)
+		);
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[2]);
+		let bodies = module.code_section().unwrap().bodies();
+		assert!(bodies[0].code().elements().contains(&Call(0)));
+		assert!(bodies[0].code().elements().contains(&Call(3)));
+		assert!(bodies[1].code().elements().contains(&Call(1)));
+
+		let engine = wasmi::Engine::default();
+		let wasmi_module = wasmi::Module::new(&engine, &mut &binary[..]).unwrap();
+		let mut store = wasmi::Store::new(&engine, ());
+		let mut linker = wasmi::Linker::new(&engine);
+		linker.func_wrap("env", "nop", || {}).unwrap();
+		linker
+			.func_wrap("env", "out_of_gas", || -> Result<(), wasmi::core::Trap> {
+				Err(wasmi::core::TrapCode::OutOfFuel.into())
+			})
+			.unwrap();
+		let instance = linker
+			.instantiate(&mut store, &wasmi_module)
+			.unwrap()
+			.start(&mut store)
+			.unwrap();
+		let gas_left = instance.get_global(&store, "gas_left").unwrap();
+		let f = instance.get_typed_func::<i32, ()>(&store, "f").unwrap();
+
+		gas_left.set(&mut store, wasmi::Value::I64(100)).unwrap();
+		f.call(&mut store, 10).unwrap();
+		// The gas function costs 11 and the function itself 3.
+		assert_eq!(gas_left.get(&store).i64(), Some(100 - 14));
+
+		// Running out of gas is reported by the host function and leaves the global untouched.
+		gas_left.set(&mut store, wasmi::Value::I64(3)).unwrap();
+		let error = f.call(&mut store, 10).unwrap_err();
+		assert!(matches!(error.trap_code(), Some(wasmi::core::TrapCode::OutOfFuel)));
+		assert_eq!(gas_left.get(&store).i64(), Some(3));
+	}
+
+	#[test]
+	fn imported_global() {
+		let source = r#"(module
+			(import "env" "base" (global $base i32))
+			(global $sum (mut i32) (global.get $base))
+			(memory 1)
+			(data (global.get $base) "x")
+			(func (export "f") (param i32)
+				global.get $sum
+				local.get 0
+				i32.add
+				global.set $sum
+			)
+			(export "sum" (global $sum))
+		)"#;
+
+		let module = inject(
+			parse_wat(source),
+			imported_global::Injector::new("env", "gas_left"),
+			&ConstantCostRules::default(),
+		)
+		.unwrap();
+		let binary = serialize(module.clone()).expect("serialization failed");
+		wasmparser::validate(&binary).unwrap();
+
+		// The gas global is imported after `$base`, which shifts `$sum` to index 2.
+		assert_eq!(module.import_count(elements::ImportCountType::Global), 2);
+		assert_eq!(
+			module.global_section().unwrap().entries()[0].init_expr().code()[0],
+			GetGlobal(0)
+		);
+		assert_eq!(
+			module.export_section().unwrap().entries()[1].internal(),
+			&elements::Internal::Global(2)
+		);
+		let body = &module.code_section().unwrap().bodies()[0];
+		assert!(body.code().elements().contains(&GetGlobal(2)));
+		assert!(body.code().elements().contains(&SetGlobal(2)));
+
+		let engine = wasmi::Engine::default();
+		let wasmi_module = wasmi::Module::new(&engine, &mut &binary[..]).unwrap();
+		let mut store = wasmi::Store::new(&engine, ());
+		let base = wasmi::Global::new(&mut store, wasmi::Value::I32(7), wasmi::Mutability::Const);
+		let gas_left =
+			wasmi::Global::new(&mut store, wasmi::Value::I64(1_000), wasmi::Mutability::Var);
+		let mut linker = wasmi::Linker::new(&engine);
+		linker.define("env", "base", base).unwrap();
+		linker.define("env", "gas_left", gas_left).unwrap();
+		let instance = linker
+			.instantiate(&mut store, &wasmi_module)
+			.unwrap()
+			.start(&mut store)
+			.unwrap();
+		instance
+			.get_typed_func::<i32, ()>(&store, "f")
+			.unwrap()
+			.call(&mut store, 10)
+			.unwrap();
+
+		let sum = instance.get_global(&store, "sum").unwrap();
+		assert_eq!(sum.get(&store).i32(), Some(17));
+		// The gas function costs 11 and the function itself 4.
+		assert_eq!(gas_left.get(&store).i64(), Some(1_000 - 15));
+
+		// Running out of gas leaves the sentinel value in the imported global.
+		gas_left.set(&mut store, wasmi::Value::I64(3)).unwrap();
+		let result = instance.get_typed_func::<i32, ()>(&store, "f").unwrap().call(&mut store, 10);
+		assert!(result.is_err());
+		assert_eq!(gas_left.get(&store).i64(), Some(-1));
+	}
+
 	fn parse_wat(source: &str) -> elements::Module {
 		let module_bytes = wat::parse_str(source).unwrap();
 		elements::deserialize_buffer(module_bytes.as_ref()).unwrap()
@@ -0,0 +1,341 @@
+//! Removal of the gas metering instrumentation.
+
+use super::{backend, validation::strip_metering};
+use crate::{
+	index_space,
+	manifest::{self, GasMeteringRecord, PassRecord},
+};
+use alloc::vec::Vec;
+use parity_wasm::elements::{self, FuncBody, Instruction};
+
+/// Remove the gas metering instrumentation injected by [`inject`](super::inject) from `module`.
+///
+/// The injected `i64.const`, `call $gas` pairs are deleted, calls of the function charging for
+/// `memory.grow` are turned back into `memory.grow` instructions and the injected functions,
+/// globals and imports are removed. The indices of all remaining functions and globals are
+/// restored accordingly. Types added to the type section by the instrumentation are kept.
+///
+/// The gas function is located using the last gas metering pass recorded in the manifest, see
+/// [`GasMeteringConfig::with_manifest`](super::GasMeteringConfig::with_manifest). Without a
+/// manifest, the local gas function of the built-in backends is recognized by its body, which
+/// requires the module to contain exactly one. Modules metered through a host function can only
+/// be restored with a manifest.
+///
+/// Only charges of a constant amount are recognized. Hence modules instrumented with lazy
+/// charging, inlining or loop hoisting can't be restored. If the gas function or global is still
+/// referenced after removing the recognized instrumentation, the module is returned unchanged as
+/// an `Err`.
+///
+/// When multiple passes were applied, they must be removed in the reverse order of application.
+pub fn remove(module: elements::Module) -> Result<elements::Module, elements::Module> {
+	let record = gas_metering_record(&module);
+	let meter = match find_meter(&module, record.as_ref()) {
+		Some(meter) => meter,
+		None => return Err(module),
+	};
+
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let grow_counter = (import_count..)
+		.zip(bodies)
+		.find(|(_, body)| is_grow_counter(body.code().elements(), meter.gas_func))
+		.map(|(func_idx, _)| func_idx);
+
+	let mut removed_funcs: Vec<u32> = core::iter::once(meter.gas_func)
+		.chain(grow_counter)
+		.chain(meter.out_of_gas_func)
+		.collect();
+	removed_funcs.sort_unstable();
+
+	let stripped: Vec<FuncBody> = (import_count..)
+		.zip(bodies)
+		.map(|(func_idx, body)| {
+			if removed_funcs.contains(&func_idx) {
+				body.clone()
+			} else {
+				strip_metering(body, meter.gas_func, grow_counter).0
+			}
+		})
+		.collect();
+
+	// Whatever is left of the instrumentation must not be referenced by the remaining code.
+	let references_removed = (import_count..)
+		.zip(&stripped)
+		.filter(|(func_idx, _)| !removed_funcs.contains(func_idx))
+		.flat_map(|(_, body)| body.code().elements())
+		.any(|instruction| match instruction {
+			Instruction::Call(func_idx) => removed_funcs.contains(func_idx),
+			Instruction::GetGlobal(global_idx) | Instruction::SetGlobal(global_idx) =>
+				Some(*global_idx) == meter.gas_global,
+			_ => false,
+		});
+	let exports_removed = module.export_section().map(|es| es.entries()).unwrap_or(&[]).iter().any(
+		|entry| match entry.internal() {
+			elements::Internal::Function(func_idx) => removed_funcs.contains(func_idx),
+			_ => false,
+		},
+	);
+	let elements_removed = module
+		.elements_section()
+		.map(|es| es.entries())
+		.unwrap_or(&[])
+		.iter()
+		.flat_map(|segment| segment.members())
+		.any(|func_idx| removed_funcs.contains(func_idx));
+	let start_removed = module
+		.start_section()
+		.map_or(false, |func_idx| removed_funcs.contains(&func_idx));
+	if references_removed || exports_removed || elements_removed || start_removed {
+		return Err(module)
+	}
+
+	let mut module = module;
+	if let Some(code_section) = module.code_section_mut() {
+		*code_section.bodies_mut() = stripped;
+	}
+	index_space::remove_functions(&mut module, &removed_funcs);
+	if let Some(gas_global) = meter.gas_global {
+		index_space::remove_globals(&mut module, &[gas_global]);
+	}
+	if record.is_some() {
+		manifest::remove_pass(&mut module, manifest::GAS_METERING);
+	}
+
+	Ok(module)
+}
+
+/// Returns the last gas metering pass recorded in the manifest of `module`.
+pub(super) fn gas_metering_record(module: &elements::Module) -> Option<GasMeteringRecord> {
+	manifest::read_manifest(module).and_then(|manifest| {
+		manifest.passes.into_iter().rev().find_map(|pass| match pass {
+			PassRecord::GasMetering(record) => Some(record),
+			_ => None,
+		})
+	})
+}
+
+/// The functions and the global injected by the gas metering.
+pub(super) struct Meter {
+	/// The imported or local function charging the gas.
+	pub(super) gas_func: u32,
+	/// The imported or defined global tracking the gas left.
+	pub(super) gas_global: Option<u32>,
+	/// The imported function called by the local gas function when running out of gas.
+	pub(super) out_of_gas_func: Option<u32>,
+}
+
+/// Locate the injected gas function and global, guided by the manifest `record` if available.
+pub(super) fn find_meter(
+	module: &elements::Module,
+	record: Option<&GasMeteringRecord>,
+) -> Option<Meter> {
+	let imports = module.import_section().map(|is| is.entries()).unwrap_or(&[]);
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+
+	let local_gas_funcs: Vec<(u32, u32, Option<u32>)> = (import_count..)
+		.zip(bodies)
+		.filter_map(|(func_idx, body)| {
+			let (gas_global, out_of_gas_func) =
+				backend::gas_function_global(body.code().elements())?;
+			Some((func_idx, gas_global, out_of_gas_func))
+		})
+		.collect();
+	let local_meter = |gas_global: Option<u32>| {
+		let mut candidates = local_gas_funcs
+			.iter()
+			.filter(|(_, global_idx, _)| gas_global.map_or(true, |idx| idx == *global_idx));
+		match (candidates.next(), candidates.next()) {
+			(Some((gas_func, gas_global, out_of_gas_func)), None) => Some(Meter {
+				gas_func: *gas_func,
+				gas_global: Some(*gas_global),
+				out_of_gas_func: *out_of_gas_func,
+			}),
+			_ => None,
+		}
+	};
+
+	let record = match record {
+		Some(record) => record,
+		None => return local_meter(None),
+	};
+	let is_named = |entry: &elements::ImportEntry, field: &Option<_>| {
+		Some(entry.module()) == record.module.as_deref() && Some(entry.field()) == field.as_deref()
+	};
+	// The injected import goes after all other imports of its kind, so an import of the same
+	// name already present in the original module comes first.
+	match record.meter.as_str() {
+		"external" => imports
+			.iter()
+			.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+			.enumerate()
+			.filter(|(_, entry)| is_named(entry, &record.function))
+			.last()
+			.map(|(func_idx, _)| Meter {
+				gas_func: func_idx as u32,
+				gas_global: None,
+				out_of_gas_func: None,
+			}),
+		"internal" => {
+			let gas_global =
+				module.export_section()?.entries().iter().find_map(|entry| {
+					match entry.internal() {
+						elements::Internal::Global(global_idx)
+							if Some(entry.field()) == record.global.as_deref() =>
+							Some(*global_idx),
+						_ => None,
+					}
+				})?;
+			local_meter(Some(gas_global))
+		},
+		"imported_global" => {
+			let gas_global = imports
+				.iter()
+				.filter(|entry| matches!(entry.external(), elements::External::Global(_)))
+				.enumerate()
+				.filter(|(_, entry)| is_named(entry, &record.global))
+				.last()?
+				.0;
+			local_meter(Some(gas_global as u32))
+		},
+		_ => None,
+	}
+}
+
+/// Returns whether `body` is the function charging for `memory.grow` through `gas_func`.
+pub(super) fn is_grow_counter(body: &[Instruction], gas_func: u32) -> bool {
+	use Instruction::*;
+	matches!(
+		body,
+		[
+			GetLocal(0),
+			GetLocal(0),
+			I64ExtendUI32,
+			I64Const(_),
+			I64Mul,
+			Call(func_idx),
+			GrowMemory(0),
+			End,
+		] if *func_idx == gas_func
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{super::*, *};
+	use crate::test_utils::parse_wat;
+
+	const SOURCE: &str = r#"
+(module
+	(import "env" "f" (func $f (param i32)))
+	(global $g (mut i32) (i32.const 0))
+	(memory 1)
+	(table 1 funcref)
+	(elem (i32.const 0) $h)
+	(func $h (export "h") (param i32) (result i32)
+		(call $f (local.get 0))
+		(if (result i32) (local.get 0)
+			(then (global.set $g (i32.const 1)) (memory.grow (i32.const 1)))
+			(else (i32.const 2))
+		)
+	)
+	(func $main (export "main")
+		(drop (call $h (global.get $g)))
+	)
+	(start $main)
+)
+"#;
+
+	#[test]
+	fn round_trip() {
+		let original = parse_wat(SOURCE);
+		let rules = ConstantCostRules::new(1, 10_000, 1);
+		let config = GasMeteringConfig::new().with_manifest(true);
+		let out_of_gas = mutable_global::OutOfGas::HostFunction { module: "env", function: "oog" };
+
+		let injected = [
+			inject_with_config(
+				original.clone(),
+				host_function::Injector::new("env", "gas"),
+				&rules,
+				&config,
+			),
+			inject_with_config(
+				original.clone(),
+				mutable_global::Injector::new("gas_left").with_out_of_gas(out_of_gas),
+				&rules,
+				&config,
+			),
+			inject_with_config(
+				original.clone(),
+				imported_global::Injector::new("env", "gas"),
+				&rules,
+				&config,
+			),
+			inject_with_config(
+				original.clone(),
+				signed_global::Injector::new("gas_left"),
+				&rules,
+				&GasMeteringConfig::new(),
+			),
+		];
+
+		for module in injected {
+			let module = remove(module.unwrap()).unwrap();
+			assert_eq!(module.import_section(), original.import_section());
+			assert_eq!(module.global_section(), original.global_section());
+			assert_eq!(module.export_section(), original.export_section());
+			assert_eq!(module.elements_section(), original.elements_section());
+			assert_eq!(module.start_section(), original.start_section());
+			assert_eq!(module.function_section(), original.function_section());
+			assert_eq!(module.code_section(), original.code_section());
+			assert!(manifest::read_manifest(&module).is_none());
+		}
+	}
+
+	#[test]
+	fn unrecognized_metering() {
+		let original = parse_wat(SOURCE);
+		let rules = ConstantCostRules::default();
+
+		// Lazy charging isn't done with constant amounts.
+		let module = inject(
+			original.clone(),
+			lazy::Injector::new(mutable_global::Injector::new("gas_left")),
+			&rules,
+		)
+		.unwrap();
+		assert_eq!(remove(module.clone()).unwrap_err(), module);
+
+		// Without a manifest a host gas function can't be told apart from other imports.
+		let module = inject(original, host_function::Injector::new("env", "gas"), &rules).unwrap();
+		assert_eq!(remove(module.clone()).unwrap_err(), module);
+	}
+
+	#[test]
+	fn existing_gas_import() {
+		let original = parse_wat(
+			r#"
+(module
+	(import "env" "gas" (func $gas (param i64)))
+	(func (export "f")
+		(call $gas (i64.const 1))
+	)
+)
+"#,
+		);
+		let config = GasMeteringConfig::new().with_manifest(true);
+
+		let module = inject_with_config(
+			original.clone(),
+			host_function::Injector::new("env", "gas"),
+			&ConstantCostRules::default(),
+			&config,
+		)
+		.unwrap();
+		assert_eq!(module.import_count(elements::ImportCountType::Function), 2);
+		let module = remove(module).unwrap();
+		assert_eq!(module.import_section(), original.import_section());
+		assert_eq!(module.code_section(), original.code_section());
+	}
+}
@@ -0,0 +1,457 @@
+//! Repricing of already metered modules.
+
+use super::{
+	backend, determine_metered_blocks,
+	removal::{find_meter, gas_metering_record, is_grow_counter, Meter},
+	schedule::metered_schedule_hash,
+	ImportCallRules, MemoryGrowCost, Rules,
+};
+use crate::manifest;
+use alloc::{format, vec::Vec};
+use parity_wasm::elements::{self, FuncBody, Instruction};
+
+/// Change the cost schedule of a `module` instrumented by [`inject`](super::inject) with
+/// `old_rules` to `new_rules`.
+///
+/// Instead of instrumenting the original module again, the amounts charged by the existing
+/// `i64.const`, `call $gas` pairs are recomputed and patched in place. This includes the cost of
+/// the local gas function, which is added to every charge, and the per page cost charged for
+/// `memory.grow`. The structure of the metered blocks stays as it is, since it doesn't depend on
+/// the costs. The result is the same as instrumenting the original module with `new_rules`.
+///
+/// Every charge is checked to match the costs according to `old_rules` before it is patched. If
+/// the manifest records the pass, its schedule hash must match `old_rules` as well and is updated
+/// to the one of `new_rules`. The gas function is located in the same way as by
+/// [`remove`](super::remove).
+///
+/// Only charges of a constant amount are recognized. Hence modules instrumented with lazy
+/// charging, inlining or loop hoisting can't be repriced. Neither can dynamic costs for
+/// `memory.grow` be enabled or disabled. If the manifest records the pass, such modules are
+/// rejected with [`RepriceError::Unsupported`] before looking at their code. Otherwise their
+/// charges don't match `old_rules`.
+pub fn reprice<R1: Rules, R2: Rules>(
+	module: elements::Module,
+	old_rules: &R1,
+	new_rules: &R2,
+) -> Result<elements::Module, RepriceError> {
+	let record = gas_metering_record(&module);
+	let meter = match find_meter(&module, record.as_ref()) {
+		Some(meter) => meter,
+		None => return Err(RepriceError::NotMetered(module)),
+	};
+	if let Some(record) = &record {
+		if record.lazy || record.loop_hoisting || record.inline_min_loop_depth.is_some() {
+			return Err(RepriceError::Unsupported(module))
+		}
+		if record.schedule_hash != metered_schedule_hash(&module, &meter, old_rules) {
+			return Err(RepriceError::ScheduleMismatch(module))
+		}
+	}
+	let grow_cost = match (old_rules.memory_grow_cost(), new_rules.memory_grow_cost()) {
+		(MemoryGrowCost::Free, MemoryGrowCost::Free) => None,
+		(MemoryGrowCost::Linear(old_cost), MemoryGrowCost::Linear(new_cost)) =>
+			Some((old_cost.get(), new_cost.get())),
+		_ => return Err(RepriceError::Unsupported(module)),
+	};
+
+	let mut module = module;
+	match repriced_bodies(&module, &meter, grow_cost, old_rules, new_rules) {
+		Some(bodies) =>
+			if let Some(code_section) = module.code_section_mut() {
+				*code_section.bodies_mut() = bodies;
+			},
+		None => return Err(RepriceError::ScheduleMismatch(module)),
+	}
+	if record.is_some() {
+		let schedule = format!("{:016x}", metered_schedule_hash(&module, &meter, new_rules));
+		manifest::set_param(&mut module, manifest::GAS_METERING, "schedule", &schedule);
+	}
+
+	Ok(module)
+}
+
+/// The error returned by [`reprice`].
+///
+/// Every variant holds the original module.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum RepriceError {
+	/// No gas function or global injected by [`inject`](super::inject) was found.
+	NotMetered(elements::Module),
+	/// The module was instrumented with lazy charging, inlining or loop hoisting, or the rules
+	/// enable or disable dynamic costs for `memory.grow`.
+	Unsupported(elements::Module),
+	/// The module wasn't instrumented with the old rules. Either the schedule hash recorded in the
+	/// manifest or one of the charges doesn't match them.
+	ScheduleMismatch(elements::Module),
+}
+
+impl RepriceError {
+	/// Returns the original module.
+	pub fn into_module(self) -> elements::Module {
+		match self {
+			RepriceError::NotMetered(module) |
+			RepriceError::Unsupported(module) |
+			RepriceError::ScheduleMismatch(module) => module,
+		}
+	}
+}
+
+/// Returns the function bodies of `module` with the charges repriced.
+///
+/// `grow_cost` holds the old and new cost per page of `memory.grow` if it is charged dynamically.
+fn repriced_bodies<R1: Rules, R2: Rules>(
+	module: &elements::Module,
+	meter: &Meter,
+	grow_cost: Option<(u32, u32)>,
+	old_rules: &R1,
+	new_rules: &R2,
+) -> Option<Vec<FuncBody>> {
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+
+	// The cost of the local gas function is added to every charge.
+	let (old_gas_fn_cost, new_gas_fn_cost) = match meter
+		.gas_func
+		.checked_sub(import_count)
+		.and_then(|idx| bodies.get(idx as usize))
+	{
+		Some(body) => (
+			backend::local_gas_function_cost(body.code().elements(), old_rules)?,
+			backend::local_gas_function_cost(body.code().elements(), new_rules)?,
+		),
+		None => (0, 0),
+	};
+	let grow_counter = (import_count..)
+		.zip(bodies)
+		.find(|(_, body)| is_grow_counter(body.code().elements(), meter.gas_func))
+		.map(|(func_idx, _)| func_idx);
+
+	let old_rules = ImportCallRules::new(module, old_rules);
+	let new_rules = ImportCallRules::new(module, new_rules);
+	(import_count..)
+		.zip(bodies)
+		.map(|(func_idx, body)| {
+			if func_idx == meter.gas_func {
+				Some(body.clone())
+			} else if Some(func_idx) == grow_counter {
+				let (old_cost, new_cost) = grow_cost?;
+				let mut body = body.clone();
+				match body.code_mut().elements_mut().get_mut(3) {
+					Some(Instruction::I64Const(cost)) if *cost == i64::from(old_cost) =>
+						*cost = i64::from(new_cost),
+					_ => return None,
+				}
+				Some(body)
+			} else {
+				reprice_body(
+					body,
+					meter.gas_func,
+					grow_counter,
+					(&old_rules, old_gas_fn_cost),
+					(&new_rules, new_gas_fn_cost),
+				)
+			}
+		})
+		.collect()
+}
+
+/// Reprice the charges of a single function `body`.
+///
+/// The rules are paired with the cost of the gas function under them.
+fn reprice_body<R1: Rules, R2: Rules>(
+	body: &FuncBody,
+	gas_func: u32,
+	grow_counter: Option<u32>,
+	(old_rules, old_gas_fn_cost): (&R1, u64),
+	(new_rules, new_gas_fn_cost): (&R2, u64),
+) -> Option<FuncBody> {
+	let instructions = body.code().elements();
+
+	// Reconstruct the original instructions and where the charges were inserted into them.
+	let mut original = Vec::with_capacity(instructions.len());
+	// The position in the original instructions, the position of the `i64.const` and its amount.
+	let mut charges = Vec::new();
+	let mut cursor = 0;
+	while let Some(instruction) = instructions.get(cursor) {
+		match (instruction, instructions.get(cursor + 1)) {
+			(Instruction::I64Const(amount), Some(Instruction::Call(idx))) if *idx == gas_func => {
+				charges.push((original.len(), cursor, *amount as u64));
+				cursor += 2;
+				continue
+			},
+			(Instruction::Call(idx), _) if *idx == gas_func => return None,
+			(Instruction::Call(idx), _) if Some(*idx) == grow_counter =>
+				original.push(Instruction::GrowMemory(0)),
+			_ => original.push(instruction.clone()),
+		}
+		cursor += 1;
+	}
+	let original = elements::Instructions::new(original);
+
+	let locals_count = body
+		.locals()
+		.iter()
+		.try_fold(0u32, |count, local| count.checked_add(local.count()))?;
+	let old_blocks = determine_metered_blocks(&original, old_rules, locals_count).ok()?;
+	let new_blocks = determine_metered_blocks(&original, new_rules, locals_count).ok()?;
+	if old_blocks.len() != charges.len() || new_blocks.len() != charges.len() {
+		return None
+	}
+
+	let mut repriced = body.clone();
+	let repriced_instructions = repriced.code_mut().elements_mut();
+	for ((start_pos, cursor, amount), (old_block, new_block)) in
+		charges.into_iter().zip(old_blocks.iter().zip(&new_blocks))
+	{
+		if old_block.start_pos != start_pos ||
+			new_block.start_pos != start_pos ||
+			old_block.cost.checked_add(old_gas_fn_cost)? != amount
+		{
+			return None
+		}
+		let new_amount = new_block.cost.checked_add(new_gas_fn_cost)?;
+		repriced_instructions[cursor] = Instruction::I64Const(new_amount as i64);
+	}
+
+	Some(repriced)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{super::*, *};
+	use crate::test_utils::parse_wat;
+
+	struct StepRules(u32);
+
+	impl Rules for StepRules {
+		fn instruction_cost(&self, instruction: &Instruction) -> Option<u32> {
+			match instruction {
+				Instruction::Call(_) | Instruction::If(_) => Some(3 * self.0),
+				_ => Some(self.0),
+			}
+		}
+
+		fn memory_grow_cost(&self) -> MemoryGrowCost {
+			MemoryGrowCost::Linear(core::num::NonZeroU32::new(7 * self.0).unwrap())
+		}
+
+		fn call_per_local_cost(&self) -> u32 {
+			2 * self.0
+		}
+
+		fn import_call_cost(&self, _module: &str, field: &str, call: &Instruction) -> Option<u32> {
+			match field {
+				"f" => Some(100 * self.0),
+				_ => self.instruction_cost(call),
+			}
+		}
+	}
+
+	#[test]
+	fn reprice_matches_injection() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $f (param i32)))
+	(memory 1)
+	(func $h (export "h") (param i32) (result i32) (local i64)
+		(call $f (local.get 0))
+		(if (result i32) (local.get 0)
+			(then (memory.grow (i32.const 1)))
+			(else
+				(loop
+					(br_if 0 (i32.eqz (local.get 0)))
+				)
+				(i32.const 2)
+			)
+		)
+	)
+)
+"#,
+		);
+		let (old_rules, new_rules) = (StepRules(1), StepRules(5));
+		let config = GasMeteringConfig::new().with_manifest(true);
+
+		fn check<B: Backend>(
+			module: &elements::Module,
+			backend: impl Fn() -> B,
+			config: &GasMeteringConfig,
+			(old_rules, new_rules): (&StepRules, &StepRules),
+		) {
+			let old = inject_with_config(module.clone(), backend(), old_rules, config).unwrap();
+			let new = inject_with_config(module.clone(), backend(), new_rules, config).unwrap();
+			assert_ne!(old, new);
+			assert_eq!(reprice(old, old_rules, new_rules).unwrap(), new);
+		}
+
+		let rules = (&old_rules, &new_rules);
+		check(&module, || host_function::Injector::new("env", "gas"), &config, rules);
+		check(&module, || mutable_global::Injector::new("gas_left"), &config, rules);
+		check(
+			&module,
+			|| signed_global::Injector::new("gas_left"),
+			&GasMeteringConfig::new(),
+			rules,
+		);
+		check(
+			&module,
+			|| {
+				mutable_global::Injector::new("gas_left").with_out_of_gas(
+					mutable_global::OutOfGas::HostFunction { module: "env", function: "oog" },
+				)
+			},
+			&config,
+			rules,
+		);
+	}
+
+	#[test]
+	fn reprice_rejects_mismatch() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (export "f") (param i32)
+		loop
+			local.get 0
+			i32.const -1
+			i32.add
+			local.tee 0
+			br_if 0
+		end
+	)
+)
+"#,
+		);
+		let config = GasMeteringConfig::new().with_manifest(true);
+		let backend = || mutable_global::Injector::new("gas_left");
+
+		assert_eq!(
+			reprice(module.clone(), &StepRules(1), &StepRules(2)),
+			Err(RepriceError::NotMetered(module.clone()))
+		);
+
+		// The old rules must match the module.
+		let metered =
+			inject_with_config(module.clone(), backend(), &StepRules(1), &config).unwrap();
+		assert_eq!(
+			reprice(metered.clone(), &StepRules(2), &StepRules(3)),
+			Err(RepriceError::ScheduleMismatch(metered))
+		);
+		let metered = inject(module.clone(), backend(), &StepRules(1)).unwrap();
+		assert_eq!(
+			reprice(metered.clone(), &StepRules(2), &StepRules(3)),
+			Err(RepriceError::ScheduleMismatch(metered))
+		);
+
+		// Hoisted loops aren't charged with constant amounts. Without a manifest this only shows
+		// in the charges.
+		let hoisting = GasMeteringConfig::new().with_loop_hoisting(true);
+		let metered =
+			inject_with_config(module.clone(), backend(), &StepRules(1), &hoisting).unwrap();
+		assert_eq!(
+			reprice(metered.clone(), &StepRules(1), &StepRules(3)),
+			Err(RepriceError::ScheduleMismatch(metered))
+		);
+
+		// With a manifest, lazy charging, inlining and loop hoisting are rejected up front.
+		let check_unsupported = |metered: elements::Module| {
+			assert_eq!(
+				reprice(metered.clone(), &StepRules(1), &StepRules(3)),
+				Err(RepriceError::Unsupported(metered))
+			);
+		};
+		let hoisting = hoisting.with_manifest(true);
+		check_unsupported(
+			inject_with_config(module.clone(), backend(), &StepRules(1), &hoisting).unwrap(),
+		);
+		check_unsupported(
+			inject_with_config(
+				module.clone(),
+				lazy::Injector::new(backend()),
+				&StepRules(1),
+				&config,
+			)
+			.unwrap(),
+		);
+		let inlined = inject_with_config(
+			module,
+			inline_global::Injector::new("gas_left"),
+			&StepRules(1),
+			&config,
+		)
+		.unwrap();
+		assert_eq!(gas_metering_record(&inlined).unwrap().inline_min_loop_depth, Some(1));
+		check_unsupported(inlined);
+	}
+
+	#[test]
+	fn reprice_checks_import_call_costs() {
+		struct ImportRules(u32);
+
+		impl Rules for ImportRules {
+			fn instruction_cost(&self, _: &Instruction) -> Option<u32> {
+				Some(1)
+			}
+
+			fn memory_grow_cost(&self) -> MemoryGrowCost {
+				MemoryGrowCost::Free
+			}
+
+			fn call_per_local_cost(&self) -> u32 {
+				0
+			}
+
+			fn import_call_cost(
+				&self,
+				_module: &str,
+				field: &str,
+				call: &Instruction,
+			) -> Option<u32> {
+				match field {
+					"f" => Some(self.0),
+					_ => self.instruction_cost(call),
+				}
+			}
+		}
+
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $f))
+	(func (export "g")
+		call $f
+	)
+)
+"#,
+		);
+		let config = GasMeteringConfig::new().with_manifest(true);
+		let backend = || {
+			mutable_global::Injector::new("gas_left").with_out_of_gas(
+				mutable_global::OutOfGas::HostFunction { module: "env", function: "oog" },
+			)
+		};
+		assert_ne!(
+			schedule_hash(&module, &ImportRules(1)),
+			schedule_hash(&module, &ImportRules(2))
+		);
+
+		let metered =
+			inject_with_config(module.clone(), backend(), &ImportRules(1), &config).unwrap();
+		let record = gas_metering_record(&metered).unwrap();
+		assert_eq!(record.schedule_hash, schedule_hash(&module, &ImportRules(1)));
+		assert_eq!(
+			(record.out_of_gas_module.as_deref(), record.out_of_gas_function.as_deref()),
+			(Some("env"), Some("oog"))
+		);
+		assert!(!record.loop_hoisting);
+
+		// The recorded hash covers the cost of calling `f`, but not the injected import.
+		assert_eq!(
+			reprice(metered.clone(), &ImportRules(2), &ImportRules(3)),
+			Err(RepriceError::ScheduleMismatch(metered.clone()))
+		);
+		let new = inject_with_config(module, backend(), &ImportRules(3), &config).unwrap();
+		assert_eq!(reprice(metered, &ImportRules(1), &ImportRules(3)).unwrap(), new);
+	}
+}
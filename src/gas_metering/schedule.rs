@@ -0,0 +1,300 @@
+//! Fingerprinting of cost schedules.
+
+use super::{removal::Meter, MemoryGrowCost, Rules};
+use alloc::{boxed::Box, vec, vec::Vec};
+#[cfg(feature = "sign_ext")]
+use parity_wasm::elements::SignExtInstruction;
+use parity_wasm::elements::{self, BlockType, BrTableData, Instruction};
+
+/// Calculate a hash of the cost schedule described by `rules` for the function imports of
+/// `module`, which is the module about to be instrumented.
+///
+/// The hash covers the costs of every instruction, the [`Rules::import_call_cost`] of every
+/// imported function as well as all other costs specified by [`Rules`]. Sign extension
+/// instructions are only included with the `sign_ext` feature enabled.
+///
+/// Instructions are priced with all their immediates set to zero and imported functions are
+/// priced with a `call` of their index. Hence costs depending on immediates aren't covered, e.g.
+/// on the number of `br_table` targets, the alignment or offset of memory accesses, the local,
+/// global or function index accessed, the type of a `call_indirect` or a block, or the value of
+/// a constant.
+///
+/// This allows to detect whether a module was instrumented with a different schedule than the
+/// current one, e.g. by comparing it with the hash recorded in its manifest. The recorded hash
+/// is the one of the module before the pass, i.e. without the imports added by the gas metering.
+/// It is a 64 bit FNV-1a hash and isn't meant to be collision resistant against adversarial
+/// schedules.
+pub fn schedule_hash<R: Rules>(module: &elements::Module, rules: &R) -> u64 {
+	hash_schedule(rules, function_imports(module))
+}
+
+/// Calculate the [`schedule_hash`] of a `module` instrumented already, leaving out the imported
+/// functions of `meter`.
+pub(super) fn metered_schedule_hash<R: Rules>(
+	module: &elements::Module,
+	meter: &Meter,
+	rules: &R,
+) -> u64 {
+	let added = [Some(meter.gas_func), meter.out_of_gas_func];
+	hash_schedule(
+		rules,
+		function_imports(module)
+			.enumerate()
+			.filter(|(func_idx, _)| !added.contains(&Some(*func_idx as u32)))
+			.map(|(_, import)| import),
+	)
+}
+
+fn function_imports(module: &elements::Module) -> impl Iterator<Item = (&str, &str)> {
+	module
+		.import_section()
+		.map(|is| is.entries())
+		.unwrap_or(&[])
+		.iter()
+		.filter(|entry| matches!(entry.external(), elements::External::Function(_)))
+		.map(|entry| (entry.module(), entry.field()))
+}
+
+fn hash_schedule<'a, R: Rules>(
+	rules: &R,
+	imports: impl Iterator<Item = (&'a str, &'a str)>,
+) -> u64 {
+	let mut hasher = Fnv1a::new();
+
+	for instruction in supported_instructions() {
+		hasher.write_cost(rules.instruction_cost(&instruction));
+	}
+	for (func_idx, (module, field)) in imports.enumerate() {
+		hasher.write_cost(rules.import_call_cost(
+			module,
+			field,
+			&Instruction::Call(func_idx as u32),
+		));
+	}
+	match rules.memory_grow_cost() {
+		MemoryGrowCost::Free => hasher.write_cost(None),
+		MemoryGrowCost::Linear(cost) => hasher.write_cost(Some(cost.get())),
+	}
+	hasher.write_cost(Some(rules.call_per_local_cost()));
+	hasher.write_cost(Some(rules.segment_cost()));
+	hasher.write_cost(Some(rules.data_segment_byte_cost()));
+	hasher.write_cost(Some(rules.element_segment_entry_cost()));
+
+	hasher.finish()
+}
+
+struct Fnv1a(u64);
+
+impl Fnv1a {
+	fn new() -> Self {
+		Self(0xcbf2_9ce4_8422_2325)
+	}
+
+	fn write_cost(&mut self, cost: Option<u32>) {
+		let (tag, cost) = match cost {
+			Some(cost) => (1u8, cost),
+			None => (0u8, 0),
+		};
+		for byte in [tag].iter().chain(cost.to_le_bytes().iter()) {
+			self.0 ^= u64::from(*byte);
+			self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+		}
+	}
+
+	fn finish(&self) -> u64 {
+		self.0
+	}
+}
+
+/// Returns every instruction supported by the enabled features in opcode order, with all
+/// immediates set to zero.
+///
+/// These are the instructions whose costs are covered by [`schedule_hash`].
+pub fn supported_instructions() -> Vec<Instruction> {
+	use Instruction::*;
+
+	#[allow(unused_mut)]
+	let mut instructions = vec![
+		Unreachable,
+		Nop,
+		Block(BlockType::NoResult),
+		Loop(BlockType::NoResult),
+		If(BlockType::NoResult),
+		Else,
+		End,
+		Br(0),
+		BrIf(0),
+		BrTable(Box::new(BrTableData { table: Box::new([]), default: 0 })),
+		Return,
+		Call(0),
+		CallIndirect(0, 0),
+		Drop,
+		Select,
+		GetLocal(0),
+		SetLocal(0),
+		TeeLocal(0),
+		GetGlobal(0),
+		SetGlobal(0),
+		I32Load(0, 0),
+		I64Load(0, 0),
+		F32Load(0, 0),
+		F64Load(0, 0),
+		I32Load8S(0, 0),
+		I32Load8U(0, 0),
+		I32Load16S(0, 0),
+		I32Load16U(0, 0),
+		I64Load8S(0, 0),
+		I64Load8U(0, 0),
+		I64Load16S(0, 0),
+		I64Load16U(0, 0),
+		I64Load32S(0, 0),
+		I64Load32U(0, 0),
+		I32Store(0, 0),
+		I64Store(0, 0),
+		F32Store(0, 0),
+		F64Store(0, 0),
+		I32Store8(0, 0),
+		I32Store16(0, 0),
+		I64Store8(0, 0),
+		I64Store16(0, 0),
+		I64Store32(0, 0),
+		CurrentMemory(0),
+		GrowMemory(0),
+		I32Const(0),
+		I64Const(0),
+		F32Const(0),
+		F64Const(0),
+		I32Eqz,
+		I32Eq,
+		I32Ne,
+		I32LtS,
+		I32LtU,
+		I32GtS,
+		I32GtU,
+		I32LeS,
+		I32LeU,
+		I32GeS,
+		I32GeU,
+		I64Eqz,
+		I64Eq,
+		I64Ne,
+		I64LtS,
+		I64LtU,
+		I64GtS,
+		I64GtU,
+		I64LeS,
+		I64LeU,
+		I64GeS,
+		I64GeU,
+		F32Eq,
+		F32Ne,
+		F32Lt,
+		F32Gt,
+		F32Le,
+		F32Ge,
+		F64Eq,
+		F64Ne,
+		F64Lt,
+		F64Gt,
+		F64Le,
+		F64Ge,
+		I32Clz,
+		I32Ctz,
+		I32Popcnt,
+		I32Add,
+		I32Sub,
+		I32Mul,
+		I32DivS,
+		I32DivU,
+		I32RemS,
+		I32RemU,
+		I32And,
+		I32Or,
+		I32Xor,
+		I32Shl,
+		I32ShrS,
+		I32ShrU,
+		I32Rotl,
+		I32Rotr,
+		I64Clz,
+		I64Ctz,
+		I64Popcnt,
+		I64Add,
+		I64Sub,
+		I64Mul,
+		I64DivS,
+		I64DivU,
+		I64RemS,
+		I64RemU,
+		I64And,
+		I64Or,
+		I64Xor,
+		I64Shl,
+		I64ShrS,
+		I64ShrU,
+		I64Rotl,
+		I64Rotr,
+		F32Abs,
+		F32Neg,
+		F32Ceil,
+		F32Floor,
+		F32Trunc,
+		F32Nearest,
+		F32Sqrt,
+		F32Add,
+		F32Sub,
+		F32Mul,
+		F32Div,
+		F32Min,
+		F32Max,
+		F32Copysign,
+		F64Abs,
+		F64Neg,
+		F64Ceil,
+		F64Floor,
+		F64Trunc,
+		F64Nearest,
+		F64Sqrt,
+		F64Add,
+		F64Sub,
+		F64Mul,
+		F64Div,
+		F64Min,
+		F64Max,
+		F64Copysign,
+		I32WrapI64,
+		I32TruncSF32,
+		I32TruncUF32,
+		I32TruncSF64,
+		I32TruncUF64,
+		I64ExtendSI32,
+		I64ExtendUI32,
+		I64TruncSF32,
+		I64TruncUF32,
+		I64TruncSF64,
+		I64TruncUF64,
+		F32ConvertSI32,
+		F32ConvertUI32,
+		F32ConvertSI64,
+		F32ConvertUI64,
+		F32DemoteF64,
+		F64ConvertSI32,
+		F64ConvertUI32,
+		F64ConvertSI64,
+		F64ConvertUI64,
+		F64PromoteF32,
+		I32ReinterpretF32,
+		I64ReinterpretF64,
+		F32ReinterpretI32,
+		F64ReinterpretI64,
+	];
+	#[cfg(feature = "sign_ext")]
+	instructions.extend_from_slice(&[
+		SignExt(SignExtInstruction::I32Extend8S),
+		SignExt(SignExtInstruction::I32Extend16S),
+		SignExt(SignExtInstruction::I64Extend8S),
+		SignExt(SignExtInstruction::I64Extend16S),
+		SignExt(SignExtInstruction::I64Extend32S),
+	]);
+	instructions
+}
@@ -1,4 +1,5 @@
-//! This module is used to validate the correctness of the gas metering algorithm.
+//! This module is used to validate the correctness of the gas metering algorithm and to verify
+//! the metering of already instrumented modules.
 //!
 //! Since the gas metering algorithm is complex, this checks correctness by fuzzing. The testing
 //! strategy is to generate random, valid Wasm modules using Binaryen's translate-to-fuzz
@@ -6,11 +7,16 @@
 //! function body that do not trap that the amount of gas charged by the proposed metering
 //! instructions is correct. This is done by constructing a control flow graph and exhaustively
 //! searching through all paths, which may take exponential time in the size of the function body in
-//! the worst case.
+//! the worst case. Metering instructions which charge lazily are validated the same way by tracking
+//! the costs accumulated but not yet charged along each path.
+//!
+//! The same control flow graph is used by [`verify_metering`] to check the metering of modules
+//! which were instrumented elsewhere. Instead of searching through all paths, this computes the
+//! cheapest ones, which takes polynomial time.
 
-use super::{ConstantCostRules, MeteredBlock, Rules};
-use parity_wasm::elements::{FuncBody, Instruction};
-use std::collections::BTreeMap as Map;
+use super::{ImportCallRules, MemoryGrowCost, MeteredBlock, Rules};
+use alloc::{vec, vec::Vec};
+use parity_wasm::elements::{self, FuncBody, Instruction};
 
 /// An ID for a node in a ControlFlowGraph.
 type NodeId = usize;
@@ -26,8 +32,19 @@ struct ControlFlowNode {
 	actual_cost: u64,
 
 	/// The amount of gas charged by the injected metering instructions within this basic block.
+	///
+	/// When charging lazily, this is the cost accumulated within this basic block before the last
+	/// flush in it.
 	charged_cost: u64,
 
+	/// The cost accumulated within this basic block after the last flush in it when charging
+	/// lazily. This is charged by a later flush.
+	deferred_cost: u64,
+
+	/// Whether the costs accumulated so far are charged within this basic block when charging
+	/// lazily.
+	flushes: bool,
+
 	/// Whether there are any other nodes in the graph that loop back to this one. Every cycle in
 	/// the control flow graph contains at least one node with this flag set.
 	is_loop_target: bool,
@@ -75,6 +92,17 @@ impl ControlFlowGraph {
 		self.get_node_mut(node_id).charged_cost += cost;
 	}
 
+	fn increment_deferred_cost(&mut self, node_id: NodeId, cost: u64) {
+		self.get_node_mut(node_id).deferred_cost += cost;
+	}
+
+	fn flush(&mut self, node_id: NodeId) {
+		let node = self.get_node_mut(node_id);
+		node.charged_cost += node.deferred_cost;
+		node.deferred_cost = 0;
+		node.flushes = true;
+	}
+
 	fn set_first_instr_pos(&mut self, node_id: NodeId, first_instr_pos: usize) {
 		self.get_node_mut(node_id).first_instr_pos = Some(first_instr_pos)
 	}
@@ -101,6 +129,9 @@ impl ControlFlowGraph {
 /// instructions and is closed by `end` instructions.
 struct ControlFrame {
 	is_loop: bool,
+	/// Whether this frame was opened by an `if` and no `else` was encountered yet. Execution can
+	/// skip the frame entirely then.
+	is_if_without_else: bool,
 	entry_node: NodeId,
 	exit_node: NodeId,
 	active_node: NodeId,
@@ -110,6 +141,7 @@ impl ControlFrame {
 	fn new(entry_node_id: NodeId, exit_node_id: NodeId, is_loop: bool) -> Self {
 		ControlFrame {
 			is_loop,
+			is_if_without_else: false,
 			entry_node: entry_node_id,
 			exit_node: exit_node_id,
 			active_node: entry_node_id,
@@ -119,11 +151,14 @@ impl ControlFrame {
 
 /// Construct a control flow graph from a function body and the metered blocks computed for it.
 ///
-/// This assumes that the function body has been validated already, otherwise this may panic.
+/// The costs are charged lazily if `flush_points` are specified.
+///
+/// Fails if the control flow of the function body is malformed.
 fn build_control_flow_graph(
 	body: &FuncBody,
 	rules: &impl Rules,
 	blocks: &[MeteredBlock],
+	flush_points: Option<&[usize]>,
 ) -> Result<ControlFlowGraph, ()> {
 	let mut graph = ControlFlowGraph::new();
 
@@ -134,6 +169,7 @@ fn build_control_flow_graph(
 
 	let mut stack = vec![ControlFrame::new(entry_node_id, terminal_node_id, false)];
 	let mut metered_blocks_iter = blocks.iter().peekable();
+	let mut flush_points_iter = flush_points.unwrap_or(&[]).iter().peekable();
 
 	let locals_count = body
 		.locals()
@@ -143,10 +179,7 @@ fn build_control_flow_graph(
 	let locals_init_cost = rules.call_per_local_cost().checked_mul(locals_count).ok_or(())?;
 
 	for (cursor, instruction) in body.code().elements().iter().enumerate() {
-		let active_node_id = stack
-			.last()
-			.expect("module is valid by pre-condition; control stack must not be empty; qed")
-			.active_node;
+		let active_node_id = stack.last().ok_or(())?.active_node;
 
 		// Increment the charged cost if there are metering instructions to be inserted here.
 		let apply_block =
@@ -154,7 +187,16 @@ fn build_control_flow_graph(
 		if apply_block {
 			let next_metered_block =
 				metered_blocks_iter.next().expect("peek returned an item; qed");
-			graph.increment_charged_cost(active_node_id, next_metered_block.cost);
+			if flush_points.is_some() {
+				graph.increment_deferred_cost(active_node_id, next_metered_block.cost);
+			} else {
+				graph.increment_charged_cost(active_node_id, next_metered_block.cost);
+			}
+		}
+
+		// Charge the accumulated costs if they are flushed here.
+		if flush_points_iter.next_if(|pos| **pos == cursor).is_some() {
+			graph.flush(active_node_id);
 		}
 
 		// Add locals initialization cost to the function block.
@@ -175,7 +217,9 @@ fn build_control_flow_graph(
 				let then_node_id = graph.add_node();
 				let exit_node_id = graph.add_node();
 
-				stack.push(ControlFrame::new(then_node_id, exit_node_id, false));
+				let mut frame = ControlFrame::new(then_node_id, exit_node_id, false);
+				frame.is_if_without_else = true;
+				stack.push(frame);
 				graph.new_forward_edge(active_node_id, then_node_id);
 				graph.set_first_instr_pos(then_node_id, cursor + 1);
 			},
@@ -191,22 +235,31 @@ fn build_control_flow_graph(
 			},
 			Instruction::Else => {
 				let active_frame_idx = stack.len() - 1;
-				let prev_frame_idx = stack.len() - 2;
+				let prev_frame_idx = active_frame_idx.checked_sub(1).ok_or(())?;
+
+				// The then branch continues after the `end`.
+				graph.new_forward_edge(active_node_id, stack[active_frame_idx].exit_node);
 
 				let else_node_id = graph.add_node();
 				stack[active_frame_idx].active_node = else_node_id;
+				stack[active_frame_idx].is_if_without_else = false;
 
 				let prev_node_id = stack[prev_frame_idx].active_node;
 				graph.new_forward_edge(prev_node_id, else_node_id);
 				graph.set_first_instr_pos(else_node_id, cursor + 1);
 			},
 			Instruction::End => {
-				let closing_frame = stack.pop()
-					.expect("module is valid by pre-condition; ends correspond to control stack frames; qed");
+				let closing_frame = stack.pop().ok_or(())?;
 
 				graph.new_forward_edge(active_node_id, closing_frame.exit_node);
 				graph.set_first_instr_pos(closing_frame.exit_node, cursor + 1);
 
+				// An `if` without an `else` is skipped if the condition is false.
+				if closing_frame.is_if_without_else {
+					let prev_node_id = stack.last().ok_or(())?.active_node;
+					graph.new_forward_edge(prev_node_id, closing_frame.exit_node);
+				}
+
 				if let Some(active_frame) = stack.last_mut() {
 					active_frame.active_node = closing_frame.exit_node;
 				}
@@ -215,7 +268,7 @@ fn build_control_flow_graph(
 				graph.increment_actual_cost(active_node_id, instruction_cost);
 
 				let active_frame_idx = stack.len() - 1;
-				let target_frame_idx = active_frame_idx - (*label as usize);
+				let target_frame_idx = active_frame_idx.checked_sub(*label as usize).ok_or(())?;
 				graph.new_edge(active_node_id, &stack[target_frame_idx]);
 
 				// Next instruction is unreachable, but carry on anyway.
@@ -227,7 +280,7 @@ fn build_control_flow_graph(
 				graph.increment_actual_cost(active_node_id, instruction_cost);
 
 				let active_frame_idx = stack.len() - 1;
-				let target_frame_idx = active_frame_idx - (*label as usize);
+				let target_frame_idx = active_frame_idx.checked_sub(*label as usize).ok_or(())?;
 				graph.new_edge(active_node_id, &stack[target_frame_idx]);
 
 				let new_node_id = graph.add_node();
@@ -240,7 +293,7 @@ fn build_control_flow_graph(
 
 				let active_frame_idx = stack.len() - 1;
 				for &label in [br_table_data.default].iter().chain(br_table_data.table.iter()) {
-					let target_frame_idx = active_frame_idx - (label as usize);
+					let target_frame_idx = active_frame_idx.checked_sub(label as usize).ok_or(())?;
 					graph.new_edge(active_node_id, &stack[target_frame_idx]);
 				}
 
@@ -262,7 +315,9 @@ fn build_control_flow_graph(
 		}
 	}
 
-	assert!(stack.is_empty());
+	if !stack.is_empty() {
+		return Err(())
+	}
 
 	Ok(graph)
 }
@@ -274,39 +329,56 @@ fn build_control_flow_graph(
 /// and charged gas costs. If this returns true, then the metered blocks used to construct the
 /// control flow graph are correct with respect to the function body.
 ///
+/// When charging lazily, the costs accumulated but not yet charged are tracked along each path.
+/// Nothing must be left uncharged at the end of a path or when looping back.
+///
 /// In the worst case, this runs in time exponential in the size of the graph.
+#[cfg(test)]
 fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
+	use alloc::collections::BTreeMap as Map;
+
 	fn visit(
 		graph: &ControlFlowGraph,
 		node_id: NodeId,
 		mut total_actual: u64,
 		mut total_charged: u64,
+		mut pending: u64,
 		loop_costs: &mut Map<NodeId, (u64, u64)>,
 	) -> bool {
 		let node = graph.get_node(node_id);
 
-		total_actual += node.actual_cost;
-		total_charged += node.charged_cost;
-
+		// Upon entering a loop again, everything accumulated before the loop has been charged.
 		if node.is_loop_target {
-			loop_costs.insert(node_id, (node.actual_cost, node.charged_cost));
+			loop_costs.insert(node_id, (total_actual, total_charged + pending));
+		}
+
+		total_actual += node.actual_cost;
+		if node.flushes {
+			total_charged += pending + node.charged_cost;
+			pending = node.deferred_cost;
+		} else {
+			total_charged += node.charged_cost;
+			pending += node.deferred_cost;
 		}
 
-		if node.forward_edges.is_empty() && total_actual != total_charged {
+		// Nodes only looping back don't end a path through the function. Their costs are checked
+		// along with the loop below.
+		let is_terminal = node.forward_edges.is_empty() && node.loopback_edges.is_empty();
+		if is_terminal && (total_actual != total_charged || pending != 0) {
 			return false
 		}
 
 		for loop_node_id in node.loopback_edges.iter() {
-			let (loop_actual, loop_charged) = loop_costs
-				.get_mut(loop_node_id)
+			let (entry_actual, entry_charged) = loop_costs
+				.get(loop_node_id)
 				.expect("cannot arrive at loopback edge without visiting loop entry node");
-			if loop_actual != loop_charged {
+			if pending != 0 || total_actual - entry_actual != total_charged - entry_charged {
 				return false
 			}
 		}
 
 		for next_node_id in node.forward_edges.iter() {
-			if !visit(graph, *next_node_id, total_actual, total_charged, loop_costs) {
+			if !visit(graph, *next_node_id, total_actual, total_charged, pending, loop_costs) {
 				return false
 			}
 		}
@@ -319,24 +391,249 @@ fn validate_graph_gas_costs(graph: &ControlFlowGraph) -> bool {
 	}
 
 	// Recursively explore all paths through the execution graph starting from the entry node.
-	visit(graph, 0, 0, 0, &mut Map::new())
+	visit(graph, 0, 0, 0, 0, &mut Map::new())
 }
 
 /// Validate that the metered blocks are correct with respect to the function body by exhaustively
 /// searching all paths through the control flow graph.
 ///
-/// This assumes that the function body has been validated already, otherwise this may panic.
+/// Fails if the control flow of the function body is malformed.
+#[cfg(test)]
 fn validate_metering_injections(
 	body: &FuncBody,
 	rules: &impl Rules,
 	blocks: &[MeteredBlock],
+	flush_points: Option<&[usize]>,
 ) -> Result<bool, ()> {
-	let graph = build_control_flow_graph(body, rules, blocks)?;
+	let graph = build_control_flow_graph(body, rules, blocks, flush_points)?;
 	Ok(validate_graph_gas_costs(&graph))
 }
 
+/// Check that the gas charged along every path through the control flow graph covers the actual
+/// costs of the path, assuming that nothing is charged lazily. This is the case if 1) all paths
+/// with only forward edges from the first node to a node without forward edges are charged at
+/// least their actual gas cost, and 2) the same holds for all cycles beginning with a loop entry
+/// point and ending with a node with a loop-back edge to the entry point. Every other path is a
+/// combination of those.
+///
+/// Instead of enumerating the paths, this computes the cheapest path from the first node and from
+/// every loop entry point in the DAG formed by the forward edges. Hence, this runs in time
+/// `O(L * (V + E))` where `L` is the number of loops.
+fn graph_covers_gas_costs(graph: &ControlFlowGraph) -> bool {
+	// The balance of charged and actual costs of each node.
+	let balances: Vec<i128> = graph
+		.nodes
+		.iter()
+		.map(|node| i128::from(node.charged_cost) - i128::from(node.actual_cost))
+		.collect();
+
+	// Kahn's algorithm, which also drops nodes on cycles of forward edges. There are none by
+	// construction.
+	let mut in_degrees = vec![0usize; graph.nodes.len()];
+	for node in graph.nodes.iter() {
+		for next_node_id in node.forward_edges.iter() {
+			in_degrees[*next_node_id] += 1;
+		}
+	}
+	let mut topological_order: Vec<NodeId> =
+		(0..graph.nodes.len()).filter(|node_id| in_degrees[*node_id] == 0).collect();
+	let mut cursor = 0;
+	while let Some(&node_id) = topological_order.get(cursor) {
+		for next_node_id in graph.get_node(node_id).forward_edges.iter() {
+			in_degrees[*next_node_id] -= 1;
+			if in_degrees[*next_node_id] == 0 {
+				topological_order.push(*next_node_id);
+			}
+		}
+		cursor += 1;
+	}
+	if topological_order.len() != graph.nodes.len() {
+		return false
+	}
+
+	// The smallest balance of all paths from `start` to every node, if there is any.
+	let cheapest_paths = |start: NodeId| {
+		let mut balances_from_start: Vec<Option<i128>> = vec![None; graph.nodes.len()];
+		balances_from_start[start] = Some(balances[start]);
+		for &node_id in topological_order.iter() {
+			let balance = match balances_from_start[node_id] {
+				Some(balance) => balance,
+				None => continue,
+			};
+			for &next_node_id in graph.get_node(node_id).forward_edges.iter() {
+				let next_balance = balance + balances[next_node_id];
+				let entry = &mut balances_from_start[next_node_id];
+				*entry = Some(entry.map_or(next_balance, |old| old.min(next_balance)));
+			}
+		}
+		balances_from_start
+	};
+
+	let paths_covered =
+		cheapest_paths(0)
+			.iter()
+			.zip(graph.nodes.iter())
+			.all(|(balance, node)| match balance {
+				Some(balance) if node.forward_edges.is_empty() => *balance >= 0,
+				_ => true,
+			});
+	let loops_covered = || {
+		graph.nodes.iter().enumerate().filter(|(_, node)| node.is_loop_target).all(
+			|(loop_node_id, _)| {
+				cheapest_paths(loop_node_id).iter().zip(graph.nodes.iter()).all(
+					|(balance, node)| match balance {
+						Some(balance) if node.loopback_edges.contains(&loop_node_id) =>
+							*balance >= 0,
+						_ => true,
+					},
+				)
+			},
+		)
+	};
+
+	paths_covered && loops_covered()
+}
+
+/// Split an instrumented function body into the original instructions and the metered blocks
+/// charged by the injected `i64.const`, `call $gas` pairs.
+///
+/// Calls of the `grow_counter` function are replaced by the `memory.grow` instruction they stand
+/// for.
+pub(super) fn strip_metering(
+	body: &FuncBody,
+	gas_func_idx: u32,
+	grow_counter: Option<u32>,
+) -> (FuncBody, Vec<MeteredBlock>) {
+	let instructions = body.code().elements();
+	let mut original = Vec::with_capacity(instructions.len());
+	let mut blocks: Vec<MeteredBlock> = Vec::new();
+
+	let mut cursor = 0;
+	while let Some(instruction) = instructions.get(cursor) {
+		match (instruction, instructions.get(cursor + 1)) {
+			(Instruction::I64Const(amount), Some(Instruction::Call(idx)))
+				if *idx == gas_func_idx =>
+			{
+				let amount = *amount as u64;
+				match blocks.last_mut() {
+					Some(block) if block.start_pos == original.len() =>
+						block.cost = block.cost.saturating_add(amount),
+					_ => blocks
+						.push(MeteredBlock { cost: amount, ..MeteredBlock::new(original.len()) }),
+				}
+				cursor += 2;
+				continue
+			},
+			(Instruction::Call(idx), _) if Some(*idx) == grow_counter =>
+				original.push(Instruction::GrowMemory(0)),
+			_ => original.push(instruction.clone()),
+		}
+		cursor += 1;
+	}
+
+	(FuncBody::new(body.locals().to_vec(), elements::Instructions::new(original)), blocks)
+}
+
+/// Verify that the functions of an instrumented `module` are metered correctly according to
+/// `rules`.
+///
+/// `gas_func_idx` is the index of the function charging the gas, which is either the imported
+/// host function or the local gas function. Every path through a function body must charge at
+/// least the costs of the instructions along the path. Only charges of a constant amount right
+/// before a call of the gas function are recognized, as injected by [`inject`](super::inject)
+/// without lazy charging, inlining or loop hoisting.
+///
+/// The gas function must either be imported or be a local gas function as injected by one of the
+/// built-in backends, otherwise `gas_func_idx` is returned as an `Err`. The function charging for
+/// `memory.grow` isn't verified itself. No other function may write the globals written by the
+/// local gas function. This runs in polynomial time in the size of the module.
+///
+/// Charges are attributed to the straight-line code they are part of. Thus, a charge placed after
+/// the instructions it pays for is accepted, even though an instruction trapping in between, like
+/// a division by zero or an out-of-bounds memory access, ends the execution before the preceding
+/// work was charged. [`inject`](super::inject) always charges at the start of a metered block.
+///
+/// Returns the index of the first function whose metering couldn't be verified as an `Err`.
+pub fn verify_metering<R: Rules>(
+	module: &elements::Module,
+	rules: &R,
+	gas_func_idx: u32,
+) -> Result<(), u32> {
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let rules = ImportCallRules::new(module, rules);
+
+	let grow_counter = match rules.memory_grow_cost() {
+		MemoryGrowCost::Free => None,
+		MemoryGrowCost::Linear(cost) => {
+			let grow_counter_body = [
+				Instruction::GetLocal(0),
+				Instruction::GetLocal(0),
+				Instruction::I64ExtendUI32,
+				Instruction::I64Const(i64::from(cost.get())),
+				Instruction::I64Mul,
+				Instruction::Call(gas_func_idx),
+				Instruction::GrowMemory(0),
+				Instruction::End,
+			];
+			(import_count..)
+				.zip(bodies)
+				.find(|(_, body)| body.code().elements() == grow_counter_body)
+				.map(|(func_idx, _)| func_idx)
+		},
+	};
+
+	let gas_func_body = match gas_func_idx.checked_sub(import_count) {
+		None => None,
+		Some(idx) => match bodies.get(idx as usize) {
+			Some(body) if super::backend::gas_function_global(body.code().elements()).is_some() =>
+				Some(body),
+			_ => return Err(gas_func_idx),
+		},
+	};
+	let gas_globals: Vec<u32> = gas_func_body
+		.iter()
+		.flat_map(|body| body.code().elements())
+		.filter_map(|instruction| match instruction {
+			Instruction::SetGlobal(global_idx) => Some(*global_idx),
+			_ => None,
+		})
+		.collect();
+
+	for (func_idx, body) in (import_count..).zip(bodies) {
+		if func_idx == gas_func_idx || Some(func_idx) == grow_counter {
+			continue
+		}
+
+		let (original, blocks) = strip_metering(body, gas_func_idx, grow_counter);
+		let writes_gas_global = original.code().elements().iter().any(|instruction| {
+			matches!(instruction, Instruction::SetGlobal(global_idx) if gas_globals.contains(global_idx))
+		});
+		// With dynamic costs every `memory.grow` must have been replaced by the grow counter.
+		let uncounted_grow = rules.memory_grow_cost().enabled() &&
+			body.code()
+				.elements()
+				.iter()
+				.any(|instruction| matches!(instruction, Instruction::GrowMemory(_)));
+		let covered = !writes_gas_global &&
+			!uncounted_grow &&
+			build_control_flow_graph(&original, &rules, &blocks, None)
+				.map_or(false, |graph| graph_covers_gas_costs(&graph));
+		if !covered {
+			return Err(func_idx)
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
 mod tests {
-	use super::{super::determine_metered_blocks, *};
+	use super::{
+		super::{determine_flush_points, determine_metered_blocks, ConstantCostRules},
+		*,
+	};
+	use crate::test_utils::parse_wat;
 
 	use binaryen::tools::translate_to_fuzz_mvp;
 	use parity_wasm::elements;
@@ -359,9 +656,126 @@ mod tests {
 				let metered_blocks =
 					determine_metered_blocks(func_body.code(), &rules, locals_count).unwrap();
 				let success =
-					validate_metering_injections(func_body, &rules, &metered_blocks).unwrap();
+					validate_metering_injections(func_body, &rules, &metered_blocks, None).unwrap();
+				assert!(success);
+
+				let flush_points = determine_flush_points(func_body.code()).unwrap();
+				let success = validate_metering_injections(
+					func_body,
+					&rules,
+					&metered_blocks,
+					Some(&flush_points),
+				)
+				.unwrap();
 				assert!(success);
 			}
 		}
 	}
+
+	#[test]
+	fn verify_fuzzed_metering() {
+		use super::super::{host_function, inject, mutable_global};
+
+		let rules = ConstantCostRules::new(1, 1000, 1);
+		for _ in 0..20 {
+			let mut rand_input = [0u8; 2048];
+			thread_rng().fill_bytes(&mut rand_input);
+
+			let module_bytes = translate_to_fuzz_mvp(&rand_input).write();
+			let module: elements::Module = elements::deserialize_buffer(&module_bytes)
+				.expect("failed to parse Wasm blob generated by translate_to_fuzz");
+			let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+			let functions_space = module.functions_space() as u32;
+
+			let injected =
+				inject(module.clone(), host_function::Injector::new("env", "gas"), &rules).unwrap();
+			assert_eq!(verify_metering(&injected, &rules, import_count), Ok(()));
+
+			let injected =
+				inject(module, mutable_global::Injector::new("gas_left"), &rules).unwrap();
+			assert_eq!(verify_metering(&injected, &rules, functions_space), Ok(()));
+		}
+	}
+
+	#[test]
+	fn verify_rejects_undercharging() {
+		use super::super::{inject, mutable_global};
+
+		let module = parse_wat(
+			r#"
+(module
+	(global $g (mut i32) (i32.const 0))
+	(func (param i32) (result i32)
+		loop
+			local.get 0
+			i32.const 1
+			i32.sub
+			local.tee 0
+			br_if 0
+		end
+		local.get 0
+	)
+)
+"#,
+		);
+		let rules = ConstantCostRules::default();
+		let injected = inject(module, mutable_global::Injector::new("gas_left"), &rules).unwrap();
+		assert_eq!(verify_metering(&injected, &rules, 1), Ok(()));
+
+		// Charging less for the loop body than its actual costs.
+		let mut tampered = injected.clone();
+		let body = tampered.code_section_mut().unwrap().bodies_mut()[0].code_mut().elements_mut();
+		let charge = body.iter().rposition(|i| matches!(i, Instruction::I64Const(_))).unwrap();
+		body[charge] = Instruction::I64Const(1);
+		assert_eq!(verify_metering(&tampered, &rules, 1), Err(0));
+
+		// Refilling the gas global.
+		let mut tampered = injected.clone();
+		let body = tampered.code_section_mut().unwrap().bodies_mut()[0].code_mut().elements_mut();
+		body.splice(0..0, [Instruction::I64Const(1000), Instruction::SetGlobal(1)]);
+		assert_eq!(verify_metering(&tampered, &rules, 1), Err(0));
+
+		// A gas function that doesn't charge anything.
+		let mut tampered = injected;
+		let body = tampered.code_section_mut().unwrap().bodies_mut()[1].code_mut().elements_mut();
+		*body = vec![Instruction::End];
+		assert_eq!(verify_metering(&tampered, &rules, 1), Err(1));
+
+		// An index beyond the function index space.
+		assert_eq!(verify_metering(&tampered, &rules, 2), Err(2));
+	}
+
+	#[test]
+	fn verify_rejects_undercharged_branches() {
+		let rules = ConstantCostRules::default();
+		let verify = |body: &str| {
+			let module = parse_wat(&format!(
+				r#"(module (import "env" "gas" (func (param i64))) (func (param i32) {}))"#,
+				body
+			));
+			verify_metering(&module, &rules, 0)
+		};
+		let nops = "nop ".repeat(90);
+
+		// The code after the `if` is charged before it.
+		let body = "i64.const 92 call 0 local.get 0 if i64.const 1 call 0 nop end";
+		assert_eq!(verify(&format!("{} {}", body, nops)), Ok(()));
+		let body = "i64.const 92 call 0 local.get 0 if i64.const 1 call 0 nop else i64.const 2 \
+			call 0 nop nop end";
+		assert_eq!(verify(&format!("{} {}", body, nops)), Ok(()));
+
+		// The code after an `if` without `else` is only charged in the then branch.
+		let body = "i64.const 2 call 0 local.get 0 if i64.const 91 call 0 nop end";
+		assert_eq!(verify(&format!("{} {}", body, nops)), Err(1));
+
+		// The code after the `if` is only charged in the then branch.
+		let body = "i64.const 2 call 0 local.get 0 if i64.const 91 call 0 nop else i64.const 2 \
+			call 0 nop nop end";
+		assert_eq!(verify(&format!("{} {}", body, nops)), Err(1));
+
+		// The code after the `if` is only charged in the else branch.
+		let body = "i64.const 2 call 0 local.get 0 if i64.const 1 call 0 nop else i64.const 92 \
+			call 0 nop nop end";
+		assert_eq!(verify(&format!("{} {}", body, nops)), Err(1));
+	}
 }
@@ -0,0 +1,185 @@
+//! Removal of entries from the function and global index spaces.
+//!
+//! Removing an entry shifts the indices of all the entries after it. These helpers remove the
+//! entries together with all exports of them and rewrite every other reference accordingly.
+
+use alloc::vec::Vec;
+use parity_wasm::elements::{self, IndexMap, Instruction};
+
+/// Maps the indices of an index space to the ones after removing the entries `removed`.
+struct IndexMapping<'a> {
+	/// Sorted indices of the removed entries.
+	removed: &'a [u32],
+}
+
+impl IndexMapping<'_> {
+	/// Returns the new index of the entry `idx`, or `None` if it is removed.
+	fn map(&self, idx: u32) -> Option<u32> {
+		match self.removed.binary_search(&idx) {
+			Ok(_) => None,
+			Err(shift) => Some(idx - shift as u32),
+		}
+	}
+
+	/// Rewrite `idx` in place. A removed entry keeps its index.
+	fn apply(&self, idx: &mut u32) {
+		if let Some(new_idx) = self.map(*idx) {
+			*idx = new_idx;
+		}
+	}
+}
+
+/// Remove the functions `removed` from the function index space of `module`.
+///
+/// The functions may be imported or defined. Exports of them are removed as well. The caller must
+/// make sure that they aren't referenced otherwise.
+pub(crate) fn remove_functions(module: &mut elements::Module, removed: &[u32]) {
+	let mut removed = removed.to_vec();
+	removed.sort_unstable();
+	removed.dedup();
+	let mapping = IndexMapping { removed: &removed };
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+
+	for section in module.sections_mut() {
+		match section {
+			elements::Section::Import(import_section) => {
+				let mut func_idx = 0;
+				import_section.entries_mut().retain(|entry| match entry.external() {
+					elements::External::Function(_) => {
+						func_idx += 1;
+						mapping.map(func_idx - 1).is_some()
+					},
+					_ => true,
+				});
+			},
+			elements::Section::Function(function_section) =>
+				retain_defined(function_section.entries_mut(), import_count, &mapping),
+			elements::Section::Code(code_section) => {
+				retain_defined(code_section.bodies_mut(), import_count, &mapping);
+				for body in code_section.bodies_mut() {
+					for instruction in body.code_mut().elements_mut() {
+						if let Instruction::Call(func_idx) = instruction {
+							mapping.apply(func_idx);
+						}
+					}
+				}
+			},
+			elements::Section::Export(export_section) => {
+				export_section.entries_mut().retain(|entry| match entry.internal() {
+					elements::Internal::Function(func_idx) => mapping.map(*func_idx).is_some(),
+					_ => true,
+				});
+				for entry in export_section.entries_mut() {
+					if let elements::Internal::Function(func_idx) = entry.internal_mut() {
+						mapping.apply(func_idx);
+					}
+				}
+			},
+			elements::Section::Element(elements_section) =>
+				for segment in elements_section.entries_mut() {
+					for func_idx in segment.members_mut() {
+						mapping.apply(func_idx);
+					}
+				},
+			elements::Section::Start(start_idx) => mapping.apply(start_idx),
+			elements::Section::Name(name_section) => {
+				if let Some(functions) = name_section.functions_mut() {
+					*functions.names_mut() = remap(functions.names(), &mapping);
+				}
+				if let Some(locals) = name_section.locals_mut() {
+					*locals.local_names_mut() = remap(locals.local_names(), &mapping);
+				}
+			},
+			_ => {},
+		}
+	}
+}
+
+/// Remove the globals `removed` from the global index space of `module`.
+///
+/// The globals may be imported or defined. Exports of them are removed as well. The caller must
+/// make sure that they aren't referenced otherwise.
+pub(crate) fn remove_globals(module: &mut elements::Module, removed: &[u32]) {
+	let mut removed = removed.to_vec();
+	removed.sort_unstable();
+	removed.dedup();
+	let mapping = IndexMapping { removed: &removed };
+	let import_count = module.import_count(elements::ImportCountType::Global) as u32;
+
+	let remap_instructions = |instructions: &mut [Instruction]| {
+		for instruction in instructions {
+			if let Instruction::GetGlobal(global_idx) | Instruction::SetGlobal(global_idx) =
+				instruction
+			{
+				mapping.apply(global_idx);
+			}
+		}
+	};
+
+	for section in module.sections_mut() {
+		match section {
+			elements::Section::Import(import_section) => {
+				let mut global_idx = 0;
+				import_section.entries_mut().retain(|entry| match entry.external() {
+					elements::External::Global(_) => {
+						global_idx += 1;
+						mapping.map(global_idx - 1).is_some()
+					},
+					_ => true,
+				});
+			},
+			elements::Section::Global(global_section) => {
+				retain_defined(global_section.entries_mut(), import_count, &mapping);
+				for global in global_section.entries_mut() {
+					remap_instructions(global.init_expr_mut().code_mut());
+				}
+			},
+			elements::Section::Code(code_section) =>
+				for body in code_section.bodies_mut() {
+					remap_instructions(body.code_mut().elements_mut());
+				},
+			elements::Section::Export(export_section) => {
+				export_section.entries_mut().retain(|entry| match entry.internal() {
+					elements::Internal::Global(global_idx) => mapping.map(*global_idx).is_some(),
+					_ => true,
+				});
+				for entry in export_section.entries_mut() {
+					if let elements::Internal::Global(global_idx) = entry.internal_mut() {
+						mapping.apply(global_idx);
+					}
+				}
+			},
+			elements::Section::Element(elements_section) =>
+				for segment in elements_section.entries_mut() {
+					if let Some(offset) = segment.offset_mut() {
+						remap_instructions(offset.code_mut());
+					}
+				},
+			elements::Section::Data(data_section) =>
+				for segment in data_section.entries_mut() {
+					if let Some(offset) = segment.offset_mut() {
+						remap_instructions(offset.code_mut());
+					}
+				},
+			_ => {},
+		}
+	}
+}
+
+/// Retain the defined entries of an index space that aren't removed. Defined entries come after
+/// the `import_count` imported ones.
+fn retain_defined<T>(entries: &mut Vec<T>, import_count: u32, mapping: &IndexMapping) {
+	let mut idx = import_count;
+	entries.retain(|_| {
+		idx += 1;
+		mapping.map(idx - 1).is_some()
+	});
+}
+
+/// Remap the keys of a name map, dropping the names of removed entries.
+fn remap<T: Clone + 'static>(names: &IndexMap<T>, mapping: &IndexMapping) -> IndexMap<T> {
+	names
+		.iter()
+		.filter_map(|(idx, name)| Some((mapping.map(idx)?, name.clone())))
+		.collect()
+}
@@ -2,10 +2,31 @@
 
 extern crate alloc;
 
+mod debug_line;
+#[cfg(feature = "dwarf")]
+mod dwarf;
 mod export_globals;
 pub mod gas_metering;
-mod stack_limiter;
+mod index_space;
+mod manifest;
+mod offset_map;
+pub mod overhead;
+mod report;
+pub mod stack_limiter;
+#[cfg(test)]
+mod test_utils;
 
+pub use debug_line::rewrite_debug_line;
+#[cfg(feature = "dwarf")]
+pub use dwarf::{rewrite_debug_info, strip_debug_info};
 pub use export_globals::export_mutable_globals;
+pub use manifest::{
+	read_manifest, GasMeteringRecord, Manifest, PassRecord, RepeatedPass, StackLimiterRecord,
+};
+pub use offset_map::OffsetMap;
 pub use parity_wasm;
-pub use stack_limiter::inject as inject_stack_limiter;
+pub use report::{FunctionReport, MeteredBlockReport, Report, ReportFormat};
+pub use stack_limiter::{
+	inject as inject_stack_limiter, ConstantStackCostRules, NativeStackCostRules, StackCostRules,
+	StackLimiterConfig,
+};
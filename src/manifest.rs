@@ -0,0 +1,358 @@
+//! Records the instrumentation passes applied to a module.
+//!
+//! Passes record themselves in a custom section named `wasm-instrument`. Every line of its payload
+//! describes one applied pass: the name of the pass followed by space separated `key=value`
+//! parameters. Spaces, `=`, `%` and line breaks within values are percent-encoded. Use
+//! [`read_manifest`] to parse the section.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use parity_wasm::elements;
+
+/// The name of the custom section recording the applied passes.
+pub(crate) const SECTION_NAME: &str = "wasm-instrument";
+
+/// The name the gas metering pass is recorded with.
+pub(crate) const GAS_METERING: &str = "gas_metering";
+
+/// The name the stack limiter pass is recorded with.
+pub(crate) const STACK_LIMITER: &str = "stack_limiter";
+
+/// How to handle a module that was already instrumented by the same pass.
+///
+/// Passes can only detect modules they were applied to with this option set, as only then they
+/// record themselves in the `wasm-instrument` custom section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatedPass {
+	/// Apply the pass again.
+	Instrument,
+	/// Return the module unchanged.
+	Skip,
+	/// Fail with an error.
+	Error,
+}
+
+/// The instrumentation passes applied to a module, as recorded in its `wasm-instrument` custom
+/// section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+	/// The recorded passes in the order they were applied.
+	pub passes: Vec<PassRecord>,
+}
+
+/// A single recorded instrumentation pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PassRecord {
+	/// The module was instrumented by [`gas_metering::inject`](crate::gas_metering::inject).
+	GasMetering(GasMeteringRecord),
+	/// The module was instrumented by [`inject_stack_limiter`](crate::inject_stack_limiter).
+	StackLimiter(StackLimiterRecord),
+}
+
+/// The record of a gas metering pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasMeteringRecord {
+	/// The version of this crate that applied the pass.
+	pub version: String,
+	/// The name of the backend, see [`Backend::name`](crate::gas_metering::Backend::name).
+	pub backend: String,
+	/// The kind of [`GasMeter`](crate::gas_metering::GasMeter): `external`, `internal` or
+	/// `imported_global`.
+	pub meter: String,
+	/// The module the gas function or global is imported from.
+	pub module: Option<String>,
+	/// The name of the imported gas function.
+	pub function: Option<String>,
+	/// The name of the exported or imported gas global.
+	pub global: Option<String>,
+	/// The module the function called when running out of gas is imported from, see
+	/// [`Backend::out_of_gas_function`](crate::gas_metering::Backend::out_of_gas_function).
+	pub out_of_gas_module: Option<String>,
+	/// The name of the imported function called when running out of gas.
+	pub out_of_gas_function: Option<String>,
+	/// Whether the gas is charged lazily.
+	pub lazy: bool,
+	/// Whether the gas of counted loops is charged before entering them, see
+	/// [`GasMeteringConfig::with_loop_hoisting`](crate::gas_metering::GasMeteringConfig::with_loop_hoisting).
+	pub loop_hoisting: bool,
+	/// The minimal loop depth from which the gas function is inlined, if it is, see
+	/// [`Backend::inline_min_loop_depth`](crate::gas_metering::Backend::inline_min_loop_depth).
+	pub inline_min_loop_depth: Option<u32>,
+	/// The hash of the cost schedule, see
+	/// [`schedule_hash`](crate::gas_metering::schedule_hash).
+	pub schedule_hash: u64,
+}
+
+/// The record of a stack limiter pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackLimiterRecord {
+	/// The version of this crate that applied the pass.
+	pub version: String,
+	/// The stack limit the module was instrumented with.
+	pub stack_limit: u32,
+	/// The index of the global tracking the stack height.
+	pub stack_height_global: u32,
+	/// Pairs of the index of an original function and the index of the thunk generated for it.
+	pub thunks: Vec<(u32, u32)>,
+}
+
+/// Read the manifest recorded by the instrumentation passes applied to `module`.
+///
+/// Passes are only recorded when enabled in their configuration. Returns `None` if the module has
+/// no `wasm-instrument` custom section or it is malformed. Lines of passes unknown to this
+/// version of the crate are skipped.
+pub fn read_manifest(module: &elements::Module) -> Option<Manifest> {
+	let section = module.custom_sections().find(|section| section.name() == SECTION_NAME)?;
+	let payload = core::str::from_utf8(section.payload()).ok()?;
+
+	let mut passes = Vec::new();
+	for line in payload.lines().filter(|line| !line.is_empty()) {
+		let mut tokens = line.split(' ');
+		let pass = tokens.next()?;
+		let params = tokens
+			.map(|token| {
+				let (key, value) = token.split_once('=')?;
+				Some((key, decode(value)?))
+			})
+			.collect::<Option<Vec<_>>>()?;
+		let param = |key: &str| params.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone());
+
+		let record = match pass {
+			GAS_METERING => PassRecord::GasMetering(GasMeteringRecord {
+				version: param("version")?,
+				backend: param("backend")?,
+				meter: param("meter")?,
+				module: param("module"),
+				function: param("function"),
+				global: param("global"),
+				out_of_gas_module: param("out_of_gas_module"),
+				out_of_gas_function: param("out_of_gas_function"),
+				lazy: param("lazy")?.parse().ok()?,
+				loop_hoisting: param("loop_hoisting")?.parse().ok()?,
+				inline_min_loop_depth: match param("inline_min_loop_depth") {
+					Some(depth) => Some(depth.parse().ok()?),
+					None => None,
+				},
+				schedule_hash: u64::from_str_radix(&param("schedule")?, 16).ok()?,
+			}),
+			STACK_LIMITER => PassRecord::StackLimiter(StackLimiterRecord {
+				version: param("version")?,
+				stack_limit: param("stack_limit")?.parse().ok()?,
+				stack_height_global: param("stack_height_global")?.parse().ok()?,
+				thunks: param("thunks")?
+					.split(',')
+					.filter(|pair| !pair.is_empty())
+					.map(|pair| {
+						let (func_idx, thunk_idx) = pair.split_once(':')?;
+						Some((func_idx.parse().ok()?, thunk_idx.parse().ok()?))
+					})
+					.collect::<Option<_>>()?,
+			}),
+			_ => continue,
+		};
+		passes.push(record);
+	}
+
+	Some(Manifest { passes })
+}
+
+/// Returns whether `pass` is recorded in the custom section of `module`.
+pub(crate) fn contains_pass(module: &elements::Module, pass: &str) -> bool {
+	module
+		.custom_sections()
+		.filter(|section| section.name() == SECTION_NAME)
+		.flat_map(|section| section.payload().split(|byte| *byte == b'\n'))
+		.any(|line| line.split(|byte| *byte == b' ').next() == Some(pass.as_bytes()))
+}
+
+/// Record `pass` with its `params` in the custom section of `module`.
+///
+/// The version of this crate is recorded as the first parameter.
+pub(crate) fn record_pass(module: &mut elements::Module, pass: &str, params: &[(&str, &str)]) {
+	let mut line = String::from(pass);
+	let version = ("version", env!("CARGO_PKG_VERSION"));
+	for (key, value) in core::iter::once(&version).chain(params) {
+		line.push(' ');
+		line.push_str(key);
+		line.push('=');
+		encode(value, &mut line);
+	}
+	line.push('\n');
+
+	let mut payload: Vec<u8> = module
+		.custom_sections()
+		.find(|section| section.name() == SECTION_NAME)
+		.map(|section| section.payload().to_vec())
+		.unwrap_or_default();
+	payload.extend_from_slice(line.as_bytes());
+	module.set_custom_section(SECTION_NAME, payload);
+}
+
+/// Remove the last record of `pass` from the custom section of `module`.
+///
+/// The custom section is removed altogether once it records no passes anymore.
+pub(crate) fn remove_pass(module: &mut elements::Module, pass: &str) {
+	let payload = match module.custom_sections().find(|section| section.name() == SECTION_NAME) {
+		Some(section) => section.payload(),
+		None => return,
+	};
+	let mut lines: Vec<&[u8]> =
+		payload.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()).collect();
+	if let Some(pos) = lines
+		.iter()
+		.rposition(|line| line.split(|byte| *byte == b' ').next() == Some(pass.as_bytes()))
+	{
+		lines.remove(pos);
+	}
+
+	if lines.is_empty() {
+		module.clear_custom_section(SECTION_NAME);
+	} else {
+		let payload = lines.iter().flat_map(|line| line.iter().chain(b"\n")).copied().collect();
+		module.set_custom_section(SECTION_NAME, payload);
+	}
+}
+
+/// Set the parameter `key` of the last record of `pass` in the custom section of `module` to
+/// `value`.
+///
+/// Nothing is changed if the pass or the parameter isn't recorded.
+pub(crate) fn set_param(module: &mut elements::Module, pass: &str, key: &str, value: &str) {
+	let payload = match module.custom_sections().find(|section| section.name() == SECTION_NAME) {
+		Some(section) => section.payload(),
+		None => return,
+	};
+	let payload = match core::str::from_utf8(payload) {
+		Ok(payload) => payload,
+		Err(_) => return,
+	};
+	let mut lines: Vec<String> = payload.lines().map(String::from).collect();
+	let line = match lines.iter_mut().rev().find(|line| line.split(' ').next() == Some(pass)) {
+		Some(line) => line,
+		None => return,
+	};
+	*line = line
+		.split(' ')
+		.map(|token| match token.split_once('=') {
+			Some((k, _)) if k == key => {
+				let mut token = String::from(key);
+				token.push('=');
+				encode(value, &mut token);
+				token
+			},
+			_ => String::from(token),
+		})
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	let mut payload = lines.join("\n");
+	payload.push('\n');
+	module.set_custom_section(SECTION_NAME, payload.into_bytes());
+}
+
+/// Percent-encode `value` and append it to `encoded`.
+fn encode(value: &str, encoded: &mut String) {
+	for c in value.chars() {
+		match c {
+			' ' | '=' | '%' | '\n' => {
+				let _ = write!(encoded, "%{:02X}", c as u32);
+			},
+			c => encoded.push(c),
+		}
+	}
+}
+
+/// Decode a percent-encoded value.
+fn decode(value: &str) -> Option<String> {
+	let mut decoded = Vec::with_capacity(value.len());
+	let mut bytes = value.bytes();
+	while let Some(byte) = bytes.next() {
+		if byte == b'%' {
+			let hex = [bytes.next()?, bytes.next()?];
+			decoded.push(u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?);
+		} else {
+			decoded.push(byte);
+		}
+	}
+	String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		gas_metering::{self, lazy, mutable_global, schedule_hash, ConstantCostRules},
+		stack_limiter,
+		test_utils::parse_wat,
+		ConstantStackCostRules, StackLimiterConfig,
+	};
+
+	#[test]
+	fn both_passes() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $f (export "f")
+		i32.const 0
+		drop
+	)
+	(start $f)
+)
+"#,
+		);
+		let rules = ConstantCostRules::new(2, 0, 1);
+		let hash = schedule_hash(&module, &rules);
+		let module = gas_metering::inject_with_config(
+			module.clone(),
+			lazy::Injector::new(mutable_global::Injector::new("gas_left")),
+			&rules,
+			&gas_metering::GasMeteringConfig::new().with_manifest(true),
+		)
+		.unwrap();
+		let module = stack_limiter::inject_with_config(
+			module,
+			&StackLimiterConfig::new(1024).with_manifest(true),
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+
+		assert_ne!(hash, schedule_hash(&module, &ConstantCostRules::default()));
+		assert_eq!(
+			read_manifest(&module).unwrap().passes,
+			vec![
+				PassRecord::GasMetering(GasMeteringRecord {
+					version: env!("CARGO_PKG_VERSION").into(),
+					backend: "mutable_global".into(),
+					meter: "internal".into(),
+					module: None,
+					function: None,
+					global: Some("gas_left".into()),
+					out_of_gas_module: None,
+					out_of_gas_function: None,
+					lazy: true,
+					loop_hoisting: false,
+					inline_min_loop_depth: None,
+					schedule_hash: hash,
+				}),
+				PassRecord::StackLimiter(StackLimiterRecord {
+					version: env!("CARGO_PKG_VERSION").into(),
+					stack_limit: 1024,
+					stack_height_global: 1,
+					thunks: vec![(0, 2)],
+				}),
+			]
+		);
+	}
+
+	#[test]
+	fn malformed_manifest() {
+		let mut module = parse_wat("(module)");
+		assert_eq!(read_manifest(&module), None);
+
+		module.set_custom_section(SECTION_NAME, b"unknown pass=1\n".to_vec());
+		assert_eq!(read_manifest(&module), Some(Manifest { passes: vec![] }));
+
+		module.set_custom_section(SECTION_NAME, b"stack_limiter version=0.4.0\n".to_vec());
+		assert_eq!(read_manifest(&module), None);
+	}
+}
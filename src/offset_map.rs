@@ -0,0 +1,552 @@
+//! Mapping of instrumented instructions back to the original ones.
+//!
+//! The instrumentation passes insert instructions into function bodies, which shifts the position
+//! of every following instruction. An [`OffsetMap`] records for each instruction of an
+//! instrumented function body the position of the original instruction it belongs to. Inserted
+//! instructions belong to the original instruction they precede.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use parity_wasm::elements::{self, Serialize};
+
+/// Maps the instruction positions of the functions of an instrumented module to the positions
+/// in the original module.
+///
+/// Positions are indices into the instructions of a function body, including its final `end`.
+/// Functions are identified by their index in the function index space. Only functions that
+/// existed in the original module are mapped, functions added by the instrumentation aren't.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+	/// Keyed by the index of the function in the instrumented module.
+	functions: BTreeMap<u32, FunctionOffsets>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FunctionOffsets {
+	/// The index of the function in the original module.
+	original_func_idx: u32,
+	/// The original position of the instruction at each position of the instrumented body.
+	positions: Vec<u32>,
+}
+
+impl OffsetMap {
+	/// Returns whether the map contains no functions.
+	pub fn is_empty(&self) -> bool {
+		self.functions.is_empty()
+	}
+
+	/// Returns the index in the original module of the function `func_idx` of the instrumented
+	/// module.
+	pub fn original_function(&self, func_idx: u32) -> Option<u32> {
+		self.functions.get(&func_idx).map(|function| function.original_func_idx)
+	}
+
+	/// Returns the original position of the instruction at `pos` in the body of the function
+	/// `func_idx` of the instrumented module.
+	pub fn original_position(&self, func_idx: u32, pos: u32) -> Option<u32> {
+		self.functions.get(&func_idx)?.positions.get(pos as usize).copied()
+	}
+
+	/// Returns the positions in the instrumented body of the function `func_idx` that belong to
+	/// the original instruction at `original_pos`.
+	///
+	/// The last of them is the original instruction itself, all others were inserted in front of
+	/// it.
+	pub fn instrumented_positions(
+		&self,
+		func_idx: u32,
+		original_pos: u32,
+	) -> Option<core::ops::Range<u32>> {
+		let positions = &self.functions.get(&func_idx)?.positions;
+		let start = positions.partition_point(|pos| *pos < original_pos);
+		let end = positions.partition_point(|pos| *pos <= original_pos);
+		(start < end).then(|| start as u32..end as u32)
+	}
+
+	/// Combine this map with the map of a pass applied afterwards.
+	///
+	/// `next` maps the module produced by the pass this map belongs to to the module produced by
+	/// the later pass. The result maps the latter back to the original module.
+	pub fn chain(&self, next: &OffsetMap) -> OffsetMap {
+		let functions = next
+			.functions
+			.iter()
+			.filter_map(|(func_idx, function)| {
+				let previous = self.functions.get(&function.original_func_idx)?;
+				let positions = function
+					.positions
+					.iter()
+					.map(|pos| previous.positions.get(*pos as usize).copied())
+					.collect::<Option<_>>()?;
+				Some((
+					*func_idx,
+					FunctionOffsets { original_func_idx: previous.original_func_idx, positions },
+				))
+			})
+			.collect();
+		OffsetMap { functions }
+	}
+
+	/// Record that the instructions at each position of the body of the function `func_idx` of
+	/// the instrumented module belong to the original instructions at `positions` of the function
+	/// `original_func_idx`.
+	pub(crate) fn insert(&mut self, func_idx: u32, original_func_idx: u32, positions: Vec<u32>) {
+		self.functions
+			.insert(func_idx, FunctionOffsets { original_func_idx, positions });
+	}
+}
+
+/// The byte offsets of a function body relative to the start of the code section payload.
+struct BodyLayout {
+	/// Offset of the size of the body.
+	start: u64,
+	/// Offsets of the instructions.
+	instructions: Vec<u64>,
+	/// Offset right after the body.
+	end: u64,
+}
+
+/// Compute the byte offsets of all function bodies of `module` as serialized by `parity-wasm`.
+fn code_layout(module: &elements::Module) -> Result<Vec<BodyLayout>, &'static str> {
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let mut offset = serialized_len(elements::VarUint32::from(bodies.len() as u32))?;
+
+	let mut layout = Vec::with_capacity(bodies.len());
+	for body in bodies {
+		let start = offset;
+		let end = start + serialized_len(body.clone())?;
+		let mut instructions = Vec::with_capacity(body.code().elements().len());
+		let mut instruction_offset = 0;
+		for instruction in body.code().elements() {
+			instructions.push(instruction_offset);
+			instruction_offset += serialized_len(instruction.clone())?;
+		}
+		// The instructions are the last part of the body.
+		let code_start = end - instruction_offset;
+		instructions.iter_mut().for_each(|offset| *offset += code_start);
+
+		layout.push(BodyLayout { start, instructions, end });
+		offset = end;
+	}
+	Ok(layout)
+}
+
+fn serialized_len<T: Serialize>(value: T) -> Result<u64, &'static str> {
+	elements::serialize(value)
+		.map(|bytes| bytes.len() as u64)
+		.map_err(|_| "Failed to serialize the code section")
+}
+
+/// Compute the byte offsets of all function bodies in the code section of the wasm `binary`.
+///
+/// Unlike [`code_layout`] the offsets are read from the binary itself, so that they stay
+/// accurate if it encodes integers with more bytes than necessary. Returns the number of imported
+/// functions along with the layout.
+fn binary_code_layout(binary: &[u8]) -> Result<(u32, Vec<BodyLayout>), &'static str> {
+	let mut reader = Reader { data: binary, pos: 0 };
+	if reader.bytes(8)? != b"\0asm\x01\0\0\0" {
+		return Err("The original module isn't a wasm binary")
+	}
+
+	let mut func_imports = 0;
+	let mut layout = Vec::new();
+	while reader.pos < binary.len() {
+		let id = reader.u8()?;
+		let size = reader.leb()? as usize;
+		let payload = reader.bytes(size)?;
+		match id {
+			IMPORT_SECTION => func_imports = count_function_imports(payload)?,
+			CODE_SECTION => layout = bodies_layout(payload)?,
+			_ => {},
+		}
+	}
+	Ok((func_imports, layout))
+}
+
+const IMPORT_SECTION: u8 = 2;
+const CODE_SECTION: u8 = 10;
+
+fn count_function_imports(payload: &[u8]) -> Result<u32, &'static str> {
+	let mut reader = Reader { data: payload, pos: 0 };
+	let mut func_imports = 0;
+	for _ in 0..reader.leb()? {
+		for _ in 0..2 {
+			let len = reader.leb()? as usize;
+			reader.bytes(len)?;
+		}
+		match reader.u8()? {
+			// Function
+			0 => {
+				reader.leb()?;
+				func_imports += 1;
+			},
+			// Table
+			1 => {
+				reader.u8()?;
+				reader.limits()?;
+			},
+			// Memory
+			2 => reader.limits()?,
+			// Global
+			3 => {
+				reader.bytes(2)?;
+			},
+			_ => return Err("Unsupported import in the original module"),
+		}
+	}
+	Ok(func_imports)
+}
+
+fn bodies_layout(payload: &[u8]) -> Result<Vec<BodyLayout>, &'static str> {
+	let mut reader = Reader { data: payload, pos: 0 };
+	let count = reader.leb()?;
+
+	let mut layout = Vec::new();
+	for _ in 0..count {
+		let start = reader.pos as u64;
+		let size = reader.leb()? as usize;
+		let end = reader.pos.checked_add(size).ok_or("Truncated code section")?;
+		if end > payload.len() {
+			return Err("Truncated code section")
+		}
+		for _ in 0..reader.leb()? {
+			reader.leb()?;
+			reader.u8()?;
+		}
+		let mut instructions = Vec::new();
+		while reader.pos < end {
+			instructions.push(reader.pos as u64);
+			reader.instruction()?;
+		}
+		if reader.pos != end {
+			return Err("Malformed function body")
+		}
+		layout.push(BodyLayout { start, instructions, end: end as u64 });
+	}
+	Ok(layout)
+}
+
+/// Reads the parts of a wasm binary needed to locate the instructions.
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+		let bytes = self
+			.pos
+			.checked_add(len)
+			.and_then(|end| self.data.get(self.pos..end))
+			.ok_or("Unexpected end of the original module")?;
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8, &'static str> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	/// Read a signed or unsigned LEB128 integer of up to 64 bits, which is returned unsigned.
+	fn leb(&mut self) -> Result<u64, &'static str> {
+		let mut value = 0;
+		for shift in (0..70).step_by(7) {
+			let byte = self.u8()?;
+			value |= u64::from(byte & 0x7f).checked_shl(shift).unwrap_or(0);
+			if byte & 0x80 == 0 {
+				return Ok(value)
+			}
+		}
+		Err("Invalid LEB128 in the original module")
+	}
+
+	fn limits(&mut self) -> Result<(), &'static str> {
+		let flags = self.u8()?;
+		self.leb()?;
+		if flags & 1 != 0 {
+			self.leb()?;
+		}
+		Ok(())
+	}
+
+	/// Skip a single instruction including its immediates.
+	fn instruction(&mut self) -> Result<(), &'static str> {
+		match self.u8()? {
+			// block, loop, if
+			0x02..=0x04 => match self.data.get(self.pos) {
+				Some(0x40) | Some(0x6f..=0x7f) => {
+					self.u8()?;
+				},
+				_ => {
+					self.leb()?;
+				},
+			},
+			// br_table
+			0x0e =>
+				for _ in 0..=self.leb()? {
+					self.leb()?;
+				},
+			// call_indirect and the memory instructions
+			0x11 | 0x28..=0x3e => {
+				self.leb()?;
+				self.leb()?;
+			},
+			// br, br_if, call, variable instructions, memory.size, memory.grow and the integer
+			// constants
+			0x0c | 0x0d | 0x10 | 0x20..=0x24 | 0x3f..=0x42 => {
+				self.leb()?;
+			},
+			0x43 => {
+				self.bytes(4)?;
+			},
+			0x44 => {
+				self.bytes(8)?;
+			},
+			0xfc => match self.leb()? {
+				// The saturating truncations.
+				0..=7 => {},
+				// data.drop, memory.fill, elem.drop
+				9 | 11 | 13 => {
+					self.leb()?;
+				},
+				// memory.init, memory.copy, table.init, table.copy
+				8 | 10 | 12 | 14 => {
+					self.leb()?;
+					self.leb()?;
+				},
+				_ => return Err("Unsupported instruction in the original module"),
+			},
+			0xfd | 0xfe => return Err("Unsupported instruction in the original module"),
+			_ => {},
+		}
+		Ok(())
+	}
+}
+
+/// Maps the byte offsets within the code section of an original binary to those of the
+/// instrumented module, as used by the addresses of DWARF debug info.
+pub(crate) struct AddressMap {
+	bodies: Vec<BodyMapping>,
+}
+
+struct BodyMapping {
+	original: BodyLayout,
+	instrumented: BodyLayout,
+	/// The positions in the instrumented body of each original instruction.
+	positions: Vec<core::ops::Range<u32>>,
+}
+
+impl AddressMap {
+	/// Create the map between the code sections of the `original` binary and the `instrumented`
+	/// module, whose instructions are mapped back by `offsets`.
+	///
+	/// The offsets of the original instructions are read from the binary. The instrumented module
+	/// is laid out as serialized by `parity-wasm`.
+	pub(crate) fn new(
+		original: &[u8],
+		instrumented: &elements::Module,
+		offsets: &OffsetMap,
+	) -> Result<Self, &'static str> {
+		let (original_imports, original_layout) = binary_code_layout(original)?;
+		let instrumented_imports =
+			instrumented.import_count(elements::ImportCountType::Function) as u32;
+		let mut instrumented_layout: Vec<Option<BodyLayout>> =
+			code_layout(instrumented)?.into_iter().map(Some).collect();
+
+		let mut instrumented_funcs = BTreeMap::new();
+		for (func_idx, function) in &offsets.functions {
+			instrumented_funcs.insert(function.original_func_idx, *func_idx);
+		}
+
+		let mut bodies = Vec::new();
+		for (original_func_idx, original) in (original_imports..).zip(original_layout) {
+			let func_idx = *instrumented_funcs
+				.get(&original_func_idx)
+				.ok_or("The offset map doesn't match the module")?;
+			let instrumented = func_idx
+				.checked_sub(instrumented_imports)
+				.and_then(|idx| instrumented_layout.get_mut(idx as usize))
+				.and_then(Option::take)
+				.ok_or("The offset map doesn't match the module")?;
+			// The final `end` of the instrumented body is the one of the original body.
+			let mapped_positions = &offsets.functions[&func_idx].positions;
+			let positions = (0..original.instructions.len() as u32)
+				.map(|pos| offsets.instrumented_positions(func_idx, pos))
+				.collect::<Option<Vec<_>>>()
+				.filter(|_| {
+					mapped_positions.len() == instrumented.instructions.len() &&
+						mapped_positions.last().map(|pos| *pos as usize + 1) ==
+							Some(original.instructions.len())
+				})
+				.ok_or("The offset map doesn't match the module")?;
+			bodies.push(BodyMapping { original, instrumented, positions });
+		}
+
+		Ok(Self { bodies })
+	}
+
+	/// Map the `address` within the original code section to the instrumented one.
+	///
+	/// An address of an original instruction is mapped to the first instruction inserted in
+	/// front of it. Returns `None` for addresses outside of the function bodies.
+	pub(crate) fn map(&self, address: u64) -> Option<u64> {
+		let idx = self.bodies.partition_point(|body| body.original.start <= address);
+		let body = &self.bodies[idx.checked_sub(1)?];
+		let (original, instrumented) = (&body.original, &body.instrumented);
+		if address >= original.end {
+			// Only the end of the last body is adjacent to no other body.
+			return (address == original.end).then(|| instrumented.end)
+		}
+
+		let pos = original.instructions.partition_point(|offset| *offset <= address);
+		let mapped = match pos.checked_sub(1) {
+			None if address == original.start => instrumented.start,
+			// Within the size or the locals of the body.
+			None => {
+				let first_instruction =
+					original.instructions.first().copied().unwrap_or(original.end);
+				let new_first_instruction =
+					instrumented.instructions.first().copied().unwrap_or(instrumented.end);
+				new_first_instruction
+					.saturating_sub(first_instruction - address)
+					.max(instrumented.start)
+			},
+			Some(pos) => {
+				let offset = address - original.instructions[pos];
+				let positions = &body.positions[pos];
+				if offset == 0 {
+					instrumented.instructions[positions.start as usize]
+				} else {
+					instrumented.instructions[positions.end as usize - 1] + offset
+				}
+			},
+		};
+		Some(mapped)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		gas_metering, stack_limiter, test_utils::parse_wat, ConstantStackCostRules,
+		StackLimiterConfig,
+	};
+
+	/// Check that the last instruction belonging to each original instruction is the original one.
+	///
+	/// Only the kinds of instructions are compared, as passes may shift function indices.
+	fn assert_maps_back(
+		original: &elements::Module,
+		instrumented: &elements::Module,
+		offsets: &OffsetMap,
+	) {
+		let original_imports = original.import_count(elements::ImportCountType::Function) as u32;
+		let instrumented_imports =
+			instrumented.import_count(elements::ImportCountType::Function) as u32;
+		let bodies = |module: &elements::Module| module.code_section().unwrap().bodies().to_vec();
+		let (original_bodies, instrumented_bodies) = (bodies(original), bodies(instrumented));
+
+		for (func_idx, function) in &offsets.functions {
+			let original = original_bodies
+				[(function.original_func_idx - original_imports) as usize]
+				.code()
+				.elements();
+			let instrumented = instrumented_bodies[(func_idx - instrumented_imports) as usize]
+				.code()
+				.elements();
+			assert_eq!(function.positions.len(), instrumented.len());
+			for (pos, instruction) in original.iter().enumerate() {
+				let positions = offsets.instrumented_positions(*func_idx, pos as u32).unwrap();
+				assert_eq!(
+					core::mem::discriminant(&instrumented[positions.end as usize - 1]),
+					core::mem::discriminant(instruction),
+				);
+			}
+		}
+		assert_eq!(offsets.functions.len(), original_bodies.len());
+	}
+
+	#[test]
+	fn maps_back_to_original_instructions() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $f (param i32)))
+	(func $g (export "g") (param i32) (result i32)
+		(call $f (local.get 0))
+		(if (result i32) (local.get 0)
+			(then (call $h (i32.const 1)))
+			(else (i32.const 2))
+		)
+	)
+	(func $h (param i32) (result i32)
+		local.get 0
+	)
+)
+"#,
+		);
+
+		let (metered, gas_offsets) = gas_metering::inject_with_offset_map(
+			module.clone(),
+			gas_metering::host_function::Injector::new("env", "gas"),
+			&gas_metering::ConstantCostRules::default(),
+			&gas_metering::GasMeteringConfig::new(),
+		)
+		.unwrap();
+		assert_maps_back(&module, &metered, &gas_offsets);
+		// The gas function is imported in front of the original functions.
+		assert_eq!(gas_offsets.original_function(2), Some(1));
+		assert_eq!(gas_offsets.original_function(1), None);
+
+		let (limited, stack_offsets) = stack_limiter::inject_with_offset_map(
+			metered.clone(),
+			&StackLimiterConfig::new(1024),
+			&ConstantStackCostRules::default(),
+		)
+		.unwrap();
+		assert_maps_back(&metered, &limited, &stack_offsets);
+		// The thunk of the exported function isn't mapped.
+		assert_eq!(stack_offsets.original_function(4), None);
+
+		assert_maps_back(&module, &limited, &gas_offsets.chain(&stack_offsets));
+	}
+
+	#[test]
+	fn binary_layout_matches_serialization() {
+		let binary = wat::parse_str(
+			r#"
+(module
+	(import "env" "f" (func $f (param i32)))
+	(import "env" "memory" (memory 1 2))
+	(import "env" "g" (global $g i32))
+	(type $t (func (param i32)))
+	(table 1 funcref)
+	(func $h (param i32) (result f64) (local i64 f32)
+		(block $b (result i32)
+			(br_table $b $b (i32.const -1) (local.get 0))
+		)
+		(call_indirect (type $t) (i32.const 0))
+		(i64.store offset=8 (i32.const 0) (i64.const 1234567))
+		(drop (memory.grow (memory.size)))
+		(drop (f32.const 1.5))
+		(drop (global.get $g))
+		(f64.const 2.5)
+	)
+)
+"#,
+		)
+		.unwrap();
+		let module: elements::Module = elements::deserialize_buffer(&binary).unwrap();
+
+		let (func_imports, layout) = binary_code_layout(&binary).unwrap();
+		let expected = code_layout(&module).unwrap();
+		assert_eq!(func_imports, 1);
+		assert_eq!(layout.len(), expected.len());
+		for (body, expected) in layout.iter().zip(&expected) {
+			assert_eq!(
+				(body.start, &body.instructions, body.end),
+				(expected.start, &expected.instructions, expected.end)
+			);
+		}
+	}
+}
@@ -0,0 +1,271 @@
+//! Measure the overhead of instrumenting a module.
+//!
+//! [`measure`] applies a number of [`OverheadConfig`]s to a module and reports how much each of
+//! them grows it. This allows a build pipeline to reject modules whose instrumentation exceeds a
+//! budget.
+
+use crate::{
+	gas_metering::{self, Backend, GasMeteringConfig, InjectError, Rules},
+	stack_limiter::{self, StackCostRules, StackLimiterConfig},
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use parity_wasm::elements;
+
+type Pass = Box<dyn FnOnce(elements::Module) -> Result<elements::Module, &'static str>>;
+
+/// A named sequence of instrumentation passes whose overhead is measured by [`measure`].
+///
+/// The passes are applied in the order they were added.
+pub struct OverheadConfig {
+	name: String,
+	passes: Vec<Pass>,
+}
+
+impl OverheadConfig {
+	/// Create a new [`OverheadConfig`] without any passes. The `name` identifies its
+	/// [`Overhead`] in the report.
+	pub fn new(name: impl Into<String>) -> Self {
+		Self { name: name.into(), passes: Vec::new() }
+	}
+
+	/// Add a gas metering pass, see [`gas_metering::inject_with_config`].
+	pub fn gas_metering<B, R>(mut self, backend: B, rules: R, config: GasMeteringConfig) -> Self
+	where
+		B: Backend + 'static,
+		R: Rules + 'static,
+	{
+		self.passes.push(Box::new(move |module| {
+			gas_metering::inject_with_config(module, backend, &rules, &config).map_err(|err| {
+				match err {
+					InjectError::ForbiddenInstruction(_) =>
+						"The module contains an instruction forbidden by the gas rules",
+					InjectError::AlreadyMetered(_) => "The module is already metered",
+					InjectError::DuplicateExport(_) => "The module already exports the gas global",
+				}
+			})
+		}));
+		self
+	}
+
+	/// Add a stack limiter pass, see [`stack_limiter::inject_with_config`].
+	pub fn stack_limiter<R>(mut self, config: StackLimiterConfig, rules: R) -> Self
+	where
+		R: StackCostRules + 'static,
+	{
+		self.passes.push(Box::new(move |module| {
+			stack_limiter::inject_with_config(module, &config, &rules)
+		}));
+		self
+	}
+}
+
+/// The overhead of instrumenting a module with a number of [`OverheadConfig`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverheadReport {
+	/// The size of the serialized original module in bytes.
+	pub size_before: usize,
+	/// The overhead of every config in the order they were passed to [`measure`].
+	pub overheads: Vec<Overhead>,
+}
+
+impl OverheadReport {
+	/// Returns the overhead of the config named `name`.
+	pub fn get(&self, name: &str) -> Option<&Overhead> {
+		self.overheads.iter().find(|overhead| overhead.name == name)
+	}
+
+	/// Returns the size of the module instrumented as given by `overhead` in percent of the
+	/// size of the original module.
+	pub fn size_percent(&self, overhead: &Overhead) -> usize {
+		overhead.size_after * 100 / self.size_before.max(1)
+	}
+}
+
+/// The overhead of instrumenting a module with a single [`OverheadConfig`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overhead {
+	/// The name of the config.
+	pub name: String,
+	/// The size of the serialized instrumented module in bytes.
+	pub size_after: usize,
+	/// The number of instructions added to the function bodies, including those of the added
+	/// functions.
+	pub injected_instructions: usize,
+	/// The number of functions added to the function index space.
+	pub added_functions: usize,
+	/// The number of globals added to the global index space.
+	pub added_globals: usize,
+	/// The number of added function types.
+	pub added_types: usize,
+}
+
+/// Instrument `module` with each of the `configs` and report the resulting overheads.
+///
+/// Fails if any of the passes fails or a module can't be serialized.
+pub fn measure(
+	module: &elements::Module,
+	configs: impl IntoIterator<Item = OverheadConfig>,
+) -> Result<OverheadReport, &'static str> {
+	let before = Counts::new(module);
+	let size_before = serialized_size(module)?;
+
+	let mut overheads = Vec::new();
+	for config in configs {
+		let mut instrumented = module.clone();
+		for pass in config.passes {
+			instrumented = pass(instrumented)?;
+		}
+		let after = Counts::new(&instrumented);
+		overheads.push(Overhead {
+			name: config.name,
+			size_after: serialized_size(&instrumented)?,
+			injected_instructions: after.instructions.saturating_sub(before.instructions),
+			added_functions: after.functions.saturating_sub(before.functions),
+			added_globals: after.globals.saturating_sub(before.globals),
+			added_types: after.types.saturating_sub(before.types),
+		});
+	}
+
+	Ok(OverheadReport { size_before, overheads })
+}
+
+/// The numbers of entities of a module the instrumentation adds to.
+struct Counts {
+	instructions: usize,
+	functions: usize,
+	globals: usize,
+	types: usize,
+}
+
+impl Counts {
+	fn new(module: &elements::Module) -> Self {
+		Self {
+			instructions: module.code_section().map_or(0, |code| {
+				code.bodies().iter().map(|body| body.code().elements().len()).sum()
+			}),
+			functions: module.functions_space(),
+			globals: module.globals_space(),
+			types: module.type_section().map_or(0, |types| types.types().len()),
+		}
+	}
+}
+
+fn serialized_size(module: &elements::Module) -> Result<usize, &'static str> {
+	elements::serialize(module.clone())
+		.map(|bytes| bytes.len())
+		.map_err(|_| "Failed to serialize the module")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		gas_metering::{host_function, mutable_global, ConstantCostRules},
+		test_utils::parse_wat,
+		ConstantStackCostRules,
+	};
+
+	#[test]
+	fn measure_overheads() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $f (export "f") (param i32) (result i32)
+		(if (result i32) (local.get 0)
+			(then (i32.const 1))
+			(else (call $f (i32.const 0)))
+		)
+	)
+)
+"#,
+		);
+		let report = measure(
+			&module,
+			[
+				OverheadConfig::new("none"),
+				OverheadConfig::new("host_function").gas_metering(
+					host_function::Injector::new("env", "gas"),
+					ConstantCostRules::default(),
+					GasMeteringConfig::new(),
+				),
+				OverheadConfig::new("mutable_global").gas_metering(
+					mutable_global::Injector::new("gas_left"),
+					ConstantCostRules::default(),
+					GasMeteringConfig::new(),
+				),
+				OverheadConfig::new("stack_limiter").stack_limiter(
+					StackLimiterConfig::new(1024),
+					ConstantStackCostRules::default(),
+				),
+			],
+		)
+		.unwrap();
+
+		assert_eq!(report.size_before, elements::serialize(module).unwrap().len());
+		let names: Vec<_> = report.overheads.iter().map(|overhead| &*overhead.name).collect();
+		assert_eq!(names, ["none", "host_function", "mutable_global", "stack_limiter"]);
+
+		let none = report.get("none").unwrap();
+		assert_eq!(none.size_after, report.size_before);
+		assert_eq!(report.size_percent(none), 100);
+		assert_eq!(
+			(
+				none.injected_instructions,
+				none.added_functions,
+				none.added_globals,
+				none.added_types
+			),
+			(0, 0, 0, 0)
+		);
+
+		// The gas function is imported and each of the three metered blocks is charged by a
+		// constant and a call.
+		let host_function = report.get("host_function").unwrap();
+		assert_eq!(host_function.injected_instructions, 6);
+		assert_eq!(
+			(host_function.added_functions, host_function.added_globals, host_function.added_types),
+			(1, 0, 1)
+		);
+		assert!(report.size_percent(host_function) > 100);
+
+		// The local gas function is added along with the global it decreases.
+		let mutable_global = report.get("mutable_global").unwrap();
+		assert!(mutable_global.injected_instructions > 6);
+		assert_eq!((mutable_global.added_functions, mutable_global.added_globals), (1, 1));
+
+		// The thunk of the exported function is added along with the stack height global.
+		let stack_limiter = report.get("stack_limiter").unwrap();
+		assert_eq!((stack_limiter.added_functions, stack_limiter.added_globals), (1, 1));
+		assert_eq!(stack_limiter.added_types, 0);
+	}
+
+	#[test]
+	fn failing_pass() {
+		struct Forbidden;
+
+		impl Rules for Forbidden {
+			fn instruction_cost(&self, _: &elements::Instruction) -> Option<u32> {
+				None
+			}
+
+			fn memory_grow_cost(&self) -> gas_metering::MemoryGrowCost {
+				gas_metering::MemoryGrowCost::Free
+			}
+
+			fn call_per_local_cost(&self) -> u32 {
+				0
+			}
+		}
+
+		let module = parse_wat("(module (func (drop (i32.const 0))))");
+		let config = OverheadConfig::new("forbidden").gas_metering(
+			host_function::Injector::new("env", "gas"),
+			Forbidden,
+			GasMeteringConfig::new(),
+		);
+		assert_eq!(
+			measure(&module, [config]),
+			Err("The module contains an instruction forbidden by the gas rules")
+		);
+	}
+}
@@ -0,0 +1,417 @@
+//! Reports of the numbers the instrumentation passes use.
+//!
+//! A [`Report`] lists for every defined function its stack cost as computed by the stack limiter,
+//! whether the stack limiter generates a thunk for it and the metered blocks the gas metering
+//! charges. It can be rendered for humans, as JSON or as CSV.
+
+use crate::{
+	gas_metering::{self, Rules},
+	stack_limiter::{self, StackCostRules, StackLimiterConfig},
+};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use parity_wasm::elements;
+
+/// The numbers the instrumentation passes use for the defined functions of a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+	/// The reports of the defined functions in the order of the function index space.
+	pub functions: Vec<FunctionReport>,
+}
+
+/// The numbers the instrumentation passes use for a single function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionReport {
+	/// The index of the function in the function index space.
+	pub func_idx: u32,
+	/// The name of the function from the name section or else the name of one of its exports.
+	pub name: Option<String>,
+	/// The summed up cost of the locals.
+	pub locals_cost: u32,
+	/// The maximal height of the value stack, which includes the activation frame.
+	pub max_stack_height: u32,
+	/// The cost of the activation frame, see [`StackCostRules::frame_cost`].
+	pub activation_frame_cost: u32,
+	/// The stack cost charged for calls of the function.
+	///
+	/// With [`StackLimiterConfig::with_call_graph_analysis`] this is the cumulative cost of the
+	/// function and all functions it may call, if the analysis is able to compute it.
+	pub stack_cost: u32,
+	/// Whether the stack limiter generates a thunk for the function.
+	pub thunk: bool,
+	/// The blocks charged by the gas metering.
+	pub metered_blocks: Vec<MeteredBlockReport>,
+}
+
+/// A block of instructions charged at once by the gas metering.
+///
+/// The costs of the local gas function of some backends are charged in addition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeteredBlockReport {
+	/// The position of the instruction the charge is inserted in front of.
+	pub start_pos: usize,
+	/// The cost of all instructions of the block.
+	pub cost: u64,
+}
+
+/// The formats a [`Report`] can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+	/// A plain text listing meant to be read by humans.
+	Human,
+	/// A JSON object with a `functions` array.
+	Json,
+	/// A CSV table with a row per metered block. Functions without metered blocks get a single
+	/// row with empty block columns.
+	Csv,
+}
+
+impl Report {
+	/// Compute the report of `module` given the gas and stack cost rules and the stack limiter
+	/// configuration, which decides about the stack costs charged and the generation of thunks.
+	///
+	/// Fails if a function contains an instruction forbidden by `gas_rules` or its stack cost
+	/// can't be computed.
+	pub fn new<R: Rules, S: StackCostRules>(
+		module: &elements::Module,
+		gas_rules: &R,
+		stack_rules: &S,
+		stack_config: &StackLimiterConfig,
+	) -> Result<Self, &'static str> {
+		let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
+		let metered_blocks = gas_metering::metered_blocks(module, gas_rules)
+			.map_err(|_| "The module contains an instruction forbidden by the gas rules")?;
+
+		let mut stack_costs = Vec::with_capacity(module.functions_space());
+		stack_costs.resize(func_imports as usize, 0);
+		let mut functions = Vec::with_capacity(metered_blocks.len());
+		for (func_idx, blocks) in (func_imports..).zip(metered_blocks) {
+			let (locals_cost, max_stack_height) =
+				stack_limiter::compute_stack_cost_parts(func_idx, module, stack_rules)?;
+			let stack_cost = stack_rules
+				.function_cost(locals_cost, max_stack_height)
+				.ok_or("Overflow in adding locals_cost and max_stack_height")?;
+			stack_costs.push(stack_cost);
+			functions.push(FunctionReport {
+				func_idx,
+				name: function_name(module, func_idx),
+				locals_cost,
+				max_stack_height,
+				activation_frame_cost: stack_rules.frame_cost(),
+				stack_cost,
+				thunk: false,
+				metered_blocks: blocks
+					.into_iter()
+					.map(|(start_pos, cost)| MeteredBlockReport { start_pos, cost })
+					.collect(),
+			});
+		}
+
+		stack_limiter::apply_call_graph_analysis(module, stack_config, &mut stack_costs);
+		for function in &mut functions {
+			function.stack_cost = stack_costs[function.func_idx as usize];
+		}
+
+		for func_idx in stack_limiter::thunked_functions(module, stack_config, &stack_costs) {
+			if let Some(function) = func_idx
+				.checked_sub(func_imports)
+				.and_then(|idx| functions.get_mut(idx as usize))
+			{
+				function.thunk = true;
+			}
+		}
+
+		Ok(Self { functions })
+	}
+
+	/// Render the report in the given `format`.
+	pub fn render(&self, format: ReportFormat) -> String {
+		let mut output = String::new();
+		// Writing to a `String` can't fail.
+		let _ = match format {
+			ReportFormat::Human => self.render_human(&mut output),
+			ReportFormat::Json => self.render_json(&mut output),
+			ReportFormat::Csv => self.render_csv(&mut output),
+		};
+		output
+	}
+
+	fn render_human(&self, output: &mut String) -> core::fmt::Result {
+		for function in &self.functions {
+			write!(output, "function {}", function.func_idx)?;
+			if let Some(name) = &function.name {
+				write!(output, " ({})", name)?;
+			}
+			writeln!(output)?;
+			writeln!(
+				output,
+				"  stack cost: {} (locals: {}, max height: {}, activation frame: {})",
+				function.stack_cost,
+				function.locals_cost,
+				function.max_stack_height,
+				function.activation_frame_cost,
+			)?;
+			writeln!(output, "  thunk: {}", if function.thunk { "yes" } else { "no" })?;
+			writeln!(output, "  metered blocks:")?;
+			for block in &function.metered_blocks {
+				writeln!(output, "    at {}: {}", block.start_pos, block.cost)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn render_json(&self, output: &mut String) -> core::fmt::Result {
+		output.push_str("{\"functions\":[");
+		for (i, function) in self.functions.iter().enumerate() {
+			if i > 0 {
+				output.push(',');
+			}
+			write!(output, "{{\"func_idx\":{},\"name\":", function.func_idx)?;
+			match &function.name {
+				Some(name) => write_json_string(output, name)?,
+				None => output.push_str("null"),
+			}
+			write!(
+				output,
+				",\"locals_cost\":{},\"max_stack_height\":{},\"activation_frame_cost\":{},\
+				 \"stack_cost\":{},\"thunk\":{},\"metered_blocks\":[",
+				function.locals_cost,
+				function.max_stack_height,
+				function.activation_frame_cost,
+				function.stack_cost,
+				function.thunk,
+			)?;
+			for (i, block) in function.metered_blocks.iter().enumerate() {
+				if i > 0 {
+					output.push(',');
+				}
+				write!(output, "{{\"start_pos\":{},\"cost\":{}}}", block.start_pos, block.cost)?;
+			}
+			output.push_str("]}");
+		}
+		output.push_str("]}\n");
+		Ok(())
+	}
+
+	fn render_csv(&self, output: &mut String) -> core::fmt::Result {
+		writeln!(
+			output,
+			"func_idx,name,locals_cost,max_stack_height,activation_frame_cost,stack_cost,thunk,\
+			 block_start_pos,block_cost",
+		)?;
+		for function in &self.functions {
+			// Functions without metered blocks still get a row.
+			let no_blocks = function.metered_blocks.is_empty().then(|| None);
+			for block in function.metered_blocks.iter().map(Some).chain(no_blocks) {
+				write!(output, "{},", function.func_idx)?;
+				if let Some(name) = &function.name {
+					write_csv_field(output, name);
+				}
+				write!(
+					output,
+					",{},{},{},{},{},",
+					function.locals_cost,
+					function.max_stack_height,
+					function.activation_frame_cost,
+					function.stack_cost,
+					function.thunk,
+				)?;
+				match block {
+					Some(block) => writeln!(output, "{},{}", block.start_pos, block.cost)?,
+					None => writeln!(output, ",")?,
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Returns the name of the function `func_idx` from the name section or else one of its exports.
+fn function_name(module: &elements::Module, func_idx: u32) -> Option<String> {
+	let from_names = module
+		.names_section()
+		.and_then(|names| names.functions())
+		.and_then(|functions| functions.names().get(func_idx))
+		.cloned();
+	from_names.or_else(|| {
+		module
+			.export_section()?
+			.entries()
+			.iter()
+			.find(|entry| *entry.internal() == elements::Internal::Function(func_idx))
+			.map(|entry| entry.field().into())
+	})
+}
+
+fn write_json_string(output: &mut String, value: &str) -> core::fmt::Result {
+	output.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => output.push_str("\\\""),
+			'\\' => output.push_str("\\\\"),
+			c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32)?,
+			c => output.push(c),
+		}
+	}
+	output.push('"');
+	Ok(())
+}
+
+/// Append `value` as CSV field, quoted if necessary.
+fn write_csv_field(output: &mut String, value: &str) {
+	if value.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+		output.push('"');
+		output.push_str(&value.replace('"', "\"\""));
+		output.push('"');
+	} else {
+		output.push_str(value);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{gas_metering::ConstantCostRules, test_utils::parse_wat, ConstantStackCostRules};
+
+	fn report() -> Report {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $f))
+	(func $g (export "g,\"x\"") (param i32) (result i32) (local i64)
+		(call $f)
+		(if (result i32) (local.get 0)
+			(then (i32.const 1))
+			(else (i32.const 2))
+		)
+	)
+	(func $h
+		call $f
+	)
+)
+"#,
+		);
+		Report::new(
+			&module,
+			&ConstantCostRules::new(2, 0, 1),
+			&ConstantStackCostRules::default(),
+			&StackLimiterConfig::new(1024),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn function_reports() {
+		let report = report();
+		assert_eq!(
+			report.functions,
+			vec![
+				FunctionReport {
+					func_idx: 1,
+					name: Some("g,\"x\"".into()),
+					locals_cost: 1,
+					max_stack_height: 4,
+					activation_frame_cost: 2,
+					stack_cost: 5,
+					thunk: true,
+					metered_blocks: vec![
+						MeteredBlockReport { start_pos: 0, cost: 7 },
+						MeteredBlockReport { start_pos: 3, cost: 2 },
+						MeteredBlockReport { start_pos: 5, cost: 2 },
+					],
+				},
+				FunctionReport {
+					func_idx: 2,
+					name: None,
+					locals_cost: 0,
+					max_stack_height: 2,
+					activation_frame_cost: 2,
+					stack_cost: 2,
+					thunk: false,
+					metered_blocks: vec![MeteredBlockReport { start_pos: 0, cost: 2 }],
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn cumulative_stack_costs() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $f (export "f")
+		call $g
+	)
+	(func $g (local i64)
+		nop
+	)
+)
+"#,
+		);
+		let report = |config: &StackLimiterConfig| {
+			Report::new(
+				&module,
+				&ConstantCostRules::default(),
+				&ConstantStackCostRules::default(),
+				config,
+			)
+			.unwrap()
+			.functions
+			.iter()
+			.map(|function| function.stack_cost)
+			.collect::<Vec<_>>()
+		};
+
+		let costs = report(&StackLimiterConfig::new(1024));
+		let cumulative = report(&StackLimiterConfig::new(1024).with_call_graph_analysis(true));
+		assert_eq!(cumulative, vec![costs[0] + costs[1], costs[1]]);
+	}
+
+	#[test]
+	fn render() {
+		let report = report();
+		assert_eq!(
+			report.render(ReportFormat::Human),
+			"function 1 (g,\"x\")
+  stack cost: 5 (locals: 1, max height: 4, activation frame: 2)
+  thunk: yes
+  metered blocks:
+    at 0: 7
+    at 3: 2
+    at 5: 2
+function 2
+  stack cost: 2 (locals: 0, max height: 2, activation frame: 2)
+  thunk: no
+  metered blocks:
+    at 0: 2
+"
+		);
+		assert_eq!(
+			report.render(ReportFormat::Csv),
+			"func_idx,name,locals_cost,max_stack_height,activation_frame_cost,stack_cost,thunk,\
+			 block_start_pos,block_cost
+1,\"g,\"\"x\"\"\",1,4,2,5,true,0,7
+1,\"g,\"\"x\"\"\",1,4,2,5,true,3,2
+1,\"g,\"\"x\"\"\",1,4,2,5,true,5,2
+2,,0,2,2,2,false,0,2
+"
+		);
+		assert_eq!(
+			report.render(ReportFormat::Json),
+			"{\"functions\":[\
+			 {\"func_idx\":1,\"name\":\"g,\\\"x\\\"\",\"locals_cost\":1,\"max_stack_height\":4,\
+			 \"activation_frame_cost\":2,\"stack_cost\":5,\"thunk\":true,\"metered_blocks\":[\
+			 {\"start_pos\":0,\"cost\":7},{\"start_pos\":3,\"cost\":2},{\"start_pos\":5,\"cost\":2}]},\
+			 {\"func_idx\":2,\"name\":null,\"locals_cost\":0,\"max_stack_height\":2,\
+			 \"activation_frame_cost\":2,\"stack_cost\":2,\"thunk\":false,\"metered_blocks\":[\
+			 {\"start_pos\":0,\"cost\":2}]}]}\n"
+		);
+	}
+
+	#[test]
+	fn render_csv_without_metered_blocks() {
+		let mut report = report();
+		report.functions[1].metered_blocks.clear();
+		assert_eq!(report.render(ReportFormat::Csv).lines().last(), Some("2,,0,2,2,2,false,,"));
+	}
+}
@@ -0,0 +1,197 @@
+//! Static analysis of the call graph of a module.
+//!
+//! This is used to find functions whose worst-case stack usage, including all the functions they
+//! transitively call, can be determined statically. These are all functions which can't reach a
+//! recursive function through direct calls.
+
+use alloc::{vec, vec::Vec};
+use core::cmp::{max, min};
+use parity_wasm::elements::{self, Instruction};
+
+/// The direct call graph of a module.
+///
+/// Indirect calls aren't part of the graph. Table entries are routed through thunks which
+/// account for the stack costs of indirect calls on their own.
+struct CallGraph {
+	/// Direct callees of every function in the function index space. Imported functions don't
+	/// have any callees.
+	callees: Vec<Vec<u32>>,
+}
+
+impl CallGraph {
+	fn new(module: &elements::Module) -> Self {
+		let func_imports = module.import_count(elements::ImportCountType::Function);
+		let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+
+		let mut callees = vec![Vec::new(); func_imports];
+		callees.extend(bodies.iter().map(|body| {
+			let mut callees: Vec<u32> = body
+				.code()
+				.elements()
+				.iter()
+				.filter_map(|instruction| match instruction {
+					Instruction::Call(callee) => Some(*callee),
+					_ => None,
+				})
+				.collect();
+			callees.sort_unstable();
+			callees.dedup();
+			callees
+		}));
+
+		CallGraph { callees }
+	}
+
+	/// Returns the strongly connected components of the graph.
+	///
+	/// The components are returned in reverse topological order, i.e. every component comes
+	/// after all components that are reachable from it. This is Tarjan's algorithm with an
+	/// explicit stack, so that deep call chains don't overflow the native stack.
+	fn strongly_connected_components(&self) -> Vec<Vec<u32>> {
+		let len = self.callees.len();
+		let mut next_index = 0;
+		let mut indices: Vec<Option<u32>> = vec![None; len];
+		let mut low_links = vec![0; len];
+		let mut on_stack = vec![false; len];
+		let mut stack = Vec::new();
+		let mut components = Vec::new();
+
+		for root in 0..len as u32 {
+			if indices[root as usize].is_some() {
+				continue
+			}
+
+			// Pairs of a node and the position of the next callee to visit.
+			let mut call_stack = vec![(root, 0)];
+			indices[root as usize] = Some(next_index);
+			low_links[root as usize] = next_index;
+			next_index += 1;
+			stack.push(root);
+			on_stack[root as usize] = true;
+
+			while let Some((node, next_callee)) = call_stack.last_mut() {
+				let node = *node as usize;
+				if let Some(&callee) = self.callees[node].get(*next_callee) {
+					*next_callee += 1;
+					match indices.get(callee as usize).copied() {
+						Some(None) => {
+							indices[callee as usize] = Some(next_index);
+							low_links[callee as usize] = next_index;
+							next_index += 1;
+							stack.push(callee);
+							on_stack[callee as usize] = true;
+							call_stack.push((callee, 0));
+						},
+						Some(Some(callee_index)) if on_stack[callee as usize] => {
+							low_links[node] = min(low_links[node], callee_index);
+						},
+						// Either already part of a finished component or an invalid index.
+						_ => {},
+					}
+					continue
+				}
+
+				call_stack.pop();
+				if let Some((caller, _)) = call_stack.last() {
+					let caller = *caller as usize;
+					low_links[caller] = min(low_links[caller], low_links[node]);
+				}
+
+				if Some(low_links[node]) == indices[node] {
+					let mut component = Vec::new();
+					loop {
+						let member = stack.pop().expect("node itself is on the stack; qed");
+						on_stack[member as usize] = false;
+						component.push(member);
+						if member as usize == node {
+							break
+						}
+					}
+					components.push(component);
+				}
+			}
+		}
+
+		components
+	}
+}
+
+/// Calculate the cumulative stack costs of all functions given their own `stack_costs`.
+///
+/// The cumulative stack cost of a function is its own stack cost plus the maximal cumulative stack
+/// cost of all the functions it calls directly. It is the worst-case stack usage of a call to
+/// that function. It only exists for functions which can't reach a recursive function through
+/// direct calls. For all other functions `None` is returned, as well as for functions whose
+/// cumulative stack cost overflows.
+pub fn cumulative_stack_costs(module: &elements::Module, stack_costs: &[u32]) -> Vec<Option<u32>> {
+	let graph = CallGraph::new(module);
+	let mut cumulative_costs: Vec<Option<u32>> = vec![None; graph.callees.len()];
+
+	// Every component is visited after all the components it can reach.
+	for component in graph.strongly_connected_components() {
+		let func_idx = component[0] as usize;
+		let is_recursive = component.len() > 1 || graph.callees[func_idx].contains(&component[0]);
+		if is_recursive {
+			continue
+		}
+
+		let own_cost = match stack_costs.get(func_idx) {
+			Some(own_cost) => *own_cost,
+			None => continue,
+		};
+		let max_callee_cost = graph.callees[func_idx].iter().try_fold(0, |max_cost, callee| {
+			let callee_cost = cumulative_costs.get(*callee as usize).copied().flatten()?;
+			Some(max(max_cost, callee_cost))
+		});
+		cumulative_costs[func_idx] =
+			max_callee_cost.and_then(|max_callee_cost| own_cost.checked_add(max_callee_cost));
+	}
+
+	cumulative_costs
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::parse_wat;
+
+	#[test]
+	fn cumulative_costs() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "host" (func $host))
+	(func $leaf)
+	(func $middle
+		call $leaf
+		call $host
+	)
+	(func $root
+		call $middle
+		call $leaf
+	)
+	(func $recursive
+		call $leaf
+		call $recursive
+	)
+	(func $mutual_a
+		call $mutual_b
+	)
+	(func $mutual_b
+		call $mutual_a
+	)
+	(func $calls_recursive
+		call $leaf
+		call $mutual_b
+	)
+)
+"#,
+		);
+
+		let costs = [0, 1, 10, 100, 1000, 10000, 100000, 1000000];
+		assert_eq!(
+			cumulative_stack_costs(&module, &costs),
+			vec![Some(0), Some(1), Some(11), Some(111), None, None, None, None]
+		);
+	}
+}
@@ -1,16 +1,10 @@
-use super::resolve_func_type;
+use super::{resolve_func_type, StackCostRules};
 use alloc::vec::Vec;
-use parity_wasm::elements::{self, BlockType, Type};
+use parity_wasm::elements::{self, BlockType, External, Type, ValueType};
 
 #[cfg(feature = "sign_ext")]
 use parity_wasm::elements::SignExtInstruction;
 
-// The cost in stack items that should be charged per call of a function. This is
-// is a static cost that is added to each function call. This makes sense because even
-// if a function does not use any parameters or locals some stack space on the host
-// machine might be consumed to hold some context.
-const ACTIVATION_FRAME_COST: u32 = 2;
-
 /// Control stack frame.
 #[derive(Debug)]
 struct Frame {
@@ -18,31 +12,36 @@ struct Frame {
 	/// never passes control further was executed.
 	is_polymorphic: bool,
 
-	/// Count of values which will be pushed after the exit
+	/// Type of the value which will be pushed after the exit
 	/// from the current block.
-	end_arity: u32,
+	end_type: Option<ValueType>,
 
 	/// Count of values which should be poped upon a branch to
 	/// this frame.
 	///
-	/// This might be diffirent from `end_arity` since branch
+	/// This might be diffirent from the end arity since branch
 	/// to the loop header can't take any values.
 	branch_arity: u32,
 
-	/// Stack height before entering in the block.
-	start_height: u32,
+	/// Count of values on the value stack before entering in the block.
+	start_len: usize,
 }
 
 /// This is a compound stack that abstracts tracking height of the value stack
 /// and manipulation of the control stack.
-struct Stack {
+///
+/// The height of the value stack is the sum of the costs of all values it holds plus the cost
+/// of the activation frame, as specified by the [`StackCostRules`].
+struct Stack<'a, R> {
+	rules: &'a R,
 	height: u32,
+	values: Vec<ValueType>,
 	control_stack: Vec<Frame>,
 }
 
-impl Stack {
-	fn new() -> Stack {
-		Stack { height: ACTIVATION_FRAME_COST, control_stack: Vec::new() }
+impl<'a, R: StackCostRules> Stack<'a, R> {
+	fn new(rules: &'a R) -> Self {
+		Stack { rules, height: rules.frame_cost(), values: Vec::new(), control_stack: Vec::new() }
 	}
 
 	/// Returns current height of the value stack.
@@ -80,50 +79,136 @@ impl Stack {
 		self.control_stack.pop().ok_or("stack must be non-empty")
 	}
 
-	/// Truncate the height of value stack to the specified height.
-	fn trunc(&mut self, new_height: u32) {
-		self.height = new_height;
+	/// Truncate the value stack to the specified count of values.
+	fn trunc(&mut self, new_len: usize) {
+		while self.values.len() > new_len {
+			let value_type = self.values.pop().expect("length is greater than new_len; qed");
+			// The height was increased by the same cost when the value was pushed.
+			self.height -= self.rules.value_cost(value_type);
+		}
 	}
 
-	/// Push specified number of values into the value stack.
+	/// Push a value of the specified type into the value stack.
 	///
-	/// Returns `Err` if the height overflow usize value.
-	fn push_values(&mut self, value_count: u32) -> Result<(), &'static str> {
-		self.height = self.height.checked_add(value_count).ok_or("stack overflow")?;
+	/// Returns `Err` if the height overflow u32 value.
+	fn push_value(&mut self, value_type: ValueType) -> Result<(), &'static str> {
+		self.height = self
+			.height
+			.checked_add(self.rules.value_cost(value_type))
+			.ok_or("stack overflow")?;
+		self.values.push(value_type);
 		Ok(())
 	}
 
+	/// Push values of the specified types into the value stack.
+	fn push_values(&mut self, value_types: &[ValueType]) -> Result<(), &'static str> {
+		value_types.iter().try_for_each(|value_type| self.push_value(*value_type))
+	}
+
+	/// Pop a single value from the value stack.
+	///
+	/// Returns `None` if the value was popped from the polymorphic part of the stack and
+	/// thus its type is unknown.
+	fn pop_value(&mut self) -> Result<Option<ValueType>, &'static str> {
+		let top_frame = self.frame(0)?;
+		if self.values.len() == top_frame.start_len {
+			// It is an error to pop more values than was pushed in the current frame
+			// (ie pop values pushed in the parent frame), unless the frame became
+			// polymorphic.
+			return if top_frame.is_polymorphic {
+				Ok(None)
+			} else {
+				Err("trying to pop more values than pushed")
+			}
+		}
+
+		let value_type = self.values.pop().ok_or("stack underflow")?;
+		self.height -= self.rules.value_cost(value_type);
+		Ok(Some(value_type))
+	}
+
 	/// Pop specified number of values from the value stack.
 	///
 	/// Returns `Err` if the stack happen to be negative value after
 	/// values popped.
 	fn pop_values(&mut self, value_count: u32) -> Result<(), &'static str> {
-		if value_count == 0 {
-			return Ok(())
-		}
-		{
-			let top_frame = self.frame(0)?;
-			if self.height == top_frame.start_height {
-				// It is an error to pop more values than was pushed in the current frame
-				// (ie pop values pushed in the parent frame), unless the frame became
-				// polymorphic.
-				return if top_frame.is_polymorphic {
-					Ok(())
-				} else {
-					return Err("trying to pop more values than pushed")
-				}
-			}
+		for _ in 0..value_count {
+			self.pop_value()?;
 		}
+		Ok(())
+	}
 
-		self.height = self.height.checked_sub(value_count).ok_or("stack underflow")?;
+	/// Pop the operands of an instruction and push its result of the specified type.
+	fn apply_op(&mut self, operands: u32, result: ValueType) -> Result<(), &'static str> {
+		self.pop_values(operands)?;
+		self.push_value(result)
+	}
+}
 
-		Ok(())
+/// Types of the locals of a function, including its parameters.
+struct Locals<'a> {
+	params: &'a [ValueType],
+	/// Local groups along with the index of the first local after each group.
+	groups: Vec<(u32, ValueType)>,
+}
+
+impl<'a> Locals<'a> {
+	fn new(params: &'a [ValueType], body: &elements::FuncBody) -> Result<Self, &'static str> {
+		let mut end = params.len() as u32;
+		let groups = body
+			.locals()
+			.iter()
+			.map(|local| {
+				end = end.checked_add(local.count()).ok_or("Overflow in local count")?;
+				Ok((end, local.value_type()))
+			})
+			.collect::<Result<_, _>>()?;
+		Ok(Self { params, groups })
 	}
+
+	fn get(&self, idx: u32) -> Result<ValueType, &'static str> {
+		if let Some(value_type) = self.params.get(idx as usize) {
+			return Ok(*value_type)
+		}
+		let group = self.groups.partition_point(|(end, _)| *end <= idx);
+		self.groups
+			.get(group)
+			.map(|(_, value_type)| *value_type)
+			.ok_or("Local not found")
+	}
+}
+
+/// Returns the types of all globals of the module, including imported ones.
+fn global_types(module: &elements::Module) -> Vec<ValueType> {
+	let imported =
+		module
+			.import_section()
+			.map(|is| is.entries())
+			.unwrap_or(&[])
+			.iter()
+			.filter_map(|entry| match entry.external() {
+				External::Global(global_type) => Some(global_type.content_type()),
+				_ => None,
+			});
+	let defined = module
+		.global_section()
+		.map(|gs| gs.entries())
+		.unwrap_or(&[])
+		.iter()
+		.map(|entry| entry.global_type().content_type());
+	imported.chain(defined).collect()
 }
 
+/// Returns the maximal height of the value stack of the function, including the activation frame.
+///
 /// This function expects the function to be validated.
-pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static str> {
+pub fn compute<R: StackCostRules>(
+	func_idx: u32,
+	module: &elements::Module,
+	rules: &R,
+) -> Result<u32, &'static str> {
 	use parity_wasm::elements::Instruction::*;
+	use ValueType::*;
 
 	let func_section = module.function_section().ok_or("No function section")?;
 	let code_section = module.code_section().ok_or("No code section")?;
@@ -144,8 +229,10 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 		.get(func_idx as usize)
 		.ok_or("Function body for the index isn't found")?;
 	let instructions = body.code();
+	let locals = Locals::new(func_signature.params(), body)?;
+	let globals = global_types(module);
 
-	let mut stack = Stack::new();
+	let mut stack = Stack::new(rules);
 	let mut max_height: u32 = 0;
 	let mut pc = 0;
 
@@ -154,9 +241,9 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 	let func_arity = func_signature.results().len() as u32;
 	stack.push_frame(Frame {
 		is_polymorphic: false,
-		end_arity: func_arity,
+		end_type: func_signature.results().first().cloned(),
 		branch_arity: func_arity,
-		start_height: 0,
+		start_len: 0,
 	});
 
 	loop {
@@ -176,17 +263,21 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 		match opcode {
 			Nop => {},
 			Block(ty) | Loop(ty) | If(ty) => {
-				let end_arity = u32::from(*ty != BlockType::NoResult);
+				let end_type = match *ty {
+					BlockType::NoResult => None,
+					BlockType::Value(value_type) => Some(value_type),
+				};
+				let end_arity = u32::from(end_type.is_some());
 				let branch_arity = if let Loop(_) = *opcode { 0 } else { end_arity };
 				if let If(_) = *opcode {
 					stack.pop_values(1)?;
 				}
-				let height = stack.height();
+				let start_len = stack.values.len();
 				stack.push_frame(Frame {
 					is_polymorphic: false,
-					end_arity,
+					end_type,
 					branch_arity,
-					start_height: height,
+					start_len,
 				});
 			},
 			Else => {
@@ -195,8 +286,10 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 			},
 			End => {
 				let frame = stack.pop_frame()?;
-				stack.trunc(frame.start_height);
-				stack.push_values(frame.end_arity)?;
+				stack.trunc(frame.start_len);
+				if let Some(end_type) = frame.end_type {
+					stack.push_value(end_type)?;
+				}
 			},
 			Unreachable => {
 				stack.mark_unreachable()?;
@@ -211,15 +304,19 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 				stack.mark_unreachable()?;
 			},
 			BrIf(target) => {
-				// Pop values for the destination block result.
-				let target_arity = stack.frame(*target)?.branch_arity;
-				stack.pop_values(target_arity)?;
-
 				// Pop condition value.
 				stack.pop_values(1)?;
 
-				// Push values back.
-				stack.push_values(target_arity)?;
+				// Pop values for the destination block result and push them back. Thus,
+				// effectively this doesn't modify the stack height.
+				let target_arity = stack.frame(*target)?.branch_arity;
+				let mut values = Vec::with_capacity(target_arity as usize);
+				for _ in 0..target_arity {
+					values.push(stack.pop_value()?);
+				}
+				for value_type in values.into_iter().rev().flatten() {
+					stack.push_value(value_type)?;
+				}
 			},
 			BrTable(br_table_data) => {
 				let arity_of_default = stack.frame(br_table_data.default)?.branch_arity;
@@ -253,8 +350,7 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 				stack.pop_values(ty.params().len() as u32)?;
 
 				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
+				stack.push_values(ty.results())?;
 			},
 			CallIndirect(x, _) => {
 				let Type::Function(ty) =
@@ -267,57 +363,56 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 				stack.pop_values(ty.params().len() as u32)?;
 
 				// Push result of the function execution to the stack.
-				let callee_arity = ty.results().len() as u32;
-				stack.push_values(callee_arity)?;
+				stack.push_values(ty.results())?;
 			},
 			Drop => {
 				stack.pop_values(1)?;
 			},
 			Select => {
 				// Pop two values and one condition.
-				stack.pop_values(2)?;
+				stack.pop_values(1)?;
+				let selected = stack.pop_value()?;
 				stack.pop_values(1)?;
 
-				// Push the selected value.
-				stack.push_values(1)?;
+				// Push the selected value. Its type is only unknown in unreachable code, so the
+				// type we assume in that case doesn't matter.
+				stack.push_value(selected.unwrap_or(I32))?;
 			},
-			GetLocal(_) => {
-				stack.push_values(1)?;
+			GetLocal(idx) => {
+				stack.push_value(locals.get(*idx)?)?;
 			},
 			SetLocal(_) => {
 				stack.pop_values(1)?;
 			},
-			TeeLocal(_) => {
+			TeeLocal(idx) => {
 				// This instruction pops and pushes the value, so
 				// effectively it doesn't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.apply_op(1, locals.get(*idx)?)?;
 			},
-			GetGlobal(_) => {
-				stack.push_values(1)?;
+			GetGlobal(idx) => {
+				let global_type = globals.get(*idx as usize).ok_or("Global not found")?;
+				stack.push_value(*global_type)?;
 			},
 			SetGlobal(_) => {
 				stack.pop_values(1)?;
 			},
+
+			// These instructions pop the address and pushes the result,
+			// which effictively don't modify the stack height.
 			I32Load(_, _) |
-			I64Load(_, _) |
-			F32Load(_, _) |
-			F64Load(_, _) |
 			I32Load8S(_, _) |
 			I32Load8U(_, _) |
 			I32Load16S(_, _) |
-			I32Load16U(_, _) |
+			I32Load16U(_, _) => stack.apply_op(1, I32)?,
+			I64Load(_, _) |
 			I64Load8S(_, _) |
 			I64Load8U(_, _) |
 			I64Load16S(_, _) |
 			I64Load16U(_, _) |
 			I64Load32S(_, _) |
-			I64Load32U(_, _) => {
-				// These instructions pop the address and pushes the result,
-				// which effictively don't modify the stack height.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
+			I64Load32U(_, _) => stack.apply_op(1, I64)?,
+			F32Load(_, _) => stack.apply_op(1, F32)?,
+			F64Load(_, _) => stack.apply_op(1, F64)?,
 
 			I32Store(_, _) |
 			I64Store(_, _) |
@@ -334,24 +429,23 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 
 			CurrentMemory(_) => {
 				// Pushes current memory size
-				stack.push_values(1)?;
+				stack.push_value(I32)?;
 			},
 			GrowMemory(_) => {
 				// Grow memory takes the value of pages to grow and pushes
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.apply_op(1, I32)?;
 			},
 
-			I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => {
-				// These instructions just push the single literal value onto the stack.
-				stack.push_values(1)?;
-			},
+			// These instructions just push the single literal value onto the stack.
+			I32Const(_) => stack.push_value(I32)?,
+			I64Const(_) => stack.push_value(I64)?,
+			F32Const(_) => stack.push_value(F32)?,
+			F64Const(_) => stack.push_value(F64)?,
 
 			I32Eqz | I64Eqz => {
 				// These instructions pop the value and compare it against zero, and pushes
 				// the result of the comparison.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
+				stack.apply_op(1, I32)?;
 			},
 
 			I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS |
@@ -359,49 +453,44 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 			I64GeS | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne |
 			F64Lt | F64Gt | F64Le | F64Ge => {
 				// Comparison operations take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
+				stack.apply_op(2, I32)?;
 			},
 
-			I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs | F32Neg |
-			F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg | F64Ceil |
-			F64Floor | F64Trunc | F64Nearest | F64Sqrt => {
-				// Unary operators take one operand and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
+			// Unary operators take one operand and produce one result.
+			I32Clz | I32Ctz | I32Popcnt => stack.apply_op(1, I32)?,
+			I64Clz | I64Ctz | I64Popcnt => stack.apply_op(1, I64)?,
+			F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt =>
+				stack.apply_op(1, F32)?,
+			F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt =>
+				stack.apply_op(1, F64)?,
 
+			// Binary operators take two operands and produce one result.
 			I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or |
-			I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Add | I64Sub |
-			I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl |
-			I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul | F32Div |
-			F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min |
-			F64Max | F64Copysign => {
-				// Binary operators take two operands and produce one result.
-				stack.pop_values(2)?;
-				stack.push_values(1)?;
-			},
-
+			I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr => stack.apply_op(2, I32)?,
+			I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or |
+			I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => stack.apply_op(2, I64)?,
+			F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign =>
+				stack.apply_op(2, F32)?,
+			F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign =>
+				stack.apply_op(2, F64)?,
+
+			// Conversion operators take one value and produce one result.
 			I32WrapI64 | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 |
+			I32ReinterpretF32 => stack.apply_op(1, I32)?,
 			I64ExtendSI32 | I64ExtendUI32 | I64TruncSF32 | I64TruncUF32 | I64TruncSF64 |
-			I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 |
-			F32DemoteF64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 |
-			F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 |
-			F64ReinterpretI64 => {
-				// Conversion operators take one value and produce one result.
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
+			I64TruncUF64 | I64ReinterpretF64 => stack.apply_op(1, I64)?,
+			F32ConvertSI32 | F32ConvertUI32 | F32ConvertSI64 | F32ConvertUI64 | F32DemoteF64 |
+			F32ReinterpretI32 => stack.apply_op(1, F32)?,
+			F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64 | F64ConvertUI64 | F64PromoteF32 |
+			F64ReinterpretI64 => stack.apply_op(1, F64)?,
 
 			#[cfg(feature = "sign_ext")]
 			SignExt(SignExtInstruction::I32Extend8S) |
-			SignExt(SignExtInstruction::I32Extend16S) |
+			SignExt(SignExtInstruction::I32Extend16S) => stack.apply_op(1, I32)?,
+			#[cfg(feature = "sign_ext")]
 			SignExt(SignExtInstruction::I64Extend8S) |
 			SignExt(SignExtInstruction::I64Extend16S) |
-			SignExt(SignExtInstruction::I64Extend32S) => {
-				stack.pop_values(1)?;
-				stack.push_values(1)?;
-			},
+			SignExt(SignExtInstruction::I64Extend32S) => stack.apply_op(1, I64)?,
 		}
 		pc += 1;
 	}
@@ -411,7 +500,10 @@ pub fn compute(func_idx: u32, module: &elements::Module) -> Result<u32, &'static
 
 #[cfg(test)]
 mod tests {
-	use super::*;
+	use super::{
+		super::{ConstantStackCostRules, ACTIVATION_FRAME_COST},
+		*,
+	};
 	use parity_wasm::elements;
 
 	fn parse_wat(source: &str) -> elements::Module {
@@ -436,7 +528,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 3 + ACTIVATION_FRAME_COST);
 	}
 
@@ -453,7 +545,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
 	}
 
@@ -471,7 +563,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, ACTIVATION_FRAME_COST);
 	}
 
@@ -500,7 +592,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 2 + ACTIVATION_FRAME_COST);
 	}
 
@@ -524,7 +616,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
 	}
 
@@ -546,10 +638,52 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 1 + ACTIVATION_FRAME_COST);
 	}
 
+	#[test]
+	fn weighted_value_types() {
+		struct Rules;
+
+		impl StackCostRules for Rules {
+			fn value_cost(&self, value_type: ValueType) -> u32 {
+				match value_type {
+					ValueType::I64 | ValueType::F64 => 2,
+					_ => 1,
+				}
+			}
+
+			fn frame_cost(&self) -> u32 {
+				5
+			}
+		}
+
+		let module = parse_wat(
+			r#"
+(module
+	(global $g (mut f64) (f64.const 0))
+	(func $main (param i32) (result i64)
+		(local i64)
+		local.get 0
+		local.get 1
+		global.get $g
+		drop
+		drop
+		drop
+		i64.const 1
+		i32.const 2
+		i32.const 3
+		select
+	)
+)
+"#,
+		);
+
+		let height = compute(0, &module, &Rules).unwrap();
+		assert_eq!(height, 1 + 2 + 2 + 5);
+	}
+
 	#[test]
 	fn if_else_works() {
 		let module = parse_wat(
@@ -572,7 +706,7 @@ mod tests {
 "#,
 		);
 
-		let height = compute(0, &module).unwrap();
+		let height = compute(0, &module, &ConstantStackCostRules::default()).unwrap();
 		assert_eq!(height, 3 + ACTIVATION_FRAME_COST);
 	}
 }
@@ -1,15 +1,19 @@
 //! Contains the code for the stack height limiter instrumentation.
 
-use alloc::{vec, vec::Vec};
+use crate::{
+	manifest::{self, RepeatedPass},
+	OffsetMap,
+};
+use alloc::{format, string::ToString, vec, vec::Vec};
 use core::mem;
 use parity_wasm::{
 	builder,
-	elements::{self, Instruction, Instructions, Type},
+	elements::{self, Instruction, Instructions, Type, ValueType},
 };
 
 /// Macro to generate preamble and postamble.
 macro_rules! instrument_call {
-	($callee_idx: expr, $callee_stack_cost: expr, $stack_height_global_idx: expr, $stack_limit: expr) => {{
+	($call: expr, $callee_stack_cost: expr, $stack_height_global_idx: expr, $stack_limit: expr) => {{
 		use $crate::parity_wasm::elements::Instruction::*;
 		[
 			// stack_height += stack_cost(F)
@@ -25,7 +29,7 @@ macro_rules! instrument_call {
 			Unreachable,
 			End,
 			// Original call
-			Call($callee_idx),
+			$call,
 			// stack_height -= stack_cost(F)
 			GetGlobal($stack_height_global_idx),
 			I32Const($callee_stack_cost),
@@ -35,13 +39,279 @@ macro_rules! instrument_call {
 	}};
 }
 
+// The number of instructions following the original call in `instrument_call!`.
+const POSTAMBLE_LEN: usize = 4;
+
+mod call_graph;
 mod max_height;
+mod removal;
 mod thunk;
 
-pub struct Context {
+pub use removal::remove;
+
+// The cost in stack items that should be charged per call of a function. This is
+// is a static cost that is added to each function call. This makes sense because even
+// if a function does not use any parameters or locals some stack space on the host
+// machine might be consumed to hold some context.
+const ACTIVATION_FRAME_COST: u32 = 2;
+
+/// An interface that describes the stack costs of functions.
+///
+/// This is the stack limiter analogue of [`Rules`](crate::gas_metering::Rules). The stack cost of a
+/// function is the sum of the costs of its locals, the maximal height of its value stack and
+/// the cost of its activation frame.
+pub trait StackCostRules {
+	/// Returns the cost of a single value of the specified type.
+	///
+	/// This applies to locals and to values on the value stack alike.
+	fn value_cost(&self, value_type: ValueType) -> u32;
+
+	/// Returns the static cost that is charged per call of a function in addition to its locals
+	/// and values.
+	fn frame_cost(&self) -> u32;
+
+	/// Returns the stack cost of a function given the summed up cost of its locals and the
+	/// maximal height of its value stack, which already includes [`Self::frame_cost`].
+	///
+	/// The default implementation just adds both. Returning `None` makes the instrumentation fail.
+	fn function_cost(&self, locals_cost: u32, max_stack_height: u32) -> Option<u32> {
+		locals_cost.checked_add(max_stack_height)
+	}
+}
+
+/// A type that implements [`StackCostRules`] so that every value costs the same.
+///
+/// The [`Default`] implementation is what [`inject`] uses.
+#[derive(Debug, Clone)]
+pub struct ConstantStackCostRules {
+	value_cost: u32,
+	frame_cost: u32,
+}
+
+impl ConstantStackCostRules {
+	/// Create a new [`ConstantStackCostRules`].
+	///
+	/// Uses `value_cost` for every value regardless of its type and `frame_cost` for every
+	/// activation frame.
+	pub fn new(value_cost: u32, frame_cost: u32) -> Self {
+		Self { value_cost, frame_cost }
+	}
+}
+
+impl Default for ConstantStackCostRules {
+	/// Uses a cost of `1` per value and `2` per activation frame.
+	fn default() -> Self {
+		Self { value_cost: 1, frame_cost: ACTIVATION_FRAME_COST }
+	}
+}
+
+impl StackCostRules for ConstantStackCostRules {
+	fn value_cost(&self, _: ValueType) -> u32 {
+		self.value_cost
+	}
+
+	fn frame_cost(&self) -> u32 {
+		self.frame_cost
+	}
+}
+
+/// A type that implements [`StackCostRules`] so that the stack cost of a function is the amount
+/// of bytes it occupies on the native stack of an execution engine.
+///
+/// Using these rules, the stack limit is expressed in bytes too. This allows to derive the
+/// stack limit from the actual stack size of the thread executing the module instead of
+/// guessing an unitless number.
+///
+/// The execution engine is modelled as placing every local and every value of the value
+/// stack into one or more slots of `slot_size` bytes each, depending on the size of its type.
+/// For example, with a `slot_size` of 4 an `i32` occupies a single slot while an `i64`
+/// occupies two. Every activation frame additionally occupies `frame_size` bytes.
+#[derive(Debug, Clone)]
+pub struct NativeStackCostRules {
+	slot_size: u32,
+	frame_size: u32,
+	frame_alignment: u32,
+}
+
+impl NativeStackCostRules {
+	/// Create a new [`NativeStackCostRules`].
+	///
+	/// Panics if `slot_size` is zero.
+	pub fn new(slot_size: u32, frame_size: u32) -> Self {
+		assert!(slot_size > 0, "slot size must not be zero");
+		Self { slot_size, frame_size, frame_alignment: 1 }
+	}
+
+	/// Round up the stack cost of every function to a multiple of `alignment` bytes.
+	///
+	/// Panics if `alignment` is zero.
+	pub fn with_frame_alignment(mut self, alignment: u32) -> Self {
+		assert!(alignment > 0, "frame alignment must not be zero");
+		self.frame_alignment = alignment;
+		self
+	}
+}
+
+impl StackCostRules for NativeStackCostRules {
+	fn value_cost(&self, value_type: ValueType) -> u32 {
+		let size = match value_type {
+			ValueType::I32 | ValueType::F32 => 4,
+			ValueType::I64 | ValueType::F64 => 8,
+			// `v128` is only available with the `simd` feature of `parity-wasm`.
+			#[allow(unreachable_patterns)]
+			_ => 16,
+		};
+		// Round up to a whole number of slots.
+		(size + self.slot_size - 1) / self.slot_size * self.slot_size
+	}
+
+	fn frame_cost(&self) -> u32 {
+		self.frame_size
+	}
+
+	fn function_cost(&self, locals_cost: u32, max_stack_height: u32) -> Option<u32> {
+		let cost = locals_cost.checked_add(max_stack_height)?;
+		cost.checked_add(self.frame_alignment - 1)
+			.map(|cost| cost / self.frame_alignment * self.frame_alignment)
+	}
+}
+
+/// Configuration of the stack limiter instrumentation.
+///
+/// Use [`inject_with_config`] to apply it to a module.
+#[derive(Debug, Clone)]
+pub struct StackLimiterConfig {
+	stack_limit: u32,
+	export_thunks: bool,
+	stack_height_export: Option<&'static str>,
+	call_graph_analysis: bool,
+	table_thunks: bool,
+	external_table: bool,
+	manifest: bool,
+	repeated_pass: Option<RepeatedPass>,
+}
+
+impl StackLimiterConfig {
+	/// Create a new [`StackLimiterConfig`] with the specified `stack_limit`.
+	///
+	/// All other options are set to the same values [`inject`] uses.
+	pub fn new(stack_limit: u32) -> Self {
+		Self {
+			stack_limit,
+			export_thunks: true,
+			stack_height_export: None,
+			call_graph_analysis: false,
+			table_thunks: true,
+			external_table: false,
+			manifest: false,
+			repeated_pass: None,
+		}
+	}
+
+	/// Whether to generate thunks for the exported functions.
+	///
+	/// Enabled by default. When disabled, exported functions are left as they are and the stack
+	/// height isn't increased upon an entry to them. The host is then responsible for accounting
+	/// for the activation frame of the exported function it calls into. Table entries and the
+	/// start function are still routed through thunks.
+	pub fn with_export_thunks(mut self, enabled: bool) -> Self {
+		self.export_thunks = enabled;
+		self
+	}
+
+	/// Export the global tracking the current stack height under the specified `name`.
+	///
+	/// Not exported by default. The stack height isn't restored when the execution traps (see
+	/// [`inject`]). Exporting the global allows the host to reset it to zero before reusing an
+	/// instance after a trap.
+	pub fn with_stack_height_export(mut self, name: &'static str) -> Self {
+		self.stack_height_export = Some(name);
+		self
+	}
+
+	/// Whether to analyze the call graph in order to instrument fewer calls.
+	///
+	/// Disabled by default. When enabled, the worst-case stack cost of every function that can't
+	/// reach a recursive function through direct calls is determined statically. This cost
+	/// includes all functions it calls transitively and is charged once upon entry to it. Calls
+	/// made from within such a function aren't instrumented anymore. Only calls made from
+	/// functions that can reach a cycle in the call graph remain instrumented.
+	///
+	/// This greatly reduces the runtime overhead for call chains without recursion. However,
+	/// since the worst-case cost is charged up front, execution might trap earlier than it would
+	/// with every call instrumented.
+	///
+	/// Indirect calls aren't part of the call graph. When combined with
+	/// [`with_table_thunks(false)`](Self::with_table_thunks), functions containing a
+	/// `call_indirect` keep all their calls instrumented, and every `call_indirect` is charged
+	/// the maximal cumulative stack cost of all table members with a matching signature, even if
+	/// the actual callee uses much less. This overcharges indirect calls into deep call chains.
+	pub fn with_call_graph_analysis(mut self, enabled: bool) -> Self {
+		self.call_graph_analysis = enabled;
+		self
+	}
+
+	/// Whether to generate thunks for the table entries.
+	///
+	/// Enabled by default. When disabled, table entries keep their original indices and every
+	/// `call_indirect` is instrumented directly instead. It is charged with the maximal stack
+	/// cost of all functions in the table whose signature matches the signature of the
+	/// `call_indirect`. This saves the extra call through the thunk upon every indirect call.
+	///
+	/// Only the functions placed into the table by the element segments of the module are
+	/// considered. Thus, modules whose table is imported or exported are rejected unless allowed
+	/// by [`with_external_table`](Self::with_external_table).
+	pub fn with_table_thunks(mut self, enabled: bool) -> Self {
+		self.table_thunks = enabled;
+		self
+	}
+
+	/// Whether to accept modules with an imported or exported table when no thunks are generated
+	/// for the table entries.
+	///
+	/// Disabled by default, which makes the instrumentation fail for such modules. Functions
+	/// placed into the table from outside of the module aren't accounted for when charging a
+	/// `call_indirect`, so their stack usage isn't limited. Only enable this if the embedder
+	/// never places functions into the table. Has no effect when thunks are generated for the
+	/// table entries.
+	pub fn with_external_table(mut self, allowed: bool) -> Self {
+		self.external_table = allowed;
+		self
+	}
+
+	/// Whether to record the stack limiter in the `wasm-instrument` custom section of the module.
+	///
+	/// Disabled by default. The record contains the stack limit, the index of the stack height
+	/// global and the thunk generated for each function. It can be read with
+	/// [`read_manifest`](crate::read_manifest).
+	pub fn with_manifest(mut self, enabled: bool) -> Self {
+		self.manifest = enabled;
+		self
+	}
+
+	/// Record the stack limiter in the `wasm-instrument` custom section of the module and handle
+	/// modules that are already instrumented according to `repeated_pass`.
+	///
+	/// Not recorded by default. Instrumenting a module twice would add a second stack height
+	/// global and thunks calling the thunks of the first pass.
+	pub fn with_repeated_pass(mut self, repeated_pass: RepeatedPass) -> Self {
+		self.repeated_pass = Some(repeated_pass);
+		self
+	}
+}
+
+struct Context {
 	stack_height_global_idx: u32,
 	func_stack_costs: Vec<u32>,
 	stack_limit: u32,
+	export_thunks: bool,
+	/// Functions whose calls don't need to be instrumented because their cumulative stack cost is
+	/// charged upon entry to them.
+	uninstrumented_funcs: Vec<bool>,
+	table_thunks: bool,
+	/// The stack cost of an indirect call for each type in the type section. Only used when no
+	/// thunks are generated for the table entries.
+	indirect_stack_costs: Vec<u32>,
 }
 
 impl Context {
@@ -51,6 +321,9 @@ impl Context {
 	}
 
 	/// Returns `stack_cost` for `func_idx`.
+	///
+	/// This is the cumulative stack cost if the function was found to have one by the call graph
+	/// analysis.
 	fn stack_cost(&self, func_idx: u32) -> Option<u32> {
 		self.func_stack_costs.get(func_idx as usize).cloned()
 	}
@@ -59,6 +332,26 @@ impl Context {
 	fn stack_limit(&self) -> u32 {
 		self.stack_limit
 	}
+
+	/// Returns whether exported functions should be routed through thunks.
+	fn export_thunks(&self) -> bool {
+		self.export_thunks
+	}
+
+	/// Returns whether table entries should be routed through thunks.
+	fn table_thunks(&self) -> bool {
+		self.table_thunks
+	}
+
+	/// Returns the stack cost of an indirect call with the signature `type_idx`.
+	fn indirect_stack_cost(&self, type_idx: u32) -> Option<u32> {
+		self.indirect_stack_costs.get(type_idx as usize).cloned()
+	}
+
+	/// Returns whether the calls made by the function `func_idx` need to be instrumented.
+	fn instrument_calls_of(&self, func_idx: u32) -> bool {
+		!self.uninstrumented_funcs.get(func_idx as usize).copied().unwrap_or(false)
+	}
 }
 
 /// Inject the instumentation that makes stack overflows deterministic, by introducing
@@ -82,6 +375,8 @@ impl Context {
 /// Note, that we can't instrument all possible ways to return from the function. The simplest
 /// example would be a trap issued by the host function.
 /// That means stack height global won't be equal to zero upon the next execution after such trap.
+/// The global can be exported with [`StackLimiterConfig::with_stack_height_export`] so that the
+/// host is able to reset it.
 ///
 /// # Thunks
 ///
@@ -95,12 +390,26 @@ impl Context {
 /// will increase before and decrease the stack height after the call to original function, and
 /// then make exported function and table entries, start section to point to a corresponding thunks.
 ///
+/// Only one thunk is generated per function, no matter how many exports, table entries or the start
+/// section refer to it. Thunks for exported functions can be disabled by using
+/// [`inject_with_config`] with [`StackLimiterConfig::with_export_thunks`]. Thunks for table
+/// entries can be replaced by instrumenting the indirect calls with
+/// [`StackLimiterConfig::with_table_thunks`].
+///
+/// # Call graph analysis
+///
+/// Calls made from functions that can't reach a recursive function don't need to be
+/// instrumented if the worst-case stack cost of the whole call chain is charged upon entry. This
+/// is enabled with [`StackLimiterConfig::with_call_graph_analysis`].
+///
 /// # Stack cost
 ///
-/// Stack cost of the function is calculated as a sum of it's locals
-/// and the maximal height of the value stack.
+/// Stack cost of the function is calculated as a sum of it's locals, the maximal height of the
+/// value stack and a static cost for its activation frame.
 ///
-/// All values are treated equally, as they have the same size.
+/// All values are treated equally, as they have the same size. Use [`inject_with_config`] with
+/// custom [`StackCostRules`] to weight values by their type. [`NativeStackCostRules`] allows to
+/// express the stack costs and the stack limit in bytes.
 ///
 /// The rationale is that this makes it possible to use the following very naive wasm executor:
 ///
@@ -112,49 +421,177 @@ impl Context {
 ///   frames.
 /// - upon entry into the function entire stack frame is allocated.
 pub fn inject(
-	mut module: elements::Module,
+	module: elements::Module,
 	stack_limit: u32,
 ) -> Result<elements::Module, &'static str> {
+	inject_with_config(
+		module,
+		&StackLimiterConfig::new(stack_limit),
+		&ConstantStackCostRules::default(),
+	)
+}
+
+/// Same as [`inject`] but allows to customize the instrumentation using a [`StackLimiterConfig`]
+/// and to specify how stack costs are calculated using `rules`.
+///
+/// With the `dwarf` feature enabled the DWARF custom sections are removed from the instrumented
+/// module, as their addresses would be stale. Use [`inject_with_offset_map`] to keep and rewrite
+/// them.
+pub fn inject_with_config<R: StackCostRules>(
+	module: elements::Module,
+	config: &StackLimiterConfig,
+	rules: &R,
+) -> Result<elements::Module, &'static str> {
+	inject_impl(module, config, rules, None)
+}
+
+/// Same as [`inject_with_config`] but additionally returns an [`OffsetMap`] mapping the
+/// instructions of the instrumented functions back to the original ones.
+///
+/// The thunks added by the instrumentation aren't part of the map. The DWARF custom sections are
+/// left as they are, for the map to be applied to them with `rewrite_debug_info`.
+pub fn inject_with_offset_map<R: StackCostRules>(
+	module: elements::Module,
+	config: &StackLimiterConfig,
+	rules: &R,
+) -> Result<(elements::Module, OffsetMap), &'static str> {
+	let mut offsets = OffsetMap::default();
+	let module = inject_impl(module, config, rules, Some(&mut offsets))?;
+	Ok((module, offsets))
+}
+
+fn inject_impl<R: StackCostRules>(
+	mut module: elements::Module,
+	config: &StackLimiterConfig,
+	rules: &R,
+	offsets: Option<&mut OffsetMap>,
+) -> Result<elements::Module, &'static str> {
+	if let Some(repeated_pass) = config.repeated_pass {
+		if manifest::contains_pass(&module, manifest::STACK_LIMITER) {
+			match repeated_pass {
+				RepeatedPass::Instrument => {},
+				RepeatedPass::Skip => return Ok(module),
+				RepeatedPass::Error =>
+					return Err("The module is already instrumented with a stack limiter"),
+			}
+		}
+	}
+
+	if !config.table_thunks && !config.external_table && has_external_table(&module) {
+		return Err("The table is imported or exported, which isn't allowed without table thunks")
+	}
+
+	let stack_height_global_idx =
+		generate_stack_height_global(&mut module, config.stack_height_export)?;
+	let mut func_stack_costs = compute_stack_costs(&module, rules)?;
+	let uninstrumented_funcs = apply_call_graph_analysis(&module, config, &mut func_stack_costs);
+
+	let indirect_stack_costs = if config.table_thunks {
+		Vec::new()
+	} else {
+		compute_indirect_stack_costs(&module, &func_stack_costs)?
+	};
+
 	let mut ctx = Context {
-		stack_height_global_idx: generate_stack_height_global(&mut module),
-		func_stack_costs: compute_stack_costs(&module)?,
-		stack_limit,
+		stack_height_global_idx,
+		func_stack_costs,
+		stack_limit: config.stack_limit,
+		export_thunks: config.export_thunks,
+		uninstrumented_funcs,
+		table_thunks: config.table_thunks,
+		indirect_stack_costs,
 	};
 
-	instrument_functions(&mut ctx, &mut module)?;
-	let module = thunk::generate_thunks(&mut ctx, module)?;
+	// Without an offset map the DWARF debug info can't be rewritten and would be stale.
+	#[cfg(feature = "dwarf")]
+	let strip_debug_info = offsets.is_none();
+	instrument_functions(&mut ctx, &mut module, offsets)?;
+	let (mut module, thunks) = thunk::generate_thunks(&mut ctx, module)?;
+
+	if config.manifest || config.repeated_pass.is_some() {
+		let stack_limit = config.stack_limit.to_string();
+		let stack_height_global = stack_height_global_idx.to_string();
+		let thunks = thunks
+			.iter()
+			.map(|(func_idx, thunk_idx)| format!("{}:{}", func_idx, thunk_idx))
+			.collect::<Vec<_>>()
+			.join(",");
+		manifest::record_pass(
+			&mut module,
+			manifest::STACK_LIMITER,
+			&[
+				("stack_limit", &stack_limit),
+				("stack_height_global", &stack_height_global),
+				("thunks", &thunks),
+			],
+		);
+	}
+	#[cfg(feature = "dwarf")]
+	if strip_debug_info {
+		crate::strip_debug_info(&mut module);
+	}
 
 	Ok(module)
 }
 
 /// Generate a new global that will be used for tracking current stack height.
-fn generate_stack_height_global(module: &mut elements::Module) -> u32 {
+///
+/// The global is exported under `export_name` if specified. Returns the index of the global in
+/// the global index space.
+fn generate_stack_height_global(
+	module: &mut elements::Module,
+	export_name: Option<&'static str>,
+) -> Result<u32, &'static str> {
 	let global_entry = builder::global()
 		.value_type()
 		.i32()
 		.mutable()
 		.init_expr(Instruction::I32Const(0))
 		.build();
+	let global_idx = module.globals_space() as u32;
 
-	// Try to find an existing global section.
-	for section in module.sections_mut() {
-		if let elements::Section::Global(gs) = section {
-			gs.entries_mut().push(global_entry);
-			return (gs.entries().len() as u32) - 1
+	if let Some(name) = export_name {
+		if module
+			.export_section()
+			.map_or(false, |es| es.entries().iter().any(|entry| entry.field() == name))
+		{
+			return Err("The export name of the stack height global is already taken")
+		}
+		let export_entry =
+			elements::ExportEntry::new(name.into(), elements::Internal::Global(global_idx));
+		match module.export_section_mut() {
+			Some(es) => es.entries_mut().push(export_entry),
+			None => module
+				.insert_section(elements::Section::Export(elements::ExportSection::with_entries(
+					vec![export_entry],
+				)))
+				.expect("export section doesn't exist; qed"),
 		}
 	}
 
-	// Existing section not found, create one!
+	// Try to find an existing global section.
+	if let Some(gs) = module.global_section_mut() {
+		gs.entries_mut().push(global_entry);
+		return Ok(global_idx)
+	}
+
+	// Existing section not found, create one! The thunks are added without rebuilding the module,
+	// so the section has to be inserted at its proper position right away.
 	module
-		.sections_mut()
-		.push(elements::Section::Global(elements::GlobalSection::with_entries(vec![global_entry])));
-	0
+		.insert_section(elements::Section::Global(elements::GlobalSection::with_entries(vec![
+			global_entry,
+		])))
+		.expect("global section doesn't exist; qed");
+	Ok(global_idx)
 }
 
 /// Calculate stack costs for all functions.
 ///
 /// Returns a vector with a stack cost for each function, including imports.
-fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, &'static str> {
+fn compute_stack_costs<R: StackCostRules>(
+	module: &elements::Module,
+	rules: &R,
+) -> Result<Vec<u32>, &'static str> {
 	let func_imports = module.import_count(elements::ImportCountType::Function);
 
 	// TODO: optimize!
@@ -164,16 +601,32 @@ fn compute_stack_costs(module: &elements::Module) -> Result<Vec<u32>, &'static s
 				// We can't calculate stack_cost of the import functions.
 				Ok(0)
 			} else {
-				compute_stack_cost(func_idx as u32, module)
+				compute_stack_cost(func_idx as u32, module, rules)
 			}
 		})
 		.collect()
 }
 
-/// Stack cost of the given *defined* function is the sum of it's locals count (that is,
-/// number of arguments plus number of local variables) and the maximal stack
-/// height.
-fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, &'static str> {
+/// Stack cost of the given *defined* function is the sum of the costs of it's local variables and
+/// the maximal stack height, which includes the activation frame.
+fn compute_stack_cost<R: StackCostRules>(
+	func_idx: u32,
+	module: &elements::Module,
+	rules: &R,
+) -> Result<u32, &'static str> {
+	let (locals_cost, max_stack_height) = compute_stack_cost_parts(func_idx, module, rules)?;
+	rules
+		.function_cost(locals_cost, max_stack_height)
+		.ok_or("Overflow in adding locals_cost and max_stack_height")
+}
+
+/// Returns the cost of the locals and the maximal stack height of the given *defined* function,
+/// which make up its stack cost.
+pub(crate) fn compute_stack_cost_parts<R: StackCostRules>(
+	func_idx: u32,
+	module: &elements::Module,
+	rules: &R,
+) -> Result<(u32, u32), &'static str> {
 	// To calculate the cost of a function we need to convert index from
 	// function index space to defined function spaces.
 	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
@@ -188,28 +641,147 @@ fn compute_stack_cost(func_idx: u32, module: &elements::Module) -> Result<u32, &
 		.get(defined_func_idx as usize)
 		.ok_or("Function body is out of bounds")?;
 
-	let mut locals_count: u32 = 0;
+	let mut locals_cost: u32 = 0;
 	for local_group in body.locals() {
-		locals_count =
-			locals_count.checked_add(local_group.count()).ok_or("Overflow in local count")?;
+		let group_cost = local_group
+			.count()
+			.checked_mul(rules.value_cost(local_group.value_type()))
+			.ok_or("Overflow in locals cost")?;
+		locals_cost = locals_cost.checked_add(group_cost).ok_or("Overflow in locals cost")?;
 	}
 
-	let max_stack_height = max_height::compute(defined_func_idx, module)?;
+	let max_stack_height = max_height::compute(defined_func_idx, module, rules)?;
 
-	locals_count
-		.checked_add(max_stack_height)
-		.ok_or("Overflow in adding locals_count and max_stack_height")
+	Ok((locals_cost, max_stack_height))
+}
+
+/// Replace the `func_stack_costs` by the cumulative stack costs charged for calls if the call
+/// graph analysis is enabled in `config`.
+///
+/// Returns for every function whether its calls are left uninstrumented. Empty if the analysis
+/// is disabled.
+pub(crate) fn apply_call_graph_analysis(
+	module: &elements::Module,
+	config: &StackLimiterConfig,
+	func_stack_costs: &mut [u32],
+) -> Vec<bool> {
+	let mut uninstrumented_funcs = Vec::new();
+	if config.call_graph_analysis {
+		let cumulative_stack_costs = call_graph::cumulative_stack_costs(module, func_stack_costs);
+		uninstrumented_funcs = cumulative_stack_costs.iter().map(Option::is_some).collect();
+		for (cost, cumulative_cost) in func_stack_costs.iter_mut().zip(cumulative_stack_costs) {
+			if let Some(cumulative_cost) = cumulative_cost {
+				*cost = cumulative_cost;
+			}
+		}
+
+		// Without a thunk nothing charges the cumulative cost when the host calls the function.
+		if !config.export_thunks {
+			let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+			for entry in exports {
+				if let elements::Internal::Function(func_idx) = entry.internal() {
+					if let Some(uninstrumented) = uninstrumented_funcs.get_mut(*func_idx as usize) {
+						*uninstrumented = false;
+					}
+				}
+			}
+		}
+
+		// Indirect calls aren't part of the call graph. Without thunks they are only accounted
+		// for by instrumenting them.
+		if !config.table_thunks {
+			let func_imports = module.import_count(elements::ImportCountType::Function);
+			let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+			for (func_idx, body) in (func_imports..).zip(bodies) {
+				let has_indirect_calls = body
+					.code()
+					.elements()
+					.iter()
+					.any(|instr| matches!(instr, Instruction::CallIndirect(..)));
+				if has_indirect_calls {
+					uninstrumented_funcs[func_idx] = false;
+				}
+			}
+		}
+	}
+
+	uninstrumented_funcs
+}
+
+/// Returns the indices of the functions [`inject_with_config`] would generate a thunk for given
+/// their `func_stack_costs`.
+pub(crate) fn thunked_functions(
+	module: &elements::Module,
+	config: &StackLimiterConfig,
+	func_stack_costs: &[u32],
+) -> Vec<u32> {
+	let mut thunked: Vec<u32> =
+		thunk::thunk_candidates(module, config.export_thunks, config.table_thunks)
+			.filter(|func_idx| func_stack_costs.get(*func_idx as usize).map_or(false, |c| *c != 0))
+			.collect();
+	thunked.sort_unstable();
+	thunked.dedup();
+	thunked
+}
+
+/// Returns whether the table of `module` is imported or exported.
+fn has_external_table(module: &elements::Module) -> bool {
+	let imports = module.import_section().map(|is| is.entries()).unwrap_or(&[]);
+	let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+	imports
+		.iter()
+		.any(|entry| matches!(entry.external(), elements::External::Table(_))) ||
+		exports
+			.iter()
+			.any(|entry| matches!(entry.internal(), elements::Internal::Table(_)))
+}
+
+/// Calculate the stack costs of indirect calls for all types given the `func_stack_costs` of all
+/// functions.
+///
+/// The cost of an indirect call is the maximal stack cost of all table members whose signature
+/// matches the signature of the call. Returns a vector with a stack cost for each type.
+fn compute_indirect_stack_costs(
+	module: &elements::Module,
+	func_stack_costs: &[u32],
+) -> Result<Vec<u32>, &'static str> {
+	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+	let mut indirect_stack_costs = vec![0; types.len()];
+
+	let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+	for func_idx in elem_segments.iter().flat_map(|segment| segment.members()) {
+		let cost = *func_stack_costs.get(*func_idx as usize).ok_or("function index isn't found")?;
+		let signature = resolve_func_type(*func_idx, module)?;
+		// Signatures of indirect calls are matched structurally.
+		for (Type::Function(ty), indirect_cost) in types.iter().zip(&mut indirect_stack_costs) {
+			if ty == signature {
+				*indirect_cost = (*indirect_cost).max(cost);
+			}
+		}
+	}
+
+	Ok(indirect_stack_costs)
 }
 
 fn instrument_functions(
 	ctx: &mut Context,
 	module: &mut elements::Module,
+	mut offsets: Option<&mut OffsetMap>,
 ) -> Result<(), &'static str> {
+	let func_imports = module.import_count(elements::ImportCountType::Function) as u32;
 	for section in module.sections_mut() {
 		if let elements::Section::Code(code_section) = section {
-			for func_body in code_section.bodies_mut() {
+			for (func_idx, func_body) in (func_imports..).zip(code_section.bodies_mut()) {
 				let opcodes = func_body.code_mut();
-				instrument_function(ctx, opcodes)?;
+				let mut positions = Vec::new();
+				if ctx.instrument_calls_of(func_idx) {
+					instrument_function(ctx, opcodes, offsets.is_some().then(|| &mut positions))?;
+				} else {
+					positions.extend(0..opcodes.elements().len() as u32);
+				}
+				if let Some(offsets) = offsets.as_deref_mut() {
+					offsets.insert(func_idx, func_idx, positions);
+				}
 			}
 		}
 	}
@@ -217,7 +789,8 @@ fn instrument_functions(
 }
 
 /// This function searches `call` instructions and wrap each call
-/// with preamble and postamble.
+/// with preamble and postamble. The same is done for `call_indirect` instructions if
+/// no thunks are generated for the table entries.
 ///
 /// Before:
 ///
@@ -242,12 +815,18 @@ fn instrument_functions(
 ///
 /// drop
 /// ```
-fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(), &'static str> {
+///
+/// The original position of every resulting instruction is recorded in `positions` if provided.
+/// The postamble belongs to the instruction following the call.
+fn instrument_function(
+	ctx: &mut Context,
+	func: &mut Instructions,
+	mut positions: Option<&mut Vec<u32>>,
+) -> Result<(), &'static str> {
 	use Instruction::*;
 
 	struct InstrumentCall {
 		offset: usize,
-		callee: u32,
 		cost: u32,
 	}
 
@@ -256,22 +835,18 @@ fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(),
 		.iter()
 		.enumerate()
 		.filter_map(|(offset, instruction)| {
-			if let Call(callee) = instruction {
-				ctx.stack_cost(*callee).and_then(|cost| {
-					if cost > 0 {
-						Some(InstrumentCall { callee: *callee, offset, cost })
-					} else {
-						None
-					}
-				})
-			} else {
-				None
-			}
+			let cost = match instruction {
+				Call(callee) => ctx.stack_cost(*callee),
+				CallIndirect(type_idx, _) if !ctx.table_thunks() =>
+					ctx.indirect_stack_cost(*type_idx),
+				_ => None,
+			};
+			cost.filter(|cost| *cost > 0).map(|cost| InstrumentCall { offset, cost })
 		})
 		.collect();
 
 	// The `instrumented_call!` contains the call itself. This is why we need to subtract one.
-	let len = func.elements().len() + calls.len() * (instrument_call!(0, 0, 0, 0).len() - 1);
+	let len = func.elements().len() + calls.len() * (instrument_call!(Nop, 0, 0, 0).len() - 1);
 	let original_instrs = mem::replace(func.elements_mut(), Vec::with_capacity(len));
 	let new_instrs = func.elements_mut();
 
@@ -281,7 +856,7 @@ fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(),
 		let did_instrument = if let Some(call) = calls.peek() {
 			if call.offset == original_pos {
 				let new_seq = instrument_call!(
-					call.callee,
+					instr.clone(),
 					call.cost as i32,
 					ctx.stack_height_global_idx(),
 					ctx.stack_limit()
@@ -300,6 +875,16 @@ fn instrument_function(ctx: &mut Context, func: &mut Instructions) -> Result<(),
 		} else {
 			new_instrs.push(instr);
 		}
+
+		if let Some(positions) = positions.as_deref_mut() {
+			if did_instrument {
+				let postamble_start = new_instrs.len() - POSTAMBLE_LEN;
+				positions.resize(postamble_start, original_pos as u32);
+				positions.resize(new_instrs.len(), original_pos as u32 + 1);
+			} else {
+				positions.push(original_pos as u32);
+			}
+		}
 	}
 
 	if calls.next().is_some() {
@@ -314,6 +899,15 @@ fn resolve_func_type(
 	module: &elements::Module,
 ) -> Result<&elements::FunctionType, &'static str> {
 	let types = module.type_section().map(|ts| ts.types()).unwrap_or(&[]);
+	let sig_idx = resolve_func_type_idx(func_idx, module)?;
+	let Type::Function(ty) = types
+		.get(sig_idx as usize)
+		.ok_or("The signature as specified by a function isn't defined")?;
+	Ok(ty)
+}
+
+/// Returns the index in the type section of the signature of the function `func_idx`.
+fn resolve_func_type_idx(func_idx: u32, module: &elements::Module) -> Result<u32, &'static str> {
 	let functions = module.function_section().map(|fs| fs.entries()).unwrap_or(&[]);
 
 	let func_imports = module.import_count(elements::ImportCountType::Function);
@@ -339,10 +933,7 @@ fn resolve_func_type(
 			.ok_or("Function at the specified index is not defined")?
 			.type_ref()
 	};
-	let Type::Function(ty) = types
-		.get(sig_idx as usize)
-		.ok_or("The signature as specified by a function isn't defined")?;
-	Ok(ty)
+	Ok(sig_idx)
 }
 
 #[cfg(test)]
@@ -377,4 +968,341 @@ mod tests {
 		let module = inject(module, 1024).expect("Failed to inject stack counter");
 		validate_module(module);
 	}
+
+	#[test]
+	fn native_stack_costs() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $f (param i32) (result i64)
+		(local i32 i64)
+		i64.const 1
+		i64.const 2
+		i64.add
+	)
+)
+"#,
+		);
+
+		// Locals: i32 + i64. Max height: two i64 + frame.
+		let rules = NativeStackCostRules::new(4, 16);
+		assert_eq!(compute_stack_costs(&module, &rules).unwrap(), vec![4 + 8 + 8 + 8 + 16]);
+
+		let rules = NativeStackCostRules::new(8, 16);
+		assert_eq!(compute_stack_costs(&module, &rules).unwrap(), vec![8 + 8 + 8 + 8 + 16]);
+
+		let rules = NativeStackCostRules::new(4, 16).with_frame_alignment(16);
+		assert_eq!(compute_stack_costs(&module, &rules).unwrap(), vec![48]);
+
+		let config = StackLimiterConfig::new(1024 * 1024);
+		let module = inject_with_config(module, &config, &rules).unwrap();
+		validate_module(module);
+	}
+
+	#[test]
+	fn thunks_reuse_existing_types() {
+		let module = parse_wat(
+			r#"
+(module
+	(type (func (param i32) (result i32)))
+	(func $a (type 0)
+		local.get 0
+	)
+	(func $b (type 0)
+		local.get 0
+	)
+	(table 2 funcref)
+	(elem (i32.const 0) func $a $b)
+	(export "a" (func $a))
+	(export "b" (func $b))
+)
+"#,
+		);
+
+		let module = inject(module, 1024).expect("Failed to inject stack counter");
+		assert_eq!(module.type_section().unwrap().types().len(), 1);
+		assert_eq!(module.functions_space(), 4);
+		validate_module(module);
+	}
+
+	#[test]
+	fn one_thunk_per_function() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $f
+		i32.const 1
+		drop
+	)
+	(table 3 funcref)
+	(elem (i32.const 0) func $f $f $f)
+	(export "a" (func $f))
+	(export "b" (func $f))
+	(start $f)
+)
+"#,
+		);
+
+		let module = inject(module, 1024).expect("Failed to inject stack counter");
+		assert_eq!(module.functions_space(), 2);
+		assert!(module.elements_section().unwrap().entries()[0]
+			.members()
+			.iter()
+			.all(|&f| f == 1));
+		assert!(module
+			.export_section()
+			.unwrap()
+			.entries()
+			.iter()
+			.all(|e| *e.internal() == elements::Internal::Function(1)));
+		assert_eq!(module.start_section(), Some(1));
+		validate_module(module);
+	}
+
+	#[test]
+	fn export_stack_height_global() {
+		let module = parse_wat(
+			r#"
+(module
+	(import "env" "imported" (global i32))
+	(global (mut i32) (i32.const 0))
+	(func (export "f")
+		i32.const 1
+		drop
+	)
+)
+"#,
+		);
+
+		let config = StackLimiterConfig::new(1024).with_stack_height_export("stack_height");
+		let module = inject_with_config(module, &config, &ConstantStackCostRules::default())
+			.expect("Failed to inject stack counter");
+
+		let export = module
+			.export_section()
+			.unwrap()
+			.entries()
+			.iter()
+			.find(|e| e.field() == "stack_height")
+			.expect("stack height global is exported");
+		assert_eq!(*export.internal(), elements::Internal::Global(2));
+		assert_eq!(module.global_section().unwrap().entries().len(), 2);
+		validate_module(module);
+	}
+
+	#[test]
+	fn stack_height_export_name_collision() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (export "stack_height"))
+)
+"#,
+		);
+
+		let config = StackLimiterConfig::new(1024).with_stack_height_export("stack_height");
+		assert!(inject_with_config(module, &config, &ConstantStackCostRules::default()).is_err());
+	}
+
+	#[test]
+	fn no_export_thunks() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $exported
+		i32.const 1
+		drop
+	)
+	(func $in_table
+		i32.const 1
+		drop
+	)
+	(table 2 funcref)
+	(elem (i32.const 0) func $exported $in_table)
+	(export "exported" (func $exported))
+	(export "in_table" (func $in_table))
+)
+"#,
+		);
+
+		let config = StackLimiterConfig::new(1024).with_export_thunks(false);
+		let module = inject_with_config(module, &config, &ConstantStackCostRules::default())
+			.expect("Failed to inject stack counter");
+
+		// Only the table entries are routed through thunks.
+		assert_eq!(module.functions_space(), 4);
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[2, 3]);
+		let exports: Vec<_> = module
+			.export_section()
+			.unwrap()
+			.entries()
+			.iter()
+			.map(|e| *e.internal())
+			.collect();
+		assert_eq!(exports, vec![elements::Internal::Function(0), elements::Internal::Function(1)]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn call_graph_analysis() {
+		let module = parse_wat(
+			r#"
+(module
+	(func $leaf
+		i32.const 1
+		drop
+	)
+	(func $middle
+		call $leaf
+	)
+	(func $root (export "root")
+		call $middle
+	)
+	(func $recursive (export "recursive")
+		call $leaf
+		call $recursive
+	)
+)
+"#,
+		);
+
+		let config = StackLimiterConfig::new(1024).with_call_graph_analysis(true);
+		let module = inject_with_config(module, &config, &ConstantStackCostRules::default())
+			.expect("Failed to inject stack counter");
+
+		let bodies = module.code_section().unwrap().bodies();
+		let stack_cost_charges = |func_idx: usize| -> Vec<i32> {
+			bodies[func_idx]
+				.code()
+				.elements()
+				.windows(3)
+				.filter_map(|instrs| match instrs {
+					[Instruction::GetGlobal(_), Instruction::I32Const(cost), Instruction::I32Add] =>
+						Some(*cost),
+					_ => None,
+				})
+				.collect()
+		};
+
+		// Calls without recursion are left alone.
+		assert_eq!(bodies[1].code().elements(), &[Instruction::Call(0), Instruction::End]);
+		assert_eq!(bodies[2].code().elements(), &[Instruction::Call(1), Instruction::End]);
+		// The call to the leaf is charged with the cost of the leaf, the recursive call with its
+		// own cost.
+		assert_eq!(stack_cost_charges(3), vec![3, 2]);
+		// The thunk of `root` charges the cost of the whole call chain.
+		assert_eq!(stack_cost_charges(4), vec![7]);
+		validate_module(module);
+	}
+
+	#[test]
+	fn no_table_thunks() {
+		let module = parse_wat(
+			r#"
+(module
+	(type $ty (func (param i32)))
+	(func $small (param i32)
+		i32.const 1
+		drop
+	)
+	(func $large (param i32)
+		i32.const 1
+		i32.const 2
+		i32.const 3
+		drop
+		drop
+		drop
+	)
+	(func $other
+		i32.const 1
+		drop
+	)
+	(table 3 funcref)
+	(elem (i32.const 0) func $small $large $other)
+	(func (export "call") (param i32)
+		i32.const 0
+		local.get 0
+		call_indirect (type $ty)
+	)
+)
+"#,
+		);
+
+		let config = StackLimiterConfig::new(1024).with_table_thunks(false);
+		let module = inject_with_config(module, &config, &ConstantStackCostRules::default())
+			.expect("Failed to inject stack counter");
+
+		// Only the export is routed through a thunk.
+		assert_eq!(module.functions_space(), 5);
+		assert_eq!(module.elements_section().unwrap().entries()[0].members(), &[0, 1, 2]);
+		// The call is charged with the cost of `$large`: 3 values and the frame.
+		let call_body = module.code_section().unwrap().bodies()[3].code().elements();
+		let call_pos = call_body
+			.iter()
+			.position(|instr| matches!(instr, Instruction::CallIndirect(..)))
+			.unwrap();
+		assert_eq!(call_body[call_pos - 9], Instruction::I32Const(5));
+		assert_eq!(call_body[call_pos + 2], Instruction::I32Const(5));
+		validate_module(module);
+	}
+
+	#[test]
+	fn no_table_thunks_external_table() {
+		let rules = ConstantStackCostRules::default();
+		let config = StackLimiterConfig::new(1024).with_table_thunks(false);
+		for source in [
+			r#"(module (import "env" "table" (table 1 funcref)) (func))"#,
+			r#"(module (table (export "table") 1 funcref) (func))"#,
+		] {
+			assert_eq!(
+				inject_with_config(parse_wat(source), &config, &rules),
+				Err("The table is imported or exported, which isn't allowed without table thunks")
+			);
+			let allowed = config.clone().with_external_table(true);
+			validate_module(inject_with_config(parse_wat(source), &allowed, &rules).unwrap());
+			let thunks = StackLimiterConfig::new(1024);
+			validate_module(inject_with_config(parse_wat(source), &thunks, &rules).unwrap());
+		}
+	}
+
+	#[test]
+	fn repeated_pass() {
+		let module = parse_wat(
+			r#"
+(module
+	(func (export "f") (param i32)
+		local.get 0
+		drop
+	)
+)
+"#,
+		);
+		let rules = ConstantStackCostRules::default();
+
+		let config = StackLimiterConfig::new(1024).with_repeated_pass(RepeatedPass::Error);
+		let instrumented = inject_with_config(module, &config, &rules).unwrap();
+		assert!(inject_with_config(instrumented.clone(), &config, &rules).is_err());
+
+		let config = StackLimiterConfig::new(1024).with_repeated_pass(RepeatedPass::Skip);
+		let skipped = inject_with_config(instrumented.clone(), &config, &rules).unwrap();
+		assert_eq!(skipped, instrumented);
+
+		let config = StackLimiterConfig::new(2048).with_repeated_pass(RepeatedPass::Instrument);
+		let repeated = inject_with_config(instrumented, &config, &rules).unwrap();
+		assert_eq!(repeated.globals_space(), 2);
+		let record = |stack_limit, stack_height_global, thunks| {
+			crate::PassRecord::StackLimiter(crate::StackLimiterRecord {
+				version: env!("CARGO_PKG_VERSION").into(),
+				stack_limit,
+				stack_height_global,
+				thunks,
+			})
+		};
+		// The thunk of the first pass is routed through another thunk by the second one.
+		assert_eq!(
+			crate::read_manifest(&repeated).unwrap().passes,
+			vec![record(1024, 0, vec![(0, 1)]), record(2048, 1, vec![(1, 2)])]
+		);
+		validate_module(repeated);
+	}
 }
@@ -0,0 +1,257 @@
+//! Removal of the stack limiter instrumentation.
+
+use super::resolve_func_type;
+use crate::{
+	index_space,
+	manifest::{self, PassRecord},
+};
+use alloc::vec::Vec;
+use parity_wasm::elements::{self, Instruction};
+
+/// The number of instructions of an instrumented call, see `instrument_call!`.
+const INSTRUMENTED_CALL_LEN: usize = 15;
+
+/// Remove the stack limiter instrumentation injected by [`inject`](super::inject) from `module`.
+///
+/// The preambles and postambles around calls are deleted, exports, table entries and the start
+/// function are routed back from the thunks to the original functions and the thunks as well as
+/// the stack height global are removed. The indices of all remaining functions and globals are
+/// restored accordingly.
+///
+/// The stack height global and the thunks are located using the last stack limiter pass recorded
+/// in the manifest, see
+/// [`StackLimiterConfig::with_manifest`](super::StackLimiterConfig::with_manifest).
+/// Without a manifest, the global is recognized from the instrumented calls and the thunks as the
+/// trailing functions that only forward their arguments through an instrumented call. An original
+/// function of that shape at the very end of the module can be mistaken for a thunk in that case.
+///
+/// When multiple passes were applied, they must be removed in the reverse order of application.
+pub fn remove(mut module: elements::Module) -> Result<elements::Module, &'static str> {
+	let record = manifest::read_manifest(&module).and_then(|manifest| {
+		manifest.passes.into_iter().rev().find_map(|pass| match pass {
+			PassRecord::StackLimiter(record) => Some(record),
+			_ => None,
+		})
+	});
+
+	let import_count = module.import_count(elements::ImportCountType::Function) as u32;
+	let bodies = module.code_section().map(|cs| cs.bodies()).unwrap_or(&[]);
+	let stack_height_global = match &record {
+		Some(record) => record.stack_height_global,
+		None => bodies
+			.iter()
+			.flat_map(|body| body.code().elements().windows(INSTRUMENTED_CALL_LEN))
+			.find_map(|instructions| match instructions.first() {
+				Some(Instruction::GetGlobal(global_idx)) =>
+					instrumented_call(instructions, *global_idx).map(|_| *global_idx),
+				_ => None,
+			})
+			.ok_or("The module isn't instrumented with a stack limiter")?,
+	};
+
+	let is_thunk_of =
+		|thunk_idx: u32, func_idx: u32| -> Result<bool, &'static str> {
+			let body = thunk_idx
+				.checked_sub(import_count)
+				.and_then(|idx| bodies.get(idx as usize))
+				.ok_or("Thunks are only generated for defined functions")?;
+			let params = resolve_func_type(thunk_idx, &module)?.params().len();
+			let instructions = body.code().elements();
+			Ok(body.locals().is_empty() &&
+				instructions.len() == params + INSTRUMENTED_CALL_LEN + 1 &&
+				instructions[..params].iter().zip(0..).all(|(instruction, arg_idx)| {
+					*instruction == Instruction::GetLocal(arg_idx)
+				}) && instrumented_call(&instructions[params..], stack_height_global) ==
+				Some(&Instruction::Call(func_idx)))
+		};
+	let thunks: Vec<(u32, u32)> = match &record {
+		Some(record) => {
+			for (func_idx, thunk_idx) in &record.thunks {
+				if !is_thunk_of(*thunk_idx, *func_idx)? {
+					return Err("The thunks were modified after the stack limiter was applied")
+				}
+			}
+			record.thunks.clone()
+		},
+		None => {
+			let mut thunks = Vec::new();
+			for thunk_idx in (import_count..module.functions_space() as u32).rev() {
+				let body = &bodies[(thunk_idx - import_count) as usize];
+				let func_idx = match body.code().elements().iter().rev().nth(5) {
+					Some(Instruction::Call(func_idx)) if *func_idx < thunk_idx => *func_idx,
+					_ => break,
+				};
+				if !is_thunk_of(thunk_idx, func_idx)? {
+					break
+				}
+				thunks.push((func_idx, thunk_idx));
+			}
+			thunks
+		},
+	};
+	let original = |func_idx: &mut u32| {
+		if let Some((original_idx, _)) =
+			thunks.iter().find(|(_, thunk_idx)| *thunk_idx == *func_idx)
+		{
+			*func_idx = *original_idx;
+		}
+	};
+
+	let mut stripped: Vec<elements::FuncBody> = bodies.to_vec();
+	for body in &mut stripped {
+		let instructions = body.code().elements();
+		let mut original = Vec::with_capacity(instructions.len());
+		let mut cursor = 0;
+		while cursor < instructions.len() {
+			match instrumented_call(&instructions[cursor..], stack_height_global) {
+				Some(call) => {
+					original.push(call.clone());
+					cursor += INSTRUMENTED_CALL_LEN;
+				},
+				None => {
+					original.push(instructions[cursor].clone());
+					cursor += 1;
+				},
+			}
+		}
+		*body.code_mut() = elements::Instructions::new(original);
+	}
+
+	let references_global = (import_count..)
+		.zip(&stripped)
+		.filter(|(func_idx, _)| !thunks.iter().any(|(_, thunk_idx)| thunk_idx == func_idx))
+		.flat_map(|(_, body)| body.code().elements())
+		.chain(
+			module
+				.global_section()
+				.map(|gs| gs.entries())
+				.unwrap_or(&[])
+				.iter()
+				.flat_map(|global| global.init_expr().code()),
+		)
+		.any(|instruction| {
+			matches!(
+				instruction,
+				Instruction::GetGlobal(global_idx) | Instruction::SetGlobal(global_idx)
+					if *global_idx == stack_height_global
+			)
+		});
+	if references_global {
+		return Err("The stack height global is still referenced")
+	}
+
+	if let Some(code_section) = module.code_section_mut() {
+		*code_section.bodies_mut() = stripped;
+	}
+	for section in module.sections_mut() {
+		match section {
+			elements::Section::Export(export_section) =>
+				for entry in export_section.entries_mut() {
+					if let elements::Internal::Function(func_idx) = entry.internal_mut() {
+						original(func_idx)
+					}
+				},
+			elements::Section::Element(elements_section) =>
+				for segment in elements_section.entries_mut() {
+					for func_idx in segment.members_mut() {
+						original(func_idx)
+					}
+				},
+			elements::Section::Start(start_idx) => original(start_idx),
+			_ => {},
+		}
+	}
+
+	let thunk_indices: Vec<u32> = thunks.iter().map(|(_, thunk_idx)| *thunk_idx).collect();
+	index_space::remove_functions(&mut module, &thunk_indices);
+	index_space::remove_globals(&mut module, &[stack_height_global]);
+	if record.is_some() {
+		manifest::remove_pass(&mut module, manifest::STACK_LIMITER);
+	}
+
+	Ok(module)
+}
+
+/// Returns the original call if `instructions` start with a call instrumented using the global
+/// `stack_height_global`.
+fn instrumented_call(
+	instructions: &[Instruction],
+	stack_height_global: u32,
+) -> Option<&Instruction> {
+	use Instruction::*;
+	let g = stack_height_global;
+	match instructions.get(..INSTRUMENTED_CALL_LEN)? {
+		[
+			// preamble
+			GetGlobal(g0),
+			I32Const(cost),
+			I32Add,
+			SetGlobal(g1),
+			GetGlobal(g2),
+			I32Const(_),
+			I32GtU,
+			If(elements::BlockType::NoResult),
+			Unreachable,
+			End,
+			call @ (Call(_) | CallIndirect(..)),
+			// postamble
+			GetGlobal(g3),
+			I32Const(cost_after),
+			I32Sub,
+			SetGlobal(g4),
+		] if [g0, g1, g2, g3, g4].iter().all(|idx| **idx == g) && cost == cost_after => Some(call),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{super::*, *};
+	use crate::test_utils::parse_wat;
+
+	#[test]
+	fn round_trip() {
+		let original = parse_wat(
+			r#"
+(module
+	(import "env" "f" (func $f (param i32)))
+	(global $g (mut i32) (i32.const 0))
+	(table 2 funcref)
+	(elem (i32.const 0) $h $main)
+	(type $t (func (param i32) (result i32)))
+	(func $h (export "h") (param i32) (result i32)
+		(call $f (local.get 0))
+		(call_indirect (type $t) (local.get 0) (i32.const 0))
+	)
+	(func $main (export "main")
+		(global.set $g (call $h (global.get $g)))
+	)
+	(start $main)
+)
+"#,
+		);
+
+		let configs = [
+			StackLimiterConfig::new(1024).with_manifest(true),
+			StackLimiterConfig::new(1024)
+				.with_table_thunks(false)
+				.with_stack_height_export("sh"),
+			StackLimiterConfig::new(1024).with_export_thunks(false),
+		];
+		for config in configs {
+			let module =
+				inject_with_config(original.clone(), &config, &ConstantStackCostRules::default())
+					.unwrap();
+			let module = remove(module).unwrap();
+			assert_eq!(module.global_section(), original.global_section());
+			assert_eq!(module.export_section(), original.export_section());
+			assert_eq!(module.elements_section(), original.elements_section());
+			assert_eq!(module.start_section(), original.start_section());
+			assert_eq!(module.function_section(), original.function_section());
+			assert_eq!(module.code_section(), original.code_section());
+			assert!(manifest::read_manifest(&module).is_none());
+		}
+
+		assert!(remove(original).is_err());
+	}
+}
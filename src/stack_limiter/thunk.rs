@@ -1,42 +1,61 @@
 use alloc::{collections::BTreeMap as Map, vec::Vec};
-use parity_wasm::{
-	builder,
-	elements::{self, FunctionType, Internal},
-};
+use parity_wasm::elements::{self, FunctionType, Internal};
 
-use super::{resolve_func_type, Context};
+use super::{resolve_func_type, resolve_func_type_idx, Context};
 
 struct Thunk {
 	signature: FunctionType,
+	// Index in the type section of the original function's signature.
+	type_idx: u32,
 	// Index in function space of this thunk.
 	idx: Option<u32>,
 	callee_stack_cost: u32,
 }
 
+/// Pairs of the index of an original function and the index of the thunk generated for it.
+pub type ThunkMapping = Vec<(u32, u32)>;
+
+/// Returns the indices of the functions that get a thunk unless their stack cost is zero.
+///
+/// These are the exported functions if `export_thunks` is set, the table members if
+/// `table_thunks` is set and the start function. Indices may be repeated.
+pub fn thunk_candidates(
+	module: &elements::Module,
+	export_thunks: bool,
+	table_thunks: bool,
+) -> impl Iterator<Item = u32> + '_ {
+	let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
+	let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
+	let start_func_idx = module.start_section();
+
+	let exported_func_indices =
+		exports
+			.iter()
+			.filter(move |_| export_thunks)
+			.filter_map(|entry| match entry.internal() {
+				Internal::Function(function_idx) => Some(*function_idx),
+				_ => None,
+			});
+	let table_func_indices = elem_segments
+		.iter()
+		.filter(move |_| table_thunks)
+		.flat_map(|segment| segment.members())
+		.cloned();
+
+	exported_func_indices.chain(table_func_indices).chain(start_func_idx)
+}
+
+/// Generate the thunks and route the exports, table entries and start function through them.
 pub fn generate_thunks(
 	ctx: &mut Context,
 	module: elements::Module,
-) -> Result<elements::Module, &'static str> {
+) -> Result<(elements::Module, ThunkMapping), &'static str> {
 	// First, we need to collect all function indices that should be replaced by thunks
 	let mut replacement_map: Map<u32, Thunk> = {
-		let exports = module.export_section().map(|es| es.entries()).unwrap_or(&[]);
-		let elem_segments = module.elements_section().map(|es| es.entries()).unwrap_or(&[]);
-		let start_func_idx = module.start_section();
-
-		let exported_func_indices = exports.iter().filter_map(|entry| match entry.internal() {
-			Internal::Function(function_idx) => Some(*function_idx),
-			_ => None,
-		});
-		let table_func_indices =
-			elem_segments.iter().flat_map(|segment| segment.members()).cloned();
-
 		// Replacement map is at least export section size.
 		let mut replacement_map: Map<u32, Thunk> = Map::new();
 
-		for func_idx in exported_func_indices
-			.chain(table_func_indices)
-			.chain(start_func_idx.into_iter())
-		{
+		for func_idx in thunk_candidates(&module, ctx.export_thunks(), ctx.table_thunks()) {
 			let callee_stack_cost = ctx.stack_cost(func_idx).ok_or("function index isn't found")?;
 
 			// Don't generate a thunk if stack_cost of a callee is zero.
@@ -45,6 +64,7 @@ pub fn generate_thunks(
 					func_idx,
 					Thunk {
 						signature: resolve_func_type(func_idx, &module)?.clone(),
+						type_idx: resolve_func_type_idx(func_idx, &module)?,
 						idx: None,
 						callee_stack_cost,
 					},
@@ -58,12 +78,12 @@ pub fn generate_thunks(
 	// Then, we generate a thunk for each original function.
 
 	// Save current func_idx
-	let mut next_func_idx = module.functions_space() as u32;
+	let next_func_idx = module.functions_space() as u32;
 
-	let mut mbuilder = builder::from_module(module);
-	for (func_idx, thunk) in replacement_map.iter_mut() {
+	let mut module = module;
+	for (thunk_idx, (func_idx, thunk)) in (next_func_idx..).zip(replacement_map.iter_mut()) {
 		let instrumented_call = instrument_call!(
-			*func_idx,
+			elements::Instruction::Call(*func_idx),
 			thunk.callee_stack_cost as i32,
 			ctx.stack_height_global_idx(),
 			ctx.stack_limit()
@@ -81,24 +101,21 @@ pub fn generate_thunks(
 		thunk_body.extend_from_slice(&instrumented_call);
 		thunk_body.push(elements::Instruction::End);
 
-		// TODO: Don't generate a signature, but find an existing one.
-
-		mbuilder = mbuilder
-			.function()
-			// Signature of the thunk should match the original function signature.
-			.signature()
-			.with_params(thunk.signature.params().to_vec())
-			.with_results(thunk.signature.results().to_vec())
-			.build()
-			.body()
-			.with_instructions(elements::Instructions::new(thunk_body))
-			.build()
-			.build();
-
-		thunk.idx = Some(next_func_idx);
-		next_func_idx += 1;
+		// Signature of the thunk should match the original function signature. Thus, we just
+		// reference the type of the original function instead of adding a new one.
+		module
+			.function_section_mut()
+			.ok_or("Thunks are only generated for defined functions")?
+			.entries_mut()
+			.push(elements::Func::new(thunk.type_idx));
+		module
+			.code_section_mut()
+			.ok_or("Thunks are only generated for defined functions")?
+			.bodies_mut()
+			.push(elements::FuncBody::new(Vec::new(), elements::Instructions::new(thunk_body)));
+
+		thunk.idx = Some(thunk_idx);
 	}
-	let mut module = mbuilder.build();
 
 	// And finally, fixup thunks in export and table sections.
 
@@ -114,13 +131,13 @@ pub fn generate_thunks(
 
 	for section in module.sections_mut() {
 		match section {
-			elements::Section::Export(export_section) =>
+			elements::Section::Export(export_section) if ctx.export_thunks() =>
 				for entry in export_section.entries_mut() {
 					if let Internal::Function(function_idx) = entry.internal_mut() {
 						fixup(function_idx)
 					}
 				},
-			elements::Section::Element(elem_section) =>
+			elements::Section::Element(elem_section) if ctx.table_thunks() =>
 				for segment in elem_section.entries_mut() {
 					for function_idx in segment.members_mut() {
 						fixup(function_idx)
@@ -131,5 +148,10 @@ pub fn generate_thunks(
 		}
 	}
 
-	Ok(module)
+	let thunks = replacement_map
+		.iter()
+		.filter_map(|(func_idx, thunk)| Some((*func_idx, thunk.idx?)))
+		.collect();
+
+	Ok((module, thunks))
 }
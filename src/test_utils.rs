@@ -0,0 +1,9 @@
+//! Helpers shared by the unit tests.
+
+use parity_wasm::elements;
+
+/// Parse the text format `source` into a module.
+pub fn parse_wat(source: &str) -> elements::Module {
+	elements::deserialize_buffer(&wat::parse_str(source).expect("Failed to wat2wasm"))
+		.expect("Failed to deserialize the module")
+}
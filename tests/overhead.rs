@@ -3,9 +3,10 @@ use std::{
 	path::PathBuf,
 };
 use wasm_instrument::{
-	gas_metering::{self, host_function, mutable_global, ConstantCostRules},
-	inject_stack_limiter,
-	parity_wasm::{deserialize_buffer, elements::Module, serialize},
+	gas_metering::{host_function, mutable_global, ConstantCostRules, GasMeteringConfig},
+	overhead::{self, OverheadConfig, OverheadReport},
+	parity_wasm::{deserialize_buffer, elements::Module},
+	ConstantStackCostRules, StackLimiterConfig,
 };
 
 fn fixture_dir() -> PathBuf {
@@ -15,29 +16,45 @@ fn fixture_dir() -> PathBuf {
 	path
 }
 
-use gas_metering::Backend;
-fn gas_metered_mod_len<B: Backend>(orig_module: Module, backend: B) -> (Module, usize) {
-	let module = gas_metering::inject(orig_module, backend, &ConstantCostRules::default()).unwrap();
-	let bytes = serialize(module.clone()).unwrap();
-	let len = bytes.len();
-	(module, len)
+const STACK_LIMITED: &str = "stack_limited";
+const HOST_FN: &str = "gas_metered_host_fn";
+const MUT_GLOBAL: &str = "gas_metered_mut_global";
+const HOST_FN_THEN_STACK_LIMITED: &str = "gas_metered_host_fn_then_stack_limited";
+const MUT_GLOBAL_THEN_STACK_LIMITED: &str = "gas_metered_mut_global_then_stack_limited";
+
+fn host_fn(name: &str) -> OverheadConfig {
+	OverheadConfig::new(name).gas_metering(
+		host_function::Injector::new("env", "gas"),
+		ConstantCostRules::default(),
+		GasMeteringConfig::new(),
+	)
 }
 
-fn stack_limited_mod_len(module: Module) -> (Module, usize) {
-	let module = inject_stack_limiter(module, 128).unwrap();
-	let bytes = serialize(module.clone()).unwrap();
-	let len = bytes.len();
-	(module, len)
+fn mut_global(name: &str) -> OverheadConfig {
+	OverheadConfig::new(name).gas_metering(
+		mutable_global::Injector::new("gas_left"),
+		ConstantCostRules::default(),
+		GasMeteringConfig::new(),
+	)
+}
+
+fn stack_limited(config: OverheadConfig) -> OverheadConfig {
+	config.stack_limiter(StackLimiterConfig::new(128), ConstantStackCostRules::default())
 }
 
 struct InstrumentedWasmResults {
 	filename: String,
-	original_module_len: usize,
-	stack_limited_len: usize,
-	gas_metered_host_fn_len: usize,
-	gas_metered_mut_glob_len: usize,
-	gas_metered_host_fn_then_stack_limited_len: usize,
-	gas_metered_mut_glob_then_stack_limited_len: usize,
+	report: OverheadReport,
+}
+
+impl InstrumentedWasmResults {
+	fn size(&self, name: &str) -> usize {
+		self.report.get(name).unwrap().size_after
+	}
+
+	fn percent(&self, name: &str) -> usize {
+		self.report.size_percent(self.report.get(name).unwrap())
+	}
 }
 
 fn size_overheads_all(files: ReadDir) -> Vec<InstrumentedWasmResults> {
@@ -46,44 +63,26 @@ fn size_overheads_all(files: ReadDir) -> Vec<InstrumentedWasmResults> {
 			let entry = entry.unwrap();
 			let filename = entry.file_name().into_string().unwrap();
 
-			let (original_module_len, orig_module) = {
-				let bytes = match entry.path().extension().unwrap().to_str() {
-					Some("wasm") => read(entry.path()).unwrap(),
-					Some("wat") =>
-						wat::parse_bytes(&read(entry.path()).unwrap()).unwrap().into_owned(),
-					_ => panic!("expected fixture_dir containing .wasm or .wat files only"),
-				};
-
-				let len = bytes.len();
-				let module: Module = deserialize_buffer(&bytes).unwrap();
-				(len, module)
+			let bytes = match entry.path().extension().unwrap().to_str() {
+				Some("wasm") => read(entry.path()).unwrap(),
+				Some("wat") => wat::parse_bytes(&read(entry.path()).unwrap()).unwrap().into_owned(),
+				_ => panic!("expected fixture_dir containing .wasm or .wat files only"),
 			};
-
-			let (gm_host_fn_module, gas_metered_host_fn_len) = gas_metered_mod_len(
-				orig_module.clone(),
-				host_function::Injector::new("env", "gas"),
-			);
-
-			let (gm_mut_global_module, gas_metered_mut_glob_len) =
-				gas_metered_mod_len(orig_module.clone(), mutable_global::Injector::new("gas_left"));
-
-			let stack_limited_len = stack_limited_mod_len(orig_module).1;
-
-			let (_gm_hf_sl_mod, gas_metered_host_fn_then_stack_limited_len) =
-				stack_limited_mod_len(gm_host_fn_module);
-
-			let (_gm_mg_sl_module, gas_metered_mut_glob_then_stack_limited_len) =
-				stack_limited_mod_len(gm_mut_global_module);
-
-			InstrumentedWasmResults {
-				filename,
-				original_module_len,
-				stack_limited_len,
-				gas_metered_host_fn_len,
-				gas_metered_mut_glob_len,
-				gas_metered_host_fn_then_stack_limited_len,
-				gas_metered_mut_glob_then_stack_limited_len,
-			}
+			let module: Module = deserialize_buffer(&bytes).unwrap();
+
+			let report = overhead::measure(
+				&module,
+				[
+					stack_limited(OverheadConfig::new(STACK_LIMITED)),
+					host_fn(HOST_FN),
+					mut_global(MUT_GLOBAL),
+					stack_limited(host_fn(HOST_FN_THEN_STACK_LIMITED)),
+					stack_limited(mut_global(MUT_GLOBAL_THEN_STACK_LIMITED)),
+				],
+			)
+			.unwrap();
+
+			InstrumentedWasmResults { filename, report }
 		})
 		.collect()
 }
@@ -110,20 +109,16 @@ fn calc_size_overheads() -> Vec<InstrumentedWasmResults> {
 #[test]
 fn print_size_overhead() {
 	let mut results = calc_size_overheads();
-	results.sort_unstable_by(|a, b| {
-		b.gas_metered_mut_glob_then_stack_limited_len
-			.cmp(&a.gas_metered_mut_glob_then_stack_limited_len)
-	});
+	results.sort_unstable_by_key(|r| std::cmp::Reverse(r.size(MUT_GLOBAL_THEN_STACK_LIMITED)));
 
 	for r in results {
-		let filename = r.filename;
-		let original_size = r.original_module_len / 1024;
-		let stack_limit = r.stack_limited_len * 100 / r.original_module_len;
-		let host_fn = r.gas_metered_host_fn_len * 100 / r.original_module_len;
-		let mut_glob = r.gas_metered_mut_glob_len * 100 / r.original_module_len;
-		let host_fn_sl = r.gas_metered_host_fn_then_stack_limited_len * 100 / r.original_module_len;
-		let mut_glob_sl =
-			r.gas_metered_mut_glob_then_stack_limited_len * 100 / r.original_module_len;
+		let filename = &r.filename;
+		let original_size = r.report.size_before / 1024;
+		let stack_limit = r.percent(STACK_LIMITED);
+		let host_fn = r.percent(HOST_FN);
+		let mut_glob = r.percent(MUT_GLOBAL);
+		let host_fn_sl = r.percent(HOST_FN_THEN_STACK_LIMITED);
+		let mut_glob_sl = r.percent(MUT_GLOBAL_THEN_STACK_LIMITED);
 
 		println!(
 			"{filename:30}: orig = {original_size:4} kb, stack_limiter = {stack_limit} %, \
@@ -143,11 +138,11 @@ fn print_gas_metered_sizes() {
 	let mut results = overheads
 		.iter()
 		.map(|r| {
-			let diff = (r.gas_metered_mut_glob_len * 100 / r.gas_metered_host_fn_len) as i32 - 100;
+			let diff = (r.size(MUT_GLOBAL) * 100 / r.size(HOST_FN)) as i32 - 100;
 			(diff, r)
 		})
 		.collect::<Vec<(i32, &InstrumentedWasmResults)>>();
-	results.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+	results.sort_unstable_by_key(|r| std::cmp::Reverse(r.0));
 
 	println!(
 		"| {:28} | {:^16} | gas metered/host fn | gas metered/mut global | size diff |",
@@ -156,11 +151,11 @@ fn print_gas_metered_sizes() {
 	println!("|{:-^30}|{:-^18}|{:-^21}|{:-^24}|{:-^11}|", "", "", "", "", "",);
 	for r in results {
 		let filename = &r.1.filename;
-		let original_size = &r.1.original_module_len / 1024;
-		let host_fn = &r.1.gas_metered_host_fn_len / 1024;
-		let mut_glob = &r.1.gas_metered_mut_glob_len / 1024;
-		let host_fn_percent = &r.1.gas_metered_host_fn_len * 100 / r.1.original_module_len;
-		let mut_glob_percent = &r.1.gas_metered_mut_glob_len * 100 / r.1.original_module_len;
+		let original_size = r.1.report.size_before / 1024;
+		let host_fn = r.1.size(HOST_FN) / 1024;
+		let mut_glob = r.1.size(MUT_GLOBAL) / 1024;
+		let host_fn_percent = r.1.percent(HOST_FN);
+		let mut_glob_percent = r.1.percent(MUT_GLOBAL);
 		let host_fn = format!("{host_fn} kb ({host_fn_percent:}%)");
 		let mut_glob = format!("{mut_glob} kb ({mut_glob_percent:}%)");
 		let diff = &r.0;